@@ -1,27 +1,53 @@
 import { Component, createSignal,  onMount, ParentComponent } from "solid-js";
-import { Category, Duration, DurationSplit} from "../types/types";
+import { Category, DurationSplit, Occurrence } from "../types/types";
 import apiUtil from "../utils/apiUtil";
 import styles from "../App.module.css";
 import { daysOfWeek } from "../utils/daysOfWeek";
 import { A } from "@solidjs/router";
 
-const splitDurations = (durations: Duration[])=>{
-    let splitDurs: DurationSplit[] = [];
+const isoDate = (date: Date) => {
+    const pad = (n: number) => String(n).padStart(2, "0");
+    return `${date.getFullYear()}-${pad(date.getMonth() + 1)}-${pad(date.getDate())}`;
+};
+
+// Sunday to Saturday of the week being shown.
+const currentWeek = () => {
+    const sunday = new Date();
+    sunday.setDate(sunday.getDate() - sunday.getDay());
+    const saturday = new Date(sunday);
+    saturday.setDate(sunday.getDate() + 6);
+    return { from: isoDate(sunday), to: isoDate(saturday) };
+};
 
-    for (const i in durations){
-        const dur = durations[i];
+// The blocks that actually fall in this week, so one-off and monthly durations only show up
+// on their own days, and moved or cancelled occurrences show where they ended up.
+const splitOccurrences = (occurrences: Occurrence[], cats: Category[]) => {
+    let splitDurs: DurationSplit[] = [];
 
-        for(const x in dur.recurring_days){
-            if(dur.recurring_days[x] === 1){ 
-                splitDurs.push({
-                    day_as_int: parseInt(x, 10),
-                    ...dur
-                });
+    for (const occ of occurrences) {
+        const titles: string[] = [];
+        const cat = cats.find((cat) => cat.id === occ.category_id);
+        if (cat) {
+            titles.push(cat.title);
+            for (let i = 0; i < 2; i++) {
+                if (cat.tasks_todo[i]) {
+                    titles.push(cat.tasks_todo[i].title);
+                }
             }
         }
+        splitDurs.push({
+            id: occ.duration_id,
+            owner_id: occ.owner_id,
+            category_id: occ.category_id,
+            start_hour: occ.start_hour,
+            end_hour: occ.end_hour,
+            day_as_int: new Date(`${occ.date}T00:00:00`).getDay(),
+            color: occ.color,
+            titles,
+        });
     }
     return splitDurs;
-}; 
+};
 
 export const Calendar: Component = () => {
     const [hours, _setHours] = createSignal<number[]>([...Array(24).keys()]);
@@ -40,24 +66,13 @@ export const Calendar: Component = () => {
             }
         }, 150);
 
-        const durs: Duration[] = await api.get("/durations");
+        const { from, to } = currentWeek();
+        const occurrences: Occurrence[] = await api.get(
+            `/durations/occurrences?from=${from}&to=${to}`,
+        );
         const cats: Category[] = await api.get("/categories");
 
-        for (const dur of durs) {
-            for (const cat of cats) {
-                if (dur.category_id === cat.id) {
-                    dur.titles = [];
-                    dur.titles.push(cat.title);
-                    for (let i = 0; i < 2; i++) {
-                        if (cat.tasks_todo[i]) {
-                            dur.titles.push(cat.tasks_todo[i].title);
-                        }
-                    }
-                }
-            }
-        }
-
-        setDurations(splitDurations(durs));
+        setDurations(splitOccurrences(occurrences, cats));
     });
 
     const handleAddCalEvent = () => {
@@ -6,10 +6,92 @@ export type TaskData = {
     priority: number;
     owner_id: number;
     category_id: number;
+    estimate_minutes?: number | null;
+    due_at?: string | null;
+    start_at?: string | null;
+    rrule?: string | null;
+    repeat_after_days?: number | null;
+    parent_id?: number | null;
+    tags?: string[];
+    status_id?: number | null;
+    rank?: number | null;
 };
 
 export type Task = TaskData & {
     id: number;
+    series_id?: number | null;
+    is_blocked?: boolean;
+    created_at?: string;
+    updated_at?: string;
+    completed_at?: string | null;
+    deleted_at?: string | null;
+};
+
+export type UpdatedTask = Task & {
+    unblocked: Task[];
+};
+
+export type TaskDependencies = {
+    blocked_by: Task[];
+    blocks: Task[];
+};
+
+export type TaskStatus = {
+    id: number;
+    category_id: number;
+    title: string;
+    position: number;
+    is_done: boolean;
+};
+
+export type BoardColumn = {
+    status: TaskStatus;
+    tasks: Task[];
+};
+
+export type StatusChange = {
+    task_id: number;
+    from_status_id: number | null;
+    to_status_id: number | null;
+    changed_at: string;
+};
+
+export type TaskChange = {
+    id: number;
+    task_id: number;
+    field: string;
+    old_value: string | null;
+    new_value: string | null;
+    actor_id: number | null;
+    changed_at: string;
+};
+
+export type Tag = {
+    id: number;
+    owner_id: number;
+    name: string;
+    color: string;
+};
+
+export type TagCount = Tag & {
+    task_count: number;
+    open_count: number;
+};
+
+export type TaskNode = Task & {
+    done: number;
+    total: number;
+    subtasks: TaskNode[];
+};
+
+export type TaskCompletion = {
+    id: number;
+    task_id: number;
+    series_id: number;
+    owner_id: number;
+    due_at: string | null;
+    completed_at: string;
+    next_task_id: number | null;
 };
 
 export type Category = {
@@ -18,8 +100,15 @@ export type Category = {
     description: string;
     priority: number;
     owner_id: number;
+    counts_as_busy: boolean;
+    shows_on_blackout: boolean;
     tasks_todo: Task[];
     tasks_done: Task[];
+    task_tree?: TaskNode[];
+    created_at?: string | null;
+    updated_at?: string | null;
+    deleted_at?: string | null;
+    completed_at?: string | null;
 };
 
 export type DurationSplit = {
@@ -33,6 +122,18 @@ export type DurationSplit = {
     titles: string[];
 };
 
+// One day a duration lands on, from GET /durations/occurrences.
+export type Occurrence = {
+    duration_id: number;
+    owner_id: number;
+    category_id: number;
+    date: string;
+    start_hour: number;
+    end_hour: number;
+    color: string;
+    exception_id: number | null;
+};
+
 export type Duration = {
     id: number;
     owner_id: number;
@@ -41,6 +142,146 @@ export type Duration = {
     end_hour: number;
     recurring_days: number[];
     color: string;
+    rrule?: string;
+    dtstart?: string;
+    schedule_id?: number | null;
+    created_at?: string;
+    updated_at?: string;
+    deleted_at?: string | null;
+    completed_at?: string | null;
     titles: string[];
 };
 
+export type Trash = {
+    categories: Category[];
+    tasks: Task[];
+    durations: Duration[];
+    schedules: Schedule[];
+};
+
+
+export type DurationException = {
+    id: number;
+    duration_id: number;
+    occurrence_date: string;
+    is_cancelled: boolean;
+    new_date: string | null;
+    start_hour: number | null;
+    end_hour: number | null;
+    category_id: number | null;
+};
+
+export type Schedule = {
+    id: number;
+    owner_id: number;
+    title: string;
+    starts_on: string;
+    ends_on: string | null;
+    deleted_at?: string | null;
+};
+
+export type BusyInterval = {
+    start: string;
+    end: string;
+};
+
+export type PlanItem = {
+    task_id: number;
+    task_title: string;
+    duration_id: number;
+    category_id: number;
+    date: string;
+    starts_at: string;
+    ends_at: string;
+    minutes: number;
+};
+
+export type CategoryCapacity = {
+    category_id: number;
+    title: string;
+    open_tasks: number;
+    unestimated_tasks: number;
+    open_minutes: number;
+    allotted_minutes: number;
+    over_committed: boolean;
+    clears_on?: string | null;
+    weeks: { starts_on: string; allotted_minutes: number }[];
+};
+
+export type CategoryTarget = {
+    category_id: number;
+    hours: number;
+    min_block?: number;
+    max_block?: number;
+    preferred_start?: number | null;
+    preferred_end?: number | null;
+    color?: string | null;
+};
+
+export type AvailabilityWindow = {
+    day: number;
+    start_hour: number;
+    end_hour: number;
+};
+
+export type TimeEntry = {
+    id: number;
+    owner_id: number;
+    task_id?: number | null;
+    category_id: number;
+    started_at: string;
+    ended_at?: string | null;
+    note?: string | null;
+};
+
+export type PomodoroSession = {
+    id: number;
+    owner_id: number;
+    task_id?: number | null;
+    category_id: number;
+    work_minutes: number;
+    break_minutes: number;
+    phase: "work" | "break" | "ready";
+    phase_started_at: string;
+    started_at: string;
+    ended_at?: string | null;
+};
+
+export type PomodoroState = {
+    session: PomodoroSession;
+    phase_ends_at?: string | null;
+    remaining_seconds?: number | null;
+    completed_intervals: number;
+};
+
+export type NotificationPreferences = {
+    owner_id: number;
+    block_minutes_before?: number | null;
+    task_minutes_before?: number | null;
+    sse_enabled: boolean;
+    webhook_url?: string | null;
+    email?: string | null;
+    quiet_start?: number | null;
+    quiet_end?: number | null;
+};
+
+export type AppNotification = {
+    id: number;
+    owner_id: number;
+    kind: string;
+    reference: string;
+    title: string;
+    body: string;
+    fire_at: string;
+    delivered_at?: string | null;
+    suppressed: boolean;
+};
+
+export type Blackout = {
+    id: number;
+    owner_id: number;
+    title: string;
+    starts_on: string;
+    ends_on: string;
+    is_holiday: boolean;
+};
@@ -0,0 +1,376 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{Duration, Occurrence},
+    ical,
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{Duration as Days, NaiveDate, Timelike};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{GenericClient, Row};
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathId {
+    pub id: i32,
+}
+
+// Dates (both ends inclusive) on which the owner's blocks don't happen, like a vacation
+// or a public holiday. Categories with `shows_on_blackout` carry on regardless.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Blackout {
+    pub id: i32,
+    pub owner_id: i32,
+    pub title: String,
+    pub starts_on: NaiveDate,
+    pub ends_on: NaiveDate,
+    pub is_holiday: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlackoutRequest {
+    owner_id: i32,
+    title: String,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerQuery {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HolidayImportQuery {
+    owner_id: i32,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HolidayImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+// A holiday read from a file: (title, first day, last day).
+type Holiday = (String, NaiveDate, NaiveDate);
+
+pub fn blackout_from_row(row: &Row) -> Blackout {
+    return Blackout {
+        id: row.get(0),
+        owner_id: row.get(1),
+        title: row.get(2),
+        starts_on: row.get(3),
+        ends_on: row.get(4),
+        is_holiday: row.get(5),
+    };
+}
+
+impl Blackout {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        return self.starts_on <= date && date <= self.ends_on;
+    }
+}
+
+pub async fn get_blackouts<C: GenericClient>(client: &C, owner_id: Option<i32>) -> Vec<Blackout> {
+    let mut blackouts = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.blackout WHERE $1::INT IS NULL OR owner_id = $1
+            ORDER BY starts_on",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting blackouts")
+    {
+        blackouts.push(blackout_from_row(&row));
+    }
+    return blackouts;
+}
+
+// Categories whose blocks still happen on blackout dates.
+pub async fn get_exempt_categories<C: GenericClient>(
+    client: &C,
+    owner_id: Option<i32>,
+) -> Vec<i32> {
+    let mut ids = Vec::new();
+    for row in client
+        .query(
+            "SELECT id FROM public.category
+            WHERE shows_on_blackout AND ($1::INT IS NULL OR owner_id = $1)",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        ids.push(row.get(0));
+    }
+    return ids;
+}
+
+pub fn is_blacked_out(blackouts: &[Blackout], owner_id: i32, date: NaiveDate) -> bool {
+    return blackouts
+        .iter()
+        .any(|blackout| blackout.owner_id == owner_id && blackout.contains(date));
+}
+
+pub fn apply_blackouts(occurrences: &mut Vec<Occurrence>, blackouts: &[Blackout], exempt: &[i32]) {
+    occurrences.retain(|occ| {
+        return exempt.contains(&occ.category_id)
+            || !is_blacked_out(blackouts, occ.owner_id, occ.date);
+    });
+}
+
+// Dates the series would land on inside a blackout, for calendars that get the whole
+// series as one rule and need them as EXDATEs.
+pub fn blackout_dates(dur: &Duration, blackouts: &[Blackout]) -> Vec<NaiveDate> {
+    let mut dates = Vec::new();
+    for blackout in blackouts
+        .iter()
+        .filter(|blackout| blackout.owner_id == dur.owner_id)
+    {
+        for occ in dur.occurrences(blackout.starts_on, blackout.ends_on) {
+            if !dates.contains(&occ.date) {
+                dates.push(occ.date);
+            }
+        }
+    }
+    dates.sort();
+    return dates;
+}
+
+// All-day VEVENTs, where DTEND is the day after the last one. Timed events black out
+// every day they touch.
+pub fn parse_ics(input: &str) -> Result<Vec<Holiday>, String> {
+    let mut holidays = Vec::new();
+
+    for cal in ical::parse(input)? {
+        for event in cal.components.iter().filter(|c| c.name == "VEVENT") {
+            let title = event.value("SUMMARY").unwrap_or("Holiday".to_string());
+            let start = match event.property("DTSTART") {
+                Some(prop) => prop.date_time()?.0,
+                None => return Err(format!("'{}' has no DTSTART", title)),
+            };
+            let end = match event.property("DTEND") {
+                Some(prop) => {
+                    let end = prop.date_time()?.0;
+                    if end.time().num_seconds_from_midnight() == 0 && end.date() > start.date() {
+                        end.date() - Days::days(1)
+                    } else {
+                        end.date()
+                    }
+                }
+                None => start.date(),
+            };
+            holidays.push((title, start.date(), end.max(start.date())));
+        }
+    }
+    return Ok(holidays);
+}
+
+// Lines of `date,title` or `starts_on,ends_on,title` with dates as YYYY-MM-DD. A header
+// line is skipped.
+pub fn parse_csv(input: &str) -> Result<Vec<Holiday>, String> {
+    let mut holidays = Vec::new();
+    let date = |value: &str| NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d");
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(3, ',').collect();
+        let Ok(starts_on) = date(fields[0]) else {
+            if index == 0 {
+                continue;
+            }
+            return Err(format!("line {}: invalid date '{}'", index + 1, fields[0]));
+        };
+
+        let (ends_on, title) = match fields.get(1).map(|field| date(field)) {
+            Some(Ok(ends_on)) => (ends_on, fields.get(2).copied().unwrap_or("")),
+            _ => (starts_on, &line[fields[0].len()..]),
+        };
+        let title = title.trim_start_matches(',').trim().trim_matches('"');
+        if ends_on < starts_on {
+            return Err(format!("line {}: ends before it starts", index + 1));
+        }
+        holidays.push((
+            if title.is_empty() { "Holiday" } else { title }.to_string(),
+            starts_on,
+            ends_on,
+        ));
+    }
+    return Ok(holidays);
+}
+
+// Adds the holidays as blackouts, skipping any the owner already has.
+pub async fn import_holidays<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    holidays: &[Holiday],
+) -> Result<HolidayImportReport, tokio_postgres::Error> {
+    let mut imported = 0;
+    for (title, starts_on, ends_on) in holidays {
+        imported += client
+            .execute(
+                "INSERT INTO public.blackout (owner_id, title, starts_on, ends_on, is_holiday)
+                SELECT $1, $2, $3, $4, true
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM public.blackout
+                    WHERE owner_id = $1 AND title = $2 AND starts_on = $3 AND ends_on = $4
+                )",
+                &[&owner_id, title, starts_on, ends_on],
+            )
+            .await? as usize;
+    }
+    return Ok(HolidayImportReport {
+        imported,
+        skipped: holidays.len() - imported,
+    });
+}
+
+fn parse_holidays(input: &str, format: &str) -> Result<Vec<Holiday>, String> {
+    match format {
+        "csv" => return parse_csv(input),
+        "ics" => return parse_ics(input),
+        _ => return Err(format!("unknown holiday format '{}'", format)),
+    }
+}
+
+// #[get("/blackouts")]
+pub async fn get_owner_blackouts(query: web::Query<OwnerQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    return HttpResponse::Ok().json(get_blackouts(&client, Some(query.owner_id)).await);
+}
+
+// #[post("/blackout")]
+pub async fn create_blackout(
+    _req: HttpRequest,
+    params: web::Json<BlackoutRequest>,
+) -> impl Responder {
+    let ends_on = params.ends_on.unwrap_or(params.starts_on);
+    if ends_on < params.starts_on {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("ends_on must not be before starts_on");
+    }
+
+    let client = connect_to_db().await;
+
+    match client
+        .query_one(
+            "INSERT INTO public.blackout (owner_id, title, starts_on, ends_on)
+            VALUES ($1, $2, $3, $4) RETURNING *",
+            &[&params.owner_id, &params.title, &params.starts_on, &ends_on],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(blackout_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[delete("/blackout/{id}")]
+pub async fn delete_blackout(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    client
+        .execute("DELETE FROM public.blackout WHERE id=$1", &[&info.id])
+        .await
+        .expect("error deleting blackout");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
+
+// #[post("/blackouts/import")]
+// The body is the holiday list itself, as `format=ics` (the default) or `format=csv`.
+pub async fn import_holiday_list(
+    body: String,
+    query: web::Query<HolidayImportQuery>,
+) -> impl Responder {
+    let holidays = match parse_holidays(&body, query.format.as_deref().unwrap_or("ics")) {
+        Ok(holidays) => holidays,
+        Err(msg) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    };
+
+    let client = connect_to_db().await;
+    match import_holidays(&client, query.owner_id, &holidays).await {
+        Ok(report) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(report);
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+pub async fn run_cli(args: &[String]) -> std::io::Result<()> {
+    let usage = "usage: server holidays <file.ics|file.csv> --owner <id>";
+    let mut path = None;
+    let mut owner_id = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--owner" => owner_id = args.next().and_then(|id| id.parse::<i32>().ok()),
+            _ => path = Some(arg.to_owned()),
+        }
+    }
+
+    let (path, owner_id) = match (path, owner_id) {
+        (Some(path), Some(owner_id)) => (path, owner_id),
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    };
+
+    let format = if path.to_lowercase().ends_with(".csv") {
+        "csv"
+    } else {
+        "ics"
+    };
+    let input = std::fs::read_to_string(&path)?;
+    let holidays = match parse_holidays(&input, format) {
+        Ok(holidays) => holidays,
+        Err(err) => {
+            eprintln!("import failed: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let client = connect_to_db().await;
+    match import_holidays(&client, owner_id, &holidays).await {
+        Ok(report) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).expect("error writing report")
+            );
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
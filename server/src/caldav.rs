@@ -0,0 +1,1213 @@
+use crate::{
+    blackout::{self, Blackout},
+    color,
+    connect::connect_to_db,
+    duration::{self, duration_from_row, ConflictPolicy, Duration},
+    exception::{self, DurationException},
+    feed, ical,
+    import::{read_event, read_event_times, read_todo, whole_hours},
+    rank,
+    schedule::{self, Schedule},
+    status, subtask,
+    task::{self, task_from_row, Task},
+    time_entry,
+};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration as Days, Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio_postgres::{Client, GenericClient};
+
+// CalDAV (RFC 4791) on top of the task and duration tables. Every category is a calendar
+// collection holding its tasks as VTODOs and its durations as VEVENTs:
+//
+//   /caldav/{owner_id}/                          calendar home
+//   /caldav/{owner_id}/{category_id}/            calendar collection
+//   /caldav/{owner_id}/{category_id}/{name}.ics  task or duration
+//
+// Clients sign in with HTTP Basic auth using a CalDAV password from `POST /caldav-password`.
+// The calendar feed token only gives read access, so it isn't accepted here.
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+const CALSERVER: &str = "http://calendarserver.org/ns/";
+
+enum DavPath {
+    Root,
+    Home(i32),
+    Collection(i32, i32),
+    Object(i32, i32, String),
+}
+
+#[derive(Clone)]
+enum DavItem {
+    Task(Task),
+    Duration(Duration, Vec<DurationException>, Option<Schedule>),
+}
+
+#[derive(Clone)]
+struct DavObject {
+    name: String,
+    uid: String,
+    item: DavItem,
+}
+
+struct Collection {
+    owner_id: i32,
+    category_id: i32,
+    title: String,
+    description: Option<String>,
+    objects: Vec<DavObject>,
+    open_tasks: Vec<Task>,
+    // Left empty for categories that carry on through blackouts.
+    blackouts: Vec<Blackout>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordRequest {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResponse {
+    password: String,
+}
+
+// A property as (namespace, local name).
+type PropName = (String, String);
+
+fn parse_path(tail: &str) -> Option<DavPath> {
+    let segments: Vec<&str> = tail.split('/').filter(|s| !s.is_empty()).collect();
+
+    return match segments.as_slice() {
+        [] => Some(DavPath::Root),
+        [owner] => Some(DavPath::Home(owner.parse().ok()?)),
+        [owner, category] => Some(DavPath::Collection(owner.parse().ok()?, category.parse().ok()?)),
+        [owner, category, name] => Some(DavPath::Object(
+            owner.parse().ok()?,
+            category.parse().ok()?,
+            name.to_string(),
+        )),
+        _ => None,
+    };
+}
+
+fn home_href(owner_id: i32) -> String {
+    return format!("/caldav/{}/", owner_id);
+}
+
+fn collection_href(owner_id: i32, category_id: i32) -> String {
+    return format!("/caldav/{}/{}/", owner_id, category_id);
+}
+
+fn xml_escape(value: &str) -> String {
+    return value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+fn prefix(ns: &str) -> &'static str {
+    return match ns {
+        DAV => "d",
+        CALDAV => "c",
+        CALSERVER => "cs",
+        _ => "x",
+    };
+}
+
+fn empty_response(status: StatusCode) -> HttpResponse {
+    return HttpResponse::build(status).finish();
+}
+
+fn unauthorized() -> HttpResponse {
+    return HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Basic realm=\"chaos-manager\""))
+        .finish();
+}
+
+async fn authenticate(client: &Client, req: &HttpRequest) -> Option<i32> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+
+    let row = client
+        .query_opt(
+            "SELECT owner_id FROM public.caldav_password WHERE password=$1",
+            &[&password],
+        )
+        .await
+        .expect("error checking caldav password")?;
+    return Some(row.get(0));
+}
+
+// A 64-bit FNV-1a hash of the object as served. Unlike `DefaultHasher` it's fixed, so tags
+// stay the same across restarts and Rust releases. DTSTAMP changes on every render, so it's
+// left out.
+fn etag(calendar_data: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for line in calendar_data.lines().filter(|line| !line.starts_with("DTSTAMP")) {
+        for byte in line.bytes().chain([b'\n']) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    return format!("\"{:016x}\"", hash);
+}
+
+fn set_uid(component: &mut ical::Component, uid: &str) {
+    for prop in component.properties.iter_mut() {
+        if prop.name == "UID" {
+            prop.value = uid.to_string();
+        }
+    }
+}
+
+fn render(collection: &Collection, object: &DavObject) -> String {
+    let mut cal = feed::calendar();
+
+    match &object.item {
+        DavItem::Task(task) => {
+            let mut todo = feed::task_todo(task, Some(&collection.title));
+            set_uid(&mut todo, &object.uid);
+            cal.components.push(todo);
+        }
+        DavItem::Duration(dur, exceptions, schedule) => {
+            let titles = HashMap::from([(collection.category_id, collection.title.to_owned())]);
+            let open_tasks: Vec<&Task> = collection.open_tasks.iter().collect();
+            let exceptions: Vec<&DurationException> = exceptions.iter().collect();
+
+            let blackout_dates = blackout::blackout_dates(dur, &collection.blackouts);
+
+            for mut event in feed::duration_events(
+                dur,
+                schedule.as_ref(),
+                &exceptions,
+                &blackout_dates,
+                &titles,
+                &open_tasks,
+            ) {
+                set_uid(&mut event, &object.uid);
+                cal.components.push(event);
+            }
+        }
+    }
+    return cal.write();
+}
+
+async fn load_collection<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    category_id: i32,
+) -> Option<Collection> {
+    let row = client
+        .query_opt(
+            "SELECT title, description, shows_on_blackout FROM public.category
+            WHERE id=$1 AND owner_id=$2 AND deleted_at IS NULL",
+            &[&category_id, &owner_id],
+        )
+        .await
+        .expect("error getting category")?;
+
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE category_id=$1 AND deleted_at IS NULL ORDER BY priority ASC",
+            &[&category_id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    subtask::hide_trashed_parents(client, &mut tasks).await;
+
+    let mut durations: Vec<Duration> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.duration WHERE category_id=$1 AND deleted_at IS NULL",
+            &[&category_id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        durations.push(duration_from_row(&row));
+    }
+    let ids: Vec<i32> = durations.iter().map(|dur| dur.id).collect();
+    let exceptions = exception::get_exceptions(client, &ids).await;
+    let schedule_ids: Vec<i32> = durations.iter().filter_map(|dur| dur.schedule_id).collect();
+    let schedules = schedule::get_schedules_by_ids(client, &schedule_ids).await;
+
+    // Objects created by a client keep the name and UID the client gave them.
+    let task_ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+    let mut task_names: HashMap<i32, (String, String)> = HashMap::new();
+    let mut duration_names: HashMap<i32, (String, String)> = HashMap::new();
+    for row in client
+        .query(
+            "SELECT name, uid, task_id, duration_id FROM public.caldav_object
+            WHERE task_id = ANY($1) OR duration_id = ANY($2)",
+            &[&task_ids, &ids],
+        )
+        .await
+        .expect("error getting caldav objects")
+    {
+        let names = (row.get(0), row.get(1));
+        if let Some(task_id) = row.get::<_, Option<i32>>(2) {
+            task_names.insert(task_id, names);
+        } else if let Some(duration_id) = row.get::<_, Option<i32>>(3) {
+            duration_names.insert(duration_id, names);
+        }
+    }
+
+    let mut objects = Vec::new();
+    for task in &tasks {
+        let (name, uid) = task_names
+            .remove(&task.id)
+            .unwrap_or((format!("task-{}.ics", task.id), feed::task_uid(task.id)));
+        objects.push(DavObject {
+            name,
+            uid,
+            item: DavItem::Task(task.clone()),
+        });
+    }
+    for dur in durations {
+        let (name, uid) = duration_names
+            .remove(&dur.id)
+            .unwrap_or((format!("duration-{}.ics", dur.id), feed::duration_uid(dur.id)));
+        let dur_exceptions = exceptions
+            .iter()
+            .filter(|exception| exception.duration_id == dur.id)
+            .cloned()
+            .collect();
+        let schedule = schedules
+            .iter()
+            .find(|schedule| Some(schedule.id) == dur.schedule_id)
+            .cloned();
+        objects.push(DavObject {
+            name,
+            uid,
+            item: DavItem::Duration(dur, dur_exceptions, schedule),
+        });
+    }
+
+    let blackouts = if row.get(2) {
+        Vec::new()
+    } else {
+        blackout::get_blackouts(client, Some(owner_id)).await
+    };
+
+    return Some(Collection {
+        owner_id,
+        category_id,
+        title: row.get(0),
+        description: row.get(1),
+        open_tasks: tasks.into_iter().filter(|task| !task.is_complete).collect(),
+        objects,
+        blackouts,
+    });
+}
+
+// Props asked for in a PROPFIND or REPORT body. `None` means "everything", as for allprop or
+// an empty body.
+fn requested_props(body: &str) -> Result<Option<Vec<PropName>>, HttpResponse> {
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+    let doc = roxmltree::Document::parse(body)
+        .map_err(|_| empty_response(StatusCode::BAD_REQUEST))?;
+
+    let prop = doc
+        .root_element()
+        .children()
+        .find(|node| node.tag_name().name() == "prop" && node.tag_name().namespace() == Some(DAV));
+
+    return Ok(prop.map(|prop| {
+        prop.children()
+            .filter(|node| node.is_element())
+            .map(|node| {
+                (
+                    node.tag_name().namespace().unwrap_or("").to_string(),
+                    node.tag_name().name().to_string(),
+                )
+            })
+            .collect()
+    }));
+}
+
+fn prop(ns: &str, name: &str, xml: String) -> (PropName, String) {
+    return ((ns.to_string(), name.to_string()), xml);
+}
+
+fn response_xml(
+    href: &str,
+    available: Vec<(PropName, String)>,
+    requested: &Option<Vec<PropName>>,
+) -> String {
+    let mut found = String::new();
+    let mut missing = String::new();
+
+    let write = |out: &mut String, (ns, name): &PropName, xml: &str| {
+        let tag = match prefix(ns) {
+            "x" => format!("x:{} xmlns:x=\"{}\"", name, xml_escape(ns)),
+            p => format!("{}:{}", p, name),
+        };
+        let close = tag.split(' ').next().unwrap_or("").to_string();
+        if xml.is_empty() {
+            out.push_str(&format!("<{}/>", tag));
+        } else {
+            out.push_str(&format!("<{}>{}</{}>", tag, xml, close));
+        }
+    };
+
+    match requested {
+        None => {
+            for (name, xml) in &available {
+                // calendar-data is only sent when asked for.
+                if name.1 != "calendar-data" {
+                    write(&mut found, name, xml);
+                }
+            }
+        }
+        Some(requested) => {
+            for name in requested {
+                match available.iter().find(|(prop, _)| prop == name) {
+                    Some((_, xml)) => write(&mut found, name, xml),
+                    None => write(&mut missing, name, ""),
+                }
+            }
+        }
+    }
+
+    let mut out = format!("<d:response><d:href>{}</d:href>", xml_escape(href));
+    if !found.is_empty() {
+        out.push_str(&format!(
+            "<d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>",
+            found
+        ));
+    }
+    if !missing.is_empty() {
+        out.push_str(&format!(
+            "<d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>",
+            missing
+        ));
+    }
+    out.push_str("</d:response>");
+    return out;
+}
+
+fn not_found_xml(href: &str) -> String {
+    return format!(
+        "<d:response><d:href>{}</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+        xml_escape(href)
+    );
+}
+
+fn multistatus(responses: Vec<String>) -> HttpResponse {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <d:multistatus xmlns:d=\"{}\" xmlns:c=\"{}\" xmlns:cs=\"{}\">{}</d:multistatus>",
+        DAV,
+        CALDAV,
+        CALSERVER,
+        responses.join("")
+    );
+    return HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(body);
+}
+
+fn principal_props(owner_id: i32) -> Vec<(PropName, String)> {
+    let href = format!("<d:href>{}</d:href>", home_href(owner_id));
+    return vec![
+        prop(DAV, "current-user-principal", href.to_owned()),
+        prop(DAV, "principal-URL", href.to_owned()),
+        prop(CALDAV, "calendar-home-set", href),
+    ];
+}
+
+fn home_props(owner_id: i32) -> Vec<(PropName, String)> {
+    let mut props = principal_props(owner_id);
+    props.push(prop(
+        DAV,
+        "resourcetype",
+        "<d:collection/><d:principal/>".to_string(),
+    ));
+    props.push(prop(DAV, "displayname", "chaos-manager".to_string()));
+    return props;
+}
+
+fn collection_props(collection: &Collection) -> Vec<(PropName, String)> {
+    let etags: Vec<String> = collection
+        .objects
+        .iter()
+        .map(|object| etag(&render(collection, object)))
+        .collect();
+
+    let mut props = principal_props(collection.owner_id);
+    props.push(prop(
+        DAV,
+        "resourcetype",
+        "<d:collection/><c:calendar/>".to_string(),
+    ));
+    props.push(prop(DAV, "displayname", xml_escape(&collection.title)));
+    props.push(prop(
+        CALDAV,
+        "calendar-description",
+        xml_escape(collection.description.as_deref().unwrap_or("")),
+    ));
+    props.push(prop(
+        CALDAV,
+        "supported-calendar-component-set",
+        "<c:comp name=\"VEVENT\"/><c:comp name=\"VTODO\"/>".to_string(),
+    ));
+    props.push(prop(CALSERVER, "getctag", xml_escape(&etag(&etags.join("\n")))));
+    return props;
+}
+
+fn object_props(collection: &Collection, object: &DavObject) -> Vec<(PropName, String)> {
+    let data = render(collection, object);
+    let component = match object.item {
+        DavItem::Task(_) => "vtodo",
+        DavItem::Duration(..) => "vevent",
+    };
+
+    return vec![
+        prop(DAV, "resourcetype", String::new()),
+        prop(DAV, "getetag", xml_escape(&etag(&data))),
+        prop(
+            DAV,
+            "getcontenttype",
+            format!("text/calendar; charset=utf-8; component={}", component),
+        ),
+        prop(CALDAV, "calendar-data", xml_escape(&data)),
+    ];
+}
+
+fn object_href(collection: &Collection, object: &DavObject) -> String {
+    return format!(
+        "{}{}",
+        collection_href(collection.owner_id, collection.category_id),
+        object.name
+    );
+}
+
+async fn propfind(
+    client: &Client,
+    req: &HttpRequest,
+    path: DavPath,
+    owner_id: i32,
+    body: &str,
+) -> HttpResponse {
+    let requested = match requested_props(body) {
+        Ok(requested) => requested,
+        Err(response) => return response,
+    };
+    let depth = req
+        .headers()
+        .get("Depth")
+        .and_then(|depth| depth.to_str().ok())
+        .unwrap_or("1")
+        .to_string();
+
+    let mut responses = Vec::new();
+    match path {
+        DavPath::Root => {
+            responses.push(response_xml("/caldav/", principal_props(owner_id), &requested));
+        }
+        DavPath::Home(_) => {
+            responses.push(response_xml(&home_href(owner_id), home_props(owner_id), &requested));
+            if depth != "0" {
+                for row in client
+                    .query(
+                        "SELECT id FROM public.category
+                        WHERE owner_id=$1 AND deleted_at IS NULL ORDER BY priority ASC",
+                        &[&owner_id],
+                    )
+                    .await
+                    .expect("error getting categories")
+                {
+                    if let Some(collection) = load_collection(client, owner_id, row.get(0)).await {
+                        responses.push(response_xml(
+                            &collection_href(owner_id, collection.category_id),
+                            collection_props(&collection),
+                            &requested,
+                        ));
+                    }
+                }
+            }
+        }
+        DavPath::Collection(_, category_id) => {
+            let collection = match load_collection(client, owner_id, category_id).await {
+                Some(collection) => collection,
+                None => return empty_response(StatusCode::NOT_FOUND),
+            };
+            responses.push(response_xml(
+                &collection_href(owner_id, category_id),
+                collection_props(&collection),
+                &requested,
+            ));
+            if depth != "0" {
+                for object in &collection.objects {
+                    responses.push(response_xml(
+                        &object_href(&collection, object),
+                        object_props(&collection, object),
+                        &requested,
+                    ));
+                }
+            }
+        }
+        DavPath::Object(_, category_id, name) => {
+            let collection = match load_collection(client, owner_id, category_id).await {
+                Some(collection) => collection,
+                None => return empty_response(StatusCode::NOT_FOUND),
+            };
+            match collection.objects.iter().find(|object| object.name == name) {
+                Some(object) => responses.push(response_xml(
+                    &object_href(&collection, object),
+                    object_props(&collection, object),
+                    &requested,
+                )),
+                None => return empty_response(StatusCode::NOT_FOUND),
+            }
+        }
+    }
+    return multistatus(responses);
+}
+
+// calendar-query and calendar-multiget against a single collection.
+async fn report(client: &Client, path: DavPath, owner_id: i32, body: &str) -> HttpResponse {
+    let category_id = match path {
+        DavPath::Collection(_, category_id) => category_id,
+        _ => return empty_response(StatusCode::FORBIDDEN),
+    };
+    let collection = match load_collection(client, owner_id, category_id).await {
+        Some(collection) => collection,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    let doc = match roxmltree::Document::parse(body) {
+        Ok(doc) => doc,
+        Err(_) => return empty_response(StatusCode::BAD_REQUEST),
+    };
+    let requested = match requested_props(body) {
+        Ok(requested) => requested,
+        Err(response) => return response,
+    };
+    let root = doc.root_element();
+
+    let mut responses = Vec::new();
+    match (root.tag_name().namespace(), root.tag_name().name()) {
+        (Some(CALDAV), "calendar-multiget") => {
+            for href in root
+                .descendants()
+                .filter(|node| node.tag_name().name() == "href")
+                .filter_map(|node| node.text())
+            {
+                let href = href.trim();
+                match collection
+                    .objects
+                    .iter()
+                    .find(|object| object_href(&collection, object) == href)
+                {
+                    Some(object) => responses.push(response_xml(
+                        href,
+                        object_props(&collection, object),
+                        &requested,
+                    )),
+                    None => responses.push(not_found_xml(href)),
+                }
+            }
+        }
+        (Some(CALDAV), "calendar-query") => {
+            // The innermost comp-filter picks VEVENT or VTODO; a time-range on it narrows
+            // events down to those with an occurrence in the range.
+            let filter = root
+                .descendants()
+                .rfind(|node| node.tag_name().name() == "comp-filter");
+            let component = filter
+                .and_then(|node| node.attribute("name"))
+                .unwrap_or("VCALENDAR")
+                .to_uppercase();
+            let range = filter
+                .and_then(|node| {
+                    node.children()
+                        .find(|child| child.tag_name().name() == "time-range")
+                })
+                .map(|node| {
+                    let parse = |attr: &str| {
+                        node.attribute(attr)
+                            .and_then(|value| ical::parse_date_time(value).ok())
+                            .map(|(date_time, _)| date_time.date())
+                    };
+                    (parse("start"), parse("end"))
+                });
+
+            for object in &collection.objects {
+                let matches = match (&object.item, component.as_str()) {
+                    (DavItem::Task(_), "VTODO") | (_, "VCALENDAR") => true,
+                    (DavItem::Duration(dur, ..), "VEVENT") => match range {
+                        // Open ended ranges are cut to a few years so the expansion stays finite.
+                        Some((start, end)) => {
+                            let today = Local::now().date_naive();
+                            let start = start.unwrap_or(end.unwrap_or(today) - Days::days(366));
+                            let end = end.unwrap_or(start + Days::days(366 * 5));
+                            !dur.occurrences(start, end).is_empty()
+                        }
+                        None => true,
+                    },
+                    _ => false,
+                };
+                if matches {
+                    responses.push(response_xml(
+                        &object_href(&collection, object),
+                        object_props(&collection, object),
+                        &requested,
+                    ));
+                }
+            }
+        }
+        _ => return empty_response(StatusCode::NOT_IMPLEMENTED),
+    }
+    return multistatus(responses);
+}
+
+async fn get_object(client: &Client, path: DavPath, owner_id: i32) -> HttpResponse {
+    let (category_id, name) = match path {
+        DavPath::Object(_, category_id, name) => (category_id, name),
+        _ => return empty_response(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let collection = match load_collection(client, owner_id, category_id).await {
+        Some(collection) => collection,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    match collection.objects.iter().find(|object| object.name == name) {
+        Some(object) => {
+            let data = render(&collection, object);
+            return HttpResponse::Ok()
+                .insert_header(("ETag", etag(&data)))
+                .content_type("text/calendar; charset=utf-8")
+                .body(data);
+        }
+        None => return empty_response(StatusCode::NOT_FOUND),
+    }
+}
+
+// If-Match and If-None-Match against the object's current ETag, if it exists.
+fn preconditions_fail(req: &HttpRequest, current: Option<&str>) -> bool {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    };
+
+    if let Some(if_match) = header("If-Match") {
+        match current {
+            Some(current) if if_match == "*" || if_match == current => {}
+            _ => return true,
+        }
+    }
+    if let Some(if_none_match) = header("If-None-Match") {
+        if current.is_some() && (if_none_match == "*" || Some(if_none_match.as_str()) == current) {
+            return true;
+        }
+    }
+    return false;
+}
+
+async fn write_task<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    category_id: i32,
+    existing: Option<i32>,
+    todo: &ical::Component,
+) -> Result<(i32, bool), String> {
+    let task = read_todo(todo, &mut Vec::new())?;
+
+    match existing {
+        Some(id) => {
+            // Through the same save as the API, so completing a recurring task over CalDAV
+            // adds its next instance and unblocks whatever was waiting on it.
+            let row = client
+                .query_one("SELECT * FROM public.task WHERE id=$1", &[&id])
+                .await
+                .map_err(|err| err.to_string())?;
+            let mut saved = task_from_row(&row);
+            saved.title = task.title;
+            saved.description = Some(task.description);
+            saved.is_complete = task.is_complete;
+            saved.priority = task.priority;
+            saved.due_at = task.due_at;
+            saved.start_at = task.start_at;
+            task::save_and_complete(client, &mut saved, owner_id)
+                .await
+                .map_err(|err| err.to_string())?;
+            return Ok((id, false));
+        }
+        None => {
+            let row = client
+                .query_one(
+                    "INSERT INTO public.task (
+                        title,
+                        description,
+                        is_complete,
+                        priority,
+                        owner_id,
+                        category_id,
+                        due_at,
+                        start_at,
+                        rank
+                    ) VALUES (
+                        $1, $2, $3, $4, $5, $6, $7, $8,
+                        (SELECT COALESCE(MAX(rank), 0) + $9 FROM public.task WHERE category_id = $6)
+                    ) RETURNING id",
+                    &[
+                        &task.title,
+                        &task.description,
+                        &task.is_complete,
+                        &task.priority,
+                        &owner_id,
+                        &category_id,
+                        &task.due_at,
+                        &task.start_at,
+                        &rank::RANK_STEP,
+                    ],
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+            let id: i32 = row.get(0);
+            status::reconcile(client, id, None)
+                .await
+                .map_err(|err| err.to_string())?;
+            return Ok((id, true));
+        }
+    }
+}
+
+// Writes the series from the master VEVENT and replaces its exceptions with the EXDATEs and
+// RECURRENCE-ID overrides in the body. A series that overlaps the owner's other blocks is
+// turned down, the same as an `on_conflict=reject` request.
+async fn write_duration<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    category_id: i32,
+    existing: Option<i32>,
+    events: &[&ical::Component],
+) -> Result<(i32, bool), String> {
+    let master = events
+        .iter()
+        .find(|event| event.property("RECURRENCE-ID").is_none())
+        .ok_or("no master VEVENT".to_string())?;
+    let dur = read_event(master, &mut Vec::new())?;
+
+    let mut color = dur.color.to_owned();
+    let schedule_id = match existing {
+        Some(id) => {
+            let row = client
+                .query_one(
+                    "SELECT schedule_id, color FROM public.duration WHERE id=$1",
+                    &[&id],
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+            // COLOR only carries the nearest CSS name, so a client sending back what we
+            // served keeps the exact hex.
+            let stored: String = row.get(1);
+            if color::nearest_css_name(&stored) == color::nearest_css_name(&color) {
+                color = stored;
+            }
+            row.get(0)
+        }
+        None => None,
+    };
+    let candidate = Duration {
+        id: existing.unwrap_or_default(),
+        owner_id,
+        category_id,
+        start_hour: dur.start_hour,
+        end_hour: dur.end_hour,
+        recurring_days: dur.recurring_days.to_owned(),
+        color: color.to_owned(),
+        rrule: Some(dur.rrule.to_owned()),
+        dtstart: Some(dur.dtstart),
+        schedule_id,
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        completed_at: None,
+    };
+    if duration::resolve_conflicts(client, &candidate, ConflictPolicy::Reject)
+        .await
+        .is_some()
+    {
+        return Err("the block overlaps another one of yours".to_string());
+    }
+
+    let (id, created) = match existing {
+        Some(id) => {
+            client
+                .execute(
+                    "UPDATE public.duration
+                    SET start_hour = $1,
+                        end_hour = $2,
+                        recurring_days = $3,
+                        color = $4,
+                        rrule = $5,
+                        dtstart = $6,
+                        updated_at = $8
+                    WHERE id = $7",
+                    &[
+                        &dur.start_hour,
+                        &dur.end_hour,
+                        &dur.recurring_days,
+                        &color,
+                        &dur.rrule,
+                        &dur.dtstart,
+                        &id,
+                        &time_entry::now(),
+                    ],
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+            (id, false)
+        }
+        None => {
+            let row = client
+                .query_one(
+                    "INSERT INTO public.duration (
+                        owner_id,
+                        category_id,
+                        start_hour,
+                        end_hour,
+                        recurring_days,
+                        color,
+                        rrule,
+                        dtstart
+                    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+                    &[
+                        &owner_id,
+                        &category_id,
+                        &dur.start_hour,
+                        &dur.end_hour,
+                        &dur.recurring_days,
+                        &color,
+                        &dur.rrule,
+                        &dur.dtstart,
+                    ],
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+            (row.get(0), true)
+        }
+    };
+    duration::stamp_completed(client, id)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    client
+        .execute(
+            "DELETE FROM public.duration_exception WHERE duration_id=$1",
+            &[&id],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+
+    // Blacked out dates come out as EXDATEs too, but they aren't the client's to cancel.
+    let exempt: bool = client
+        .query_one(
+            "SELECT shows_on_blackout FROM public.category WHERE id=$1",
+            &[&category_id],
+        )
+        .await
+        .map_err(|err| err.to_string())?
+        .get(0);
+    let blackouts = blackout::get_blackouts(client, Some(owner_id)).await;
+    for date in &dur.cancelled_dates {
+        if !exempt && blackout::is_blacked_out(&blackouts, owner_id, *date) {
+            continue;
+        }
+        client
+            .execute(
+                "INSERT INTO public.duration_exception (duration_id, occurrence_date, is_cancelled)
+                VALUES ($1, $2, true)
+                ON CONFLICT (duration_id, occurrence_date) DO NOTHING",
+                &[&id, date],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    for event in events.iter().filter(|event| event.property("RECURRENCE-ID").is_some()) {
+        let original: NaiveDateTime = event
+            .property("RECURRENCE-ID")
+            .expect("checked above")
+            .date_time()?
+            .0;
+        let (start, end) = read_event_times(event)?;
+        let (start_hour, end_hour) = whole_hours(start, end)?;
+
+        client
+            .execute(
+                "INSERT INTO public.duration_exception (
+                    duration_id,
+                    occurrence_date,
+                    is_cancelled,
+                    new_date,
+                    start_hour,
+                    end_hour
+                ) VALUES ($1, $2, false, $3, $4, $5)
+                ON CONFLICT (duration_id, occurrence_date) DO UPDATE
+                SET is_cancelled = false,
+                    new_date = EXCLUDED.new_date,
+                    start_hour = EXCLUDED.start_hour,
+                    end_hour = EXCLUDED.end_hour",
+                &[
+                    &id,
+                    &original.date(),
+                    &start.date(),
+                    &start_hour,
+                    &end_hour,
+                ],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    return Ok((id, created));
+}
+
+async fn put_object(
+    client: &mut Client,
+    req: &HttpRequest,
+    path: DavPath,
+    owner_id: i32,
+    body: &str,
+) -> HttpResponse {
+    let (category_id, name) = match path {
+        DavPath::Object(_, category_id, name) => (category_id, name),
+        _ => return empty_response(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let collection = match load_collection(client, owner_id, category_id).await {
+        Some(collection) => collection,
+        None => return empty_response(StatusCode::CONFLICT),
+    };
+    let existing = collection.objects.iter().find(|object| object.name == name);
+
+    let current = existing.map(|object| etag(&render(&collection, object)));
+    if preconditions_fail(req, current.as_deref()) {
+        return empty_response(StatusCode::PRECONDITION_FAILED);
+    }
+
+    let calendars = match ical::parse(body) {
+        Ok(calendars) => calendars,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let components: Vec<&ical::Component> = calendars
+        .iter()
+        .filter(|cal| cal.name == "VCALENDAR")
+        .flat_map(|cal| cal.components.iter())
+        .filter(|component| component.name != "VTIMEZONE")
+        .collect();
+    let events: Vec<&ical::Component> = components
+        .iter()
+        .filter(|component| component.name == "VEVENT")
+        .cloned()
+        .collect();
+    let todo = components.iter().find(|component| component.name == "VTODO");
+    let uid = components
+        .first()
+        .and_then(|component| component.value("UID"))
+        .unwrap_or(name.to_owned());
+
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let result = match (todo, events.is_empty(), existing.map(|object| &object.item)) {
+        (Some(todo), true, None) => write_task(&transaction, owner_id, category_id, None, todo)
+            .await
+            .map(|(id, created)| (Some(id), None, created)),
+        (Some(todo), true, Some(DavItem::Task(task))) => {
+            write_task(&transaction, owner_id, category_id, Some(task.id), todo)
+                .await
+                .map(|(id, created)| (Some(id), None, created))
+        }
+        (None, false, None) => write_duration(&transaction, owner_id, category_id, None, &events)
+            .await
+            .map(|(id, created)| (None, Some(id), created)),
+        (None, false, Some(DavItem::Duration(dur, ..))) => {
+            write_duration(&transaction, owner_id, category_id, Some(dur.id), &events)
+                .await
+                .map(|(id, created)| (None, Some(id), created))
+        }
+        _ => Err("a resource holds one VTODO or one VEVENT series, and can't change type".to_string()),
+    };
+
+    let (task_id, duration_id, created) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            return HttpResponse::build(StatusCode::FORBIDDEN)
+                .content_type("text/plain")
+                .body(err)
+        }
+    };
+
+    if created {
+        transaction
+            .execute(
+                "INSERT INTO public.caldav_object (name, uid, task_id, duration_id)
+                VALUES ($1, $2, $3, $4)",
+                &[&name, &uid, &task_id, &duration_id],
+            )
+            .await
+            .expect("error saving caldav object");
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error saving caldav object");
+
+    let collection = load_collection(client, owner_id, category_id)
+        .await
+        .expect("collection exists");
+    let mut response = match created {
+        true => HttpResponse::Created(),
+        false => HttpResponse::NoContent(),
+    };
+    if let Some(object) = collection.objects.iter().find(|object| object.name == name) {
+        response.insert_header(("ETag", etag(&render(&collection, object))));
+    }
+    return response.finish();
+}
+
+async fn delete_object(
+    client: &Client,
+    req: &HttpRequest,
+    path: DavPath,
+    owner_id: i32,
+) -> HttpResponse {
+    let (category_id, name) = match path {
+        DavPath::Object(_, category_id, name) => (category_id, name),
+        _ => return empty_response(StatusCode::FORBIDDEN),
+    };
+    let collection = match load_collection(client, owner_id, category_id).await {
+        Some(collection) => collection,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+    let object = match collection.objects.iter().find(|object| object.name == name) {
+        Some(object) => object,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    if preconditions_fail(req, Some(&etag(&render(&collection, object)))) {
+        return empty_response(StatusCode::PRECONDITION_FAILED);
+    }
+
+    // Into the trash, the same as a delete through the API; see `trash`.
+    match &object.item {
+        DavItem::Task(task) => {
+            client
+                .execute(
+                    "UPDATE public.task SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL",
+                    &[&task.id, &time_entry::now()],
+                )
+                .await
+                .expect("error deleting task");
+        }
+        DavItem::Duration(dur, ..) => {
+            client
+                .execute(
+                    "UPDATE public.duration SET deleted_at = $2
+                    WHERE id = $1 AND deleted_at IS NULL",
+                    &[&dur.id, &time_entry::now()],
+                )
+                .await
+                .expect("error deleting duration");
+        }
+    }
+    return empty_response(StatusCode::NO_CONTENT);
+}
+
+// #[post("/caldav-password")]
+// Issues a new CalDAV password for the owner. Any previous one stops working.
+pub async fn create_password(
+    _req: HttpRequest,
+    params: web::Json<PasswordRequest>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    match client
+        .query_one(
+            "INSERT INTO public.caldav_password (owner_id, password)
+            VALUES ($1, replace(gen_random_uuid()::TEXT || gen_random_uuid()::TEXT, '-', ''))
+            ON CONFLICT (owner_id) DO UPDATE SET password = EXCLUDED.password
+            RETURNING password",
+            &[&params.owner_id],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(PasswordResponse {
+                    password: row.get(0),
+                });
+        }
+        Err(err) => {
+            return HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[route("/caldav{tail:.*}")]
+pub async fn dispatch(req: HttpRequest, body: String) -> HttpResponse {
+    if req.method().as_str() == "OPTIONS" {
+        return HttpResponse::Ok()
+            .insert_header(("DAV", "1, 3, calendar-access"))
+            .insert_header(("Allow", "OPTIONS, GET, PUT, DELETE, PROPFIND, REPORT"))
+            .finish();
+    }
+
+    let path = match parse_path(req.match_info().query("tail")) {
+        Some(path) => path,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    let mut client = connect_to_db().await;
+    let owner_id = match authenticate(&client, &req).await {
+        Some(owner_id) => owner_id,
+        None => return unauthorized(),
+    };
+    let path_owner = match &path {
+        DavPath::Root => owner_id,
+        DavPath::Home(owner) | DavPath::Collection(owner, _) | DavPath::Object(owner, _, _) => {
+            *owner
+        }
+    };
+    if path_owner != owner_id {
+        return empty_response(StatusCode::FORBIDDEN);
+    }
+
+    match req.method().as_str() {
+        "PROPFIND" => return propfind(&client, &req, path, owner_id, &body).await,
+        "REPORT" => return report(&client, path, owner_id, &body).await,
+        "GET" | "HEAD" => return get_object(&client, path, owner_id).await,
+        "PUT" => return put_object(&mut client, &req, path, owner_id, &body).await,
+        "DELETE" => return delete_object(&client, &req, path, owner_id).await,
+        _ => return empty_response(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// #[get("/.well-known/caldav")]
+pub async fn well_known() -> HttpResponse {
+    return HttpResponse::MovedPermanently()
+        .insert_header(("Location", "/caldav/"))
+        .finish();
+}
@@ -0,0 +1,166 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{expand_occurrences, Occurrence},
+    feed::block_time,
+    planner::{estimate, get_open_tasks},
+};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration as Days, Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DEFAULT_WEEKS: i64 = 4;
+const MAX_WEEKS: i64 = 52;
+// How far ahead to look for the day a backlog would be cleared.
+const PROJECTION_DAYS: i64 = 366;
+
+#[derive(Serialize, Deserialize)]
+pub struct CapacityQuery {
+    owner_id: i32,
+    #[serde(default)]
+    weeks: Option<i64>,
+}
+
+// Minutes a category's blocks give it in one week, counted from today.
+#[derive(Serialize, Deserialize)]
+pub struct WeekCapacity {
+    pub starts_on: NaiveDate,
+    pub allotted_minutes: i64,
+}
+
+// A category's open work against the time its blocks leave for it. Tasks without an
+// estimate count as the planner's default. `clears_on` is the day the open work would be
+// done if every remaining block went to it (today when there's none), or None when that's
+// more than a year out.
+#[derive(Serialize, Deserialize)]
+pub struct CategoryCapacity {
+    pub category_id: i32,
+    pub title: String,
+    pub open_tasks: i64,
+    pub unestimated_tasks: i64,
+    pub open_minutes: i64,
+    pub allotted_minutes: i64,
+    pub over_committed: bool,
+    pub clears_on: Option<NaiveDate>,
+    pub weeks: Vec<WeekCapacity>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Capacity {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub categories: Vec<CategoryCapacity>,
+}
+
+// Minutes of the block still ahead of `now`.
+fn minutes_left(occ: &Occurrence, now: NaiveDateTime) -> i64 {
+    let start = block_time(occ.date, occ.start_hour).max(now);
+    let end = block_time(occ.date, occ.end_hour);
+    return (end - start).num_minutes().max(0);
+}
+
+// The day `minutes` of work runs out, pouring it into the blocks in time order. With no
+// work left it's already clear on `from`.
+fn clear_date(blocks: &[(NaiveDate, i64)], minutes: i64, from: NaiveDate) -> Option<NaiveDate> {
+    let mut remaining = minutes;
+    if remaining <= 0 {
+        return Some(from);
+    }
+    for (date, free) in blocks {
+        remaining -= free;
+        if remaining <= 0 {
+            return Some(*date);
+        }
+    }
+    return None;
+}
+
+// #[get("/capacity")]
+// For each of the owner's categories, the estimated minutes of open tasks against the
+// minutes its blocks allot over the next `weeks` weeks (default four, at most 52). A
+// category is over committed when its open work doesn't fit in that time.
+pub async fn get_capacity(query: web::Query<CapacityQuery>) -> impl Responder {
+    let week_count = query.weeks.unwrap_or(DEFAULT_WEEKS);
+    if !(1..=MAX_WEEKS).contains(&week_count) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(format!("weeks must be between 1 and {}", MAX_WEEKS));
+    }
+
+    let now = Local::now().naive_local();
+    let from = now.date();
+    let to = from + Days::days(week_count * 7 - 1);
+    let client = connect_to_db().await;
+
+    let mut titles: BTreeMap<i32, String> = BTreeMap::new();
+    for row in client
+        .query(
+            "SELECT id, title FROM public.category WHERE owner_id=$1 AND deleted_at IS NULL",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        titles.insert(row.get(0), row.get(1));
+    }
+
+    // (open tasks, unestimated tasks, open minutes) per category.
+    let mut open: BTreeMap<i32, (i64, i64, i64)> = BTreeMap::new();
+    for task in get_open_tasks(&client, query.owner_id).await {
+        let counts = open.entry(task.category_id).or_default();
+        counts.0 += 1;
+        if task.estimate_minutes.is_none() {
+            counts.1 += 1;
+        }
+        counts.2 += estimate(&task);
+    }
+
+    let last = from + Days::days((week_count * 7).max(PROJECTION_DAYS));
+    let mut occurrences = expand_occurrences(&client, Some(query.owner_id), from, last).await;
+    occurrences.sort_by_key(|occ| (occ.date, occ.start_hour));
+
+    let mut categories = Vec::new();
+    for (category_id, title) in titles {
+        let blocks: Vec<(NaiveDate, i64)> = occurrences
+            .iter()
+            .filter(|occ| occ.category_id == category_id)
+            .map(|occ| (occ.date, minutes_left(occ, now)))
+            .collect();
+
+        let weeks: Vec<WeekCapacity> = (0..week_count)
+            .map(|week| {
+                let starts_on = from + Days::days(week * 7);
+                let ends_on = starts_on + Days::days(7);
+                return WeekCapacity {
+                    starts_on,
+                    allotted_minutes: blocks
+                        .iter()
+                        .filter(|(date, _)| *date >= starts_on && *date < ends_on)
+                        .map(|(_, free)| free)
+                        .sum(),
+                };
+            })
+            .collect();
+        let allotted_minutes = weeks.iter().map(|week| week.allotted_minutes).sum();
+
+        let (open_tasks, unestimated_tasks, open_minutes) =
+            open.get(&category_id).copied().unwrap_or_default();
+        categories.push(CategoryCapacity {
+            category_id,
+            title,
+            open_tasks,
+            unestimated_tasks,
+            open_minutes,
+            allotted_minutes,
+            over_committed: open_minutes > allotted_minutes,
+            clears_on: clear_date(&blocks, open_minutes, from),
+            weeks,
+        });
+    }
+
+    return HttpResponse::Ok().json(Capacity {
+        from,
+        to,
+        categories,
+    });
+}
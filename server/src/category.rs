@@ -1,11 +1,18 @@
-use crate::{connect::connect_to_db, task::Task};
+use crate::{
+    connect::connect_to_db,
+    status,
+    subtask::{build_tree, TaskNode},
+    task::{annotate_tasks, apply_query, task_from_row, Task, TaskQuery},
+    time_entry,
+};
 use actix_web::{
     web::Path,
     web::{self},
     HttpRequest, HttpResponse, Responder,
 };
-
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient, Row};
 
 #[derive(Serialize, Deserialize)]
 pub struct InfoPathId {
@@ -19,8 +26,17 @@ pub struct Category<'a> {
     description: Option<String>,
     priority: i32,
     owner_id: i32,
+    counts_as_busy: bool,
+    shows_on_blackout: bool,
     tasks_todo: Vec<&'a Task>,
     tasks_done: Vec<&'a Task>,
+    // With `tree=true`, all of the category's tasks nested under their parents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_tree: Option<Vec<TaskNode>>,
+    created_at: Option<NaiveDateTime>,
+    updated_at: Option<NaiveDateTime>,
+    deleted_at: Option<NaiveDateTime>,
+    completed_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,64 +45,116 @@ pub struct CategoryRequest {
     description: String,
     priority: i32,
     owner_id: i32,
+    // Whether this category's durations show as busy in free/busy lookups.
+    #[serde(default = "default_true")]
+    counts_as_busy: bool,
+    // Whether this category's durations carry on through blackout dates and holidays.
+    #[serde(default)]
+    shows_on_blackout: bool,
+}
+
+fn default_true() -> bool {
+    return true;
+}
+
+pub fn category_from_row<'a>(row: &Row) -> Category<'a> {
+    return Category {
+        id: row.get(0),
+        title: row.get(1),
+        description: row.try_get(2).expect("value was null"),
+        priority: row.get(3),
+        owner_id: row.get(4),
+        counts_as_busy: row.get(5),
+        shows_on_blackout: row.get(6),
+        tasks_todo: Vec::new(),
+        tasks_done: Vec::new(),
+        task_tree: None,
+        created_at: row.get(7),
+        updated_at: row.get(8),
+        deleted_at: row.get(9),
+        completed_at: row.get(10),
+    };
+}
+
+// Stamps completed_at once every task in the category is done and clears it when one is
+// open again. A category without tasks isn't complete.
+pub async fn stamp_completed<C: GenericClient>(client: &C, category_id: i32) -> Result<(), Error> {
+    client
+        .execute(
+            "UPDATE public.category SET completed_at = CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM public.task WHERE category_id = $1 AND deleted_at IS NULL
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM public.task
+                    WHERE category_id = $1 AND deleted_at IS NULL AND NOT is_complete
+                ) THEN COALESCE(completed_at, $2)
+            END
+            WHERE id = $1",
+            &[&category_id, &time_entry::now()],
+        )
+        .await?;
+    return Ok(());
 }
 
 // #[get("/categories")]
-pub async fn get_categories() -> impl Responder {
+// Takes the same filter, sort and tree options as `/tasks` for the tasks inside each
+// category.
+pub async fn get_categories(query: web::Query<TaskQuery>) -> impl Responder {
     let client = connect_to_db().await;
     let mut categories: Vec<Category> = Vec::new();
     let mut tasks: Vec<Task> = Vec::new();
 
     for row in client
-        .query("SELECT * FROM task ORDER BY priority ASC", &[])
+        .query(
+            "SELECT * FROM task WHERE deleted_at IS NULL ORDER BY rank NULLS LAST, id",
+            &[],
+        )
         .await
         .expect("error getting tasks")
     {
-        let task = Task {
-            id: row.get(0),
-            title: row.get(1),
-            description: row.try_get(2).expect("value was null"),
-            is_complete: row.get(3),
-            priority: row.get(4),
-            owner_id: row.get(5),
-            category_id: row.get(6),
-        };
+        let task = task_from_row(&row);
 
         tasks.push(task);
     }
 
+    annotate_tasks(&client, &mut tasks).await;
+    if let Err(msg) = apply_query(&mut tasks, &query, time_entry::now()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
     for row in client
-        .query("SELECT * FROM category", &[])
+        .query("SELECT * FROM category WHERE deleted_at IS NULL", &[])
         .await
         .expect("error getting categories")
     {
-        let cat = Category {
-            id: row.get(0),
-            title: row.get(1),
-            description: row.try_get(2).expect("value was null"),
-            priority: row.get(3),
-            owner_id: row.get(4),
-            tasks_todo: Vec::new(),
-            tasks_done: Vec::new(),
-        };
-
-        categories.push(cat);
+        categories.push(category_from_row(&row));
     }
 
-    for i in 0..tasks.len() {
-        for x in 0..categories.len() {
-            if tasks[i].category_id == categories[x].id {
-                let task = tasks.get(i).expect("error").to_owned();
-
-                if !tasks[i].is_complete {
-                    categories[x].tasks_todo.push(task.to_owned());
+    for task in tasks.iter() {
+        for cat in categories.iter_mut() {
+            if task.category_id == cat.id {
+                if !task.is_complete {
+                    cat.tasks_todo.push(task);
                 } else {
-                    categories[x].tasks_done.push(task.to_owned());
+                    cat.tasks_done.push(task);
                 }
             }
         }
     }
 
+    if query.tree {
+        for cat in categories.iter_mut() {
+            let own = tasks
+                .iter()
+                .filter(|task| task.category_id == cat.id)
+                .cloned()
+                .collect();
+            cat.task_tree = Some(build_tree(own));
+        }
+    }
+
     return HttpResponse::Ok().json(categories);
 }
 
@@ -102,6 +170,8 @@ pub async fn create_category(
         description: params.description.to_owned(),
         priority: params.priority,
         owner_id: params.owner_id,
+        counts_as_busy: params.counts_as_busy,
+        shows_on_blackout: params.shows_on_blackout,
     };
 
     match client
@@ -110,14 +180,26 @@ pub async fn create_category(
                 title, 
                 description, 
                 priority, 
-                owner_id
-            ) VALUES ($1, $2, $3, $4) RETURNING id",
-            &[&cat.title, &cat.description, &cat.priority, &cat.owner_id],
+                owner_id,
+                counts_as_busy,
+                shows_on_blackout
+            ) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            &[
+                &cat.title,
+                &cat.description,
+                &cat.priority,
+                &cat.owner_id,
+                &cat.counts_as_busy,
+                &cat.shows_on_blackout,
+            ],
         )
         .await
     {
         Ok(data) => {
             let id: i32 = data.get(0);
+            status::ensure_statuses(&client, id)
+                .await
+                .expect("error adding statuses");
             return HttpResponse::Created()
                 .content_type("application/json")
                 .json(id);
@@ -140,15 +222,7 @@ pub async fn get_category_by_id(info: Path<InfoPathId>) -> impl Responder {
         .await
         .expect("error getting category");
 
-    let cat = Category {
-        id: row.get(0),
-        title: row.get(1),
-        description: row.try_get(2).expect("value was null"),
-        priority: row.get(3),
-        owner_id: row.get(4),
-        tasks_done: Vec::new(),
-        tasks_todo: Vec::new(),
-    };
+    let cat = category_from_row(&row);
 
     return HttpResponse::Ok().json(cat);
 }
@@ -162,35 +236,50 @@ pub async fn update_category(
     let client = connect_to_db().await;
     let cat_id = info.id;
 
-    let category = Category {
+    let mut category = Category {
         id: cat_id,
         title: params.title.to_owned(),
         description: Some(params.description.to_owned()),
         priority: params.priority,
         owner_id: params.owner_id,
+        counts_as_busy: params.counts_as_busy,
+        shows_on_blackout: params.shows_on_blackout,
         tasks_done: Vec::new(),
         tasks_todo: Vec::new(),
+        task_tree: None,
+        created_at: None,
+        updated_at: Some(time_entry::now()),
+        deleted_at: None,
+        completed_at: None,
     };
 
     match client
-        .execute(
+        .query_one(
             "UPDATE public.category
             SET title = $1, 
                 description = $2,
                 priority = $3, 
-                owner_id = $4
-             WHERE id = $5",
+                owner_id = $4,
+                counts_as_busy = $5,
+                shows_on_blackout = $6,
+                updated_at = $8
+             WHERE id = $7 RETURNING created_at, completed_at",
             &[
                 &category.title,
                 &category.description,
                 &category.priority,
                 &category.owner_id,
+                &category.counts_as_busy,
+                &category.shows_on_blackout,
                 &category.id,
+                &category.updated_at,
             ],
         )
         .await
     {
-        Ok(_data) => {
+        Ok(data) => {
+            category.created_at = data.get(0);
+            category.completed_at = data.get(1);
             return HttpResponse::Created()
                 .content_type("application/json")
                 .json(category);
@@ -204,14 +293,31 @@ pub async fn update_category(
 }
 
 // #[delete("/category/{id}")]
+// Moves the category to the trash along with its tasks and durations, all stamped with the
+// same deleted_at so restoring the category brings back exactly those.
 pub async fn delete_category_by_id(info: Path<InfoPathId>) -> impl Responder {
     let id = info.id;
-    let client = connect_to_db().await;
+    let now = time_entry::now();
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
 
-    client
-        .execute("DELETE FROM public.category WHERE id=$1", &[&id])
+    for sql in [
+        "UPDATE public.category SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL",
+        "UPDATE public.task SET deleted_at = $2 WHERE category_id = $1 AND deleted_at IS NULL",
+        "UPDATE public.duration SET deleted_at = $2 WHERE category_id = $1 AND deleted_at IS NULL",
+    ] {
+        transaction
+            .execute(sql, &[&id, &now])
+            .await
+            .expect("error deleting category");
+    }
+    transaction
+        .commit()
         .await
-        .expect("error deleting category");
+        .expect("error committing transaction");
 
     return HttpResponse::Ok().json("Deleted Item");
 }
@@ -1,15 +1,18 @@
-use tokio;
-use tokio_postgres::{Client, NoTls};
+use tokio_postgres::{Client, Error, NoTls};
 use std::env;
 extern crate dotenv;
 
 pub async fn connect_to_db() -> Client {
+    return try_connect_to_db().await.expect("erorr connecting");
+}
+
+// For the background loops, which log a failed connection and try again on the next tick
+// instead of going down with it.
+pub async fn try_connect_to_db() -> Result<Client, Error> {
     dotenv::dotenv().expect("Must be illiterate because I can't read an .env file: ");
     
     let url = env::var("DB_URL").expect("Hey mann, there's no variable!");
-    let (client, connection) = tokio_postgres::connect(&url, NoTls)
-        .await
-        .expect("erorr connecting");
+    let (client, connection) = tokio_postgres::connect(&url, NoTls).await?;
 
     // The connection object performs the actual communication with the database,
     // so spawn it off to run on its own.
@@ -19,6 +22,12 @@ pub async fn connect_to_db() -> Client {
         }
     });
 
+    return Ok(client);
+}
+
+// Creates and updates the tables. Run once when the server or a CLI command starts, not on
+// every connection.
+pub async fn migrate(client: &Client) {
     client
         .batch_execute(
             "
@@ -47,6 +56,24 @@ pub async fn connect_to_db() -> Client {
         .await
         .expect("create category table error");
 
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.category
+                ADD COLUMN IF NOT EXISTS counts_as_busy BOOL NOT NULL DEFAULT true;",
+        )
+        .await
+        .expect("add category busy column error");
+
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.category
+                ADD COLUMN IF NOT EXISTS shows_on_blackout BOOL NOT NULL DEFAULT false;",
+        )
+        .await
+        .expect("add category blackout column error");
+
     client
         .batch_execute(
             "
@@ -62,6 +89,213 @@ pub async fn connect_to_db() -> Client {
         )
         .await
         .expect("create task table error");
+
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.task
+                ADD COLUMN IF NOT EXISTS estimate_minutes INT;",
+        )
+        .await
+        .expect("add task estimate column error");
+
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.task
+                ADD COLUMN IF NOT EXISTS due_at TIMESTAMP,
+                ADD COLUMN IF NOT EXISTS start_at TIMESTAMP;",
+        )
+        .await
+        .expect("add task dates error");
+
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.task
+                ADD COLUMN IF NOT EXISTS rrule VARCHAR,
+                ADD COLUMN IF NOT EXISTS repeat_after_days INT,
+                ADD COLUMN IF NOT EXISTS series_id INT REFERENCES public.task ON DELETE SET NULL;",
+        )
+        .await
+        .expect("add task recurrence error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_completion (
+            id SERIAL PRIMARY KEY,
+            task_id INT NOT NULL UNIQUE REFERENCES public.task ON DELETE CASCADE,
+            series_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            owner_id INT NOT NULL REFERENCES public.user,
+            due_at TIMESTAMP,
+            completed_at TIMESTAMP NOT NULL,
+            next_task_id INT REFERENCES public.task ON DELETE SET NULL
+        );",
+        )
+        .await
+        .expect("create task completion table error");
+
+    client
+        .batch_execute(
+            "
+            ALTER TABLE public.task
+                ADD COLUMN IF NOT EXISTS parent_id INT REFERENCES public.task ON DELETE SET NULL;",
+        )
+        .await
+        .expect("add task parent column error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_dependency (
+            task_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            blocked_by INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            PRIMARY KEY (task_id, blocked_by),
+            CHECK (task_id <> blocked_by)
+        );",
+        )
+        .await
+        .expect("create task dependency table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.tag (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            name VARCHAR NOT NULL,
+            color VARCHAR NOT NULL,
+            UNIQUE (owner_id, name)
+        );",
+        )
+        .await
+        .expect("create tag table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_tag (
+            task_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            tag_id INT NOT NULL REFERENCES public.tag ON DELETE CASCADE,
+            PRIMARY KEY (task_id, tag_id)
+        );",
+        )
+        .await
+        .expect("create task tag table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_status (
+            id SERIAL PRIMARY KEY,
+            category_id INT NOT NULL REFERENCES public.category ON DELETE CASCADE,
+            title VARCHAR NOT NULL,
+            position INT NOT NULL,
+            is_done BOOL NOT NULL DEFAULT false,
+            UNIQUE (category_id, title)
+        );
+        ALTER TABLE public.task
+            ADD COLUMN IF NOT EXISTS status_id INT REFERENCES public.task_status ON DELETE SET NULL;",
+        )
+        .await
+        .expect("create task status table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_status_change (
+            id SERIAL PRIMARY KEY,
+            task_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            from_status_id INT REFERENCES public.task_status ON DELETE SET NULL,
+            to_status_id INT REFERENCES public.task_status ON DELETE SET NULL,
+            changed_at TIMESTAMP NOT NULL
+        );",
+        )
+        .await
+        .expect("create task status change table error");
+
+    // Moves tasks from before statuses existed onto the default To do and Done columns.
+    client
+        .batch_execute(
+            "
+        INSERT INTO public.task_status (category_id, title, position, is_done)
+        SELECT category.id, s.title, s.position, s.is_done
+        FROM public.category
+        CROSS JOIN (VALUES ('To do', 0, false), ('Done', 1, true)) AS s(title, position, is_done)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM public.task_status WHERE category_id = category.id
+        );
+        UPDATE public.task SET status_id = (
+            SELECT id FROM public.task_status
+            WHERE category_id = task.category_id AND is_done = task.is_complete
+            ORDER BY position, id LIMIT 1
+        ) WHERE status_id IS NULL;",
+        )
+        .await
+        .expect("migrate task statuses error");
+
+    // Tasks without a rank are ordered the way categories listed them before.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.task ADD COLUMN IF NOT EXISTS rank FLOAT8;
+        UPDATE public.task SET rank = ordered.position * 1024
+        FROM (
+            SELECT id, ROW_NUMBER() OVER (
+                PARTITION BY category_id ORDER BY priority, id
+            ) AS position
+            FROM public.task
+        ) AS ordered
+        WHERE task.id = ordered.id
+            AND NOT EXISTS (
+                SELECT 1 FROM public.task ranked
+                WHERE ranked.category_id = task.category_id AND ranked.rank IS NOT NULL
+            );",
+        )
+        .await
+        .expect("add task rank error");
+
+    // Existing rows are dated from the migration; completed tasks take the time their
+    // completion or move into a done status was recorded, where there is one.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.category
+            ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP,
+            ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP;
+        ALTER TABLE public.task
+            ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP,
+            ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP,
+            ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP;
+        UPDATE public.task SET completed_at = COALESCE(
+            (SELECT completed_at FROM public.task_completion WHERE task_id = task.id),
+            (
+                SELECT MAX(change.changed_at) FROM public.task_status_change change
+                JOIN public.task_status s ON s.id = change.to_status_id
+                WHERE change.task_id = task.id AND s.is_done
+            )
+        ) WHERE is_complete AND completed_at IS NULL;",
+        )
+        .await
+        .expect("add timestamps error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.task_history (
+            id SERIAL PRIMARY KEY,
+            task_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            field VARCHAR NOT NULL,
+            old_value TEXT,
+            new_value TEXT,
+            actor_id INT REFERENCES public.user ON DELETE SET NULL,
+            changed_at TIMESTAMP NOT NULL
+        );",
+        )
+        .await
+        .expect("create task history table error");
+
     client
         .batch_execute(
             "
@@ -78,5 +312,277 @@ pub async fn connect_to_db() -> Client {
         .await
         .expect("create duration table error");
 
-    return client;
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.duration
+            ADD COLUMN IF NOT EXISTS rrule TEXT,
+            ADD COLUMN IF NOT EXISTS dtstart DATE;",
+        )
+        .await
+        .expect("add duration recurrence columns error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.duration_exception (
+            id SERIAL PRIMARY KEY,
+            duration_id INT NOT NULL REFERENCES public.duration ON DELETE CASCADE,
+            occurrence_date DATE NOT NULL,
+            is_cancelled BOOL NOT NULL,
+            new_date DATE,
+            start_hour INT,
+            end_hour INT,
+            category_id INT REFERENCES public.category,
+            UNIQUE (duration_id, occurrence_date)
+        );",
+        )
+        .await
+        .expect("create duration exception table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.schedule (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            title VARCHAR(255) NOT NULL,
+            starts_on DATE NOT NULL,
+            ends_on DATE
+        );
+
+        ALTER TABLE public.duration
+            ADD COLUMN IF NOT EXISTS schedule_id INT REFERENCES public.schedule ON DELETE CASCADE;",
+        )
+        .await
+        .expect("create schedule table error");
+
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.duration
+            ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP,
+            ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP;",
+        )
+        .await
+        .expect("add duration timestamps error");
+
+    // Deleted rows stay around with deleted_at set until the trash is purged. See `trash`.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.category ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;
+        ALTER TABLE public.task ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;
+        ALTER TABLE public.duration ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;",
+        )
+        .await
+        .expect("add deleted columns error");
+
+    client
+        .batch_execute("ALTER TABLE public.schedule ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;")
+        .await
+        .expect("add schedule deleted column error");
+
+    // A category is complete once all its tasks are, and a duration once its last block
+    // is over. Both are kept up to date as they're written, not backfilled.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.category ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP;
+        ALTER TABLE public.duration ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP;",
+        )
+        .await
+        .expect("add completed columns error");
+
+    // A recurring series counts COUNT and UNTIL from where its rule was set, kept apart from
+    // the due dates so an undated series doesn't start over at each instance.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.task ADD COLUMN IF NOT EXISTS series_dtstart TIMESTAMP;
+        UPDATE public.task AS task
+            SET series_dtstart = COALESCE(head.due_at, head.created_at)
+            FROM public.task AS head
+            WHERE head.id = COALESCE(task.series_id, task.id)
+                AND task.rrule IS NOT NULL
+                AND task.series_dtstart IS NULL;",
+        )
+        .await
+        .expect("add series dtstart column error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.calendar_feed (
+            owner_id INT PRIMARY KEY REFERENCES public.user,
+            token TEXT NOT NULL UNIQUE
+        );",
+        )
+        .await
+        .expect("create calendar feed table error");
+
+    // Kept apart from the feed token: the feed is read only, CalDAV can write.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.caldav_password (
+            owner_id INT PRIMARY KEY REFERENCES public.user,
+            password TEXT NOT NULL UNIQUE
+        );",
+        )
+        .await
+        .expect("create caldav password table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.caldav_object (
+            id SERIAL PRIMARY KEY,
+            name TEXT NOT NULL,
+            uid TEXT NOT NULL,
+            task_id INT UNIQUE REFERENCES public.task ON DELETE CASCADE,
+            duration_id INT UNIQUE REFERENCES public.duration ON DELETE CASCADE
+        );",
+        )
+        .await
+        .expect("create caldav object table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.plan_item (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            task_id INT NOT NULL REFERENCES public.task ON DELETE CASCADE,
+            duration_id INT NOT NULL REFERENCES public.duration ON DELETE CASCADE,
+            category_id INT NOT NULL REFERENCES public.category,
+            occurrence_date DATE NOT NULL,
+            starts_at TIMESTAMP NOT NULL,
+            ends_at TIMESTAMP NOT NULL
+        );",
+        )
+        .await
+        .expect("create plan item table error");
+
+    // A partial unique index keeps each owner down to one running timer.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.time_entry (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            task_id INT REFERENCES public.task ON DELETE SET NULL,
+            category_id INT NOT NULL REFERENCES public.category,
+            started_at TIMESTAMP NOT NULL,
+            ended_at TIMESTAMP,
+            note TEXT,
+            CHECK (ended_at IS NULL OR ended_at > started_at)
+        );
+
+        CREATE UNIQUE INDEX IF NOT EXISTS time_entry_running
+            ON public.time_entry (owner_id) WHERE ended_at IS NULL;",
+        )
+        .await
+        .expect("create time entry table error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.pomodoro_session (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            task_id INT REFERENCES public.task ON DELETE SET NULL,
+            category_id INT NOT NULL REFERENCES public.category,
+            work_minutes INT NOT NULL,
+            break_minutes INT NOT NULL,
+            phase VARCHAR(15) NOT NULL,
+            phase_started_at TIMESTAMP NOT NULL,
+            started_at TIMESTAMP NOT NULL,
+            ended_at TIMESTAMP
+        );
+
+        CREATE UNIQUE INDEX IF NOT EXISTS pomodoro_session_active
+            ON public.pomodoro_session (owner_id) WHERE ended_at IS NULL;
+
+        CREATE TABLE IF NOT EXISTS public.pomodoro_interval (
+            id SERIAL PRIMARY KEY,
+            session_id INT NOT NULL REFERENCES public.pomodoro_session ON DELETE CASCADE,
+            time_entry_id INT REFERENCES public.time_entry ON DELETE SET NULL,
+            started_at TIMESTAMP NOT NULL,
+            ended_at TIMESTAMP NOT NULL
+        );",
+        )
+        .await
+        .expect("create pomodoro tables error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.notification_preference (
+            owner_id INT PRIMARY KEY REFERENCES public.user,
+            block_minutes_before INT,
+            task_minutes_before INT,
+            sse_enabled BOOL NOT NULL DEFAULT true,
+            webhook_url TEXT,
+            email TEXT,
+            quiet_start INT,
+            quiet_end INT
+        );
+
+        CREATE TABLE IF NOT EXISTS public.notification (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            kind VARCHAR(31) NOT NULL,
+            reference TEXT NOT NULL,
+            title TEXT NOT NULL,
+            body TEXT NOT NULL,
+            fire_at TIMESTAMP NOT NULL,
+            delivered_at TIMESTAMP,
+            suppressed BOOL NOT NULL DEFAULT false,
+            UNIQUE (owner_id, kind, reference)
+        );",
+        )
+        .await
+        .expect("create notification tables error");
+
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS public.blackout (
+            id SERIAL PRIMARY KEY,
+            owner_id INT NOT NULL REFERENCES public.user,
+            title TEXT NOT NULL,
+            starts_on DATE NOT NULL,
+            ends_on DATE NOT NULL,
+            is_holiday BOOL NOT NULL DEFAULT false,
+            CHECK (ends_on >= starts_on)
+        );",
+        )
+        .await
+        .expect("create blackout table error");
+
+    // Purging a category from the trash takes the time tracked and planned against it along;
+    // exceptions that moved a block into it fall back to the block's own category.
+    client
+        .batch_execute(
+            "
+        ALTER TABLE public.time_entry
+            DROP CONSTRAINT IF EXISTS time_entry_category_id_fkey,
+            ADD CONSTRAINT time_entry_category_id_fkey FOREIGN KEY (category_id)
+                REFERENCES public.category ON DELETE CASCADE;
+        ALTER TABLE public.pomodoro_session
+            DROP CONSTRAINT IF EXISTS pomodoro_session_category_id_fkey,
+            ADD CONSTRAINT pomodoro_session_category_id_fkey FOREIGN KEY (category_id)
+                REFERENCES public.category ON DELETE CASCADE;
+        ALTER TABLE public.plan_item
+            DROP CONSTRAINT IF EXISTS plan_item_category_id_fkey,
+            ADD CONSTRAINT plan_item_category_id_fkey FOREIGN KEY (category_id)
+                REFERENCES public.category ON DELETE CASCADE;
+        ALTER TABLE public.duration_exception
+            DROP CONSTRAINT IF EXISTS duration_exception_category_id_fkey,
+            ADD CONSTRAINT duration_exception_category_id_fkey FOREIGN KEY (category_id)
+                REFERENCES public.category ON DELETE SET NULL;",
+        )
+        .await
+        .expect("alter category references error");
 }
@@ -0,0 +1,217 @@
+use crate::{
+    connect::connect_to_db,
+    task::{annotate_tasks, task_from_row, InfoPathId, Task},
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient};
+
+#[derive(Serialize, Deserialize)]
+pub struct DependencyPath {
+    id: i32,
+    blocked_by: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DependencyRequest {
+    blocked_by: i32,
+}
+
+// Both directions for one task: what it's waiting on and what's waiting on it.
+#[derive(Serialize, Deserialize)]
+pub struct Dependencies {
+    pub blocked_by: Vec<Task>,
+    pub blocks: Vec<Task>,
+}
+
+// Tasks waiting on at least one task that isn't done yet.
+pub async fn blocked_ids<C: GenericClient>(client: &C) -> Result<Vec<i32>, Error> {
+    let mut ids = Vec::new();
+    for row in client
+        .query(
+            "SELECT DISTINCT d.task_id
+            FROM public.task_dependency d
+            JOIN public.task blocker ON blocker.id = d.blocked_by
+            WHERE NOT blocker.is_complete AND blocker.deleted_at IS NULL",
+            &[],
+        )
+        .await?
+    {
+        ids.push(row.get(0));
+    }
+    return Ok(ids);
+}
+
+// Fills in `is_blocked`, which isn't stored with the task.
+pub async fn mark_blocked<C: GenericClient>(client: &C, tasks: &mut [Task]) {
+    let blocked = blocked_ids(client)
+        .await
+        .expect("error getting dependencies");
+    for task in tasks.iter_mut() {
+        task.is_blocked = blocked.contains(&task.id);
+    }
+}
+
+// Whether `task_id` already waits on `blocker_id`, directly or through other tasks.
+async fn waits_on<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+    blocker_id: i32,
+) -> Result<bool, Error> {
+    let row = client
+        .query_one(
+            "WITH RECURSIVE upstream AS (
+                SELECT blocked_by FROM public.task_dependency WHERE task_id = $1
+                UNION
+                SELECT d.blocked_by FROM public.task_dependency d
+                JOIN upstream ON d.task_id = upstream.blocked_by
+            ) SELECT EXISTS (SELECT 1 FROM upstream WHERE blocked_by = $2)",
+            &[&task_id, &blocker_id],
+        )
+        .await?;
+    return Ok(row.get(0));
+}
+
+// Tasks that were waiting on `task_id` and have nothing left to wait on now that it's done.
+pub async fn newly_unblocked<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+) -> Result<Vec<Task>, Error> {
+    let mut tasks = Vec::new();
+    for row in client
+        .query(
+            "SELECT t.* FROM public.task t
+            JOIN public.task_dependency d ON d.task_id = t.id AND d.blocked_by = $1
+            WHERE t.deleted_at IS NULL AND NOT EXISTS (
+                SELECT 1 FROM public.task_dependency other
+                JOIN public.task blocker ON blocker.id = other.blocked_by
+                WHERE other.task_id = t.id AND NOT blocker.is_complete
+                    AND blocker.deleted_at IS NULL
+            )
+            ORDER BY t.priority ASC, t.id",
+            &[&task_id],
+        )
+        .await?
+    {
+        tasks.push(task_from_row(&row));
+    }
+    return Ok(tasks);
+}
+
+async fn get_related<C: GenericClient>(client: &C, sql: &str, task_id: i32) -> Vec<Task> {
+    let mut tasks = Vec::new();
+    for row in client
+        .query(sql, &[&task_id])
+        .await
+        .expect("error getting dependencies")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    annotate_tasks(client, &mut tasks).await;
+    return tasks;
+}
+
+// #[get("/task/{id}/dependencies")]
+pub async fn get_dependencies(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let blocked_by = get_related(
+        &client,
+        "SELECT t.* FROM public.task t
+        JOIN public.task_dependency d ON d.blocked_by = t.id
+        WHERE d.task_id = $1 AND t.deleted_at IS NULL ORDER BY t.id",
+        info.id,
+    )
+    .await;
+    let blocks = get_related(
+        &client,
+        "SELECT t.* FROM public.task t
+        JOIN public.task_dependency d ON d.task_id = t.id
+        WHERE d.blocked_by = $1 AND t.deleted_at IS NULL ORDER BY t.id",
+        info.id,
+    )
+    .await;
+
+    return HttpResponse::Ok().json(Dependencies { blocked_by, blocks });
+}
+
+// #[post("/task/{id}/dependencies")]
+// Makes the task wait on `blocked_by`. Refused when `blocked_by` already waits on the task,
+// since neither could ever start.
+pub async fn add_dependency(
+    _req: HttpRequest,
+    info: Path<InfoPathId>,
+    params: web::Json<DependencyRequest>,
+) -> impl Responder {
+    if info.id == params.blocked_by {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("a task can't wait on itself");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    // Dependencies are only checked one insert at a time, so take the table for the check.
+    transaction
+        .batch_execute("LOCK TABLE public.task_dependency IN SHARE ROW EXCLUSIVE MODE")
+        .await
+        .expect("error locking dependencies");
+
+    if waits_on(&transaction, params.blocked_by, info.id)
+        .await
+        .expect("error checking dependencies")
+    {
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(format!(
+                "task {} already waits on task {}",
+                params.blocked_by, info.id
+            ));
+    }
+
+    match transaction
+        .execute(
+            "INSERT INTO public.task_dependency (task_id, blocked_by)
+            VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&info.id, &params.blocked_by],
+        )
+        .await
+    {
+        Ok(_) => {
+            transaction
+                .commit()
+                .await
+                .expect("error committing transaction");
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json("Added dependency");
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[delete("/task/{id}/dependency/{blocked_by}")]
+pub async fn delete_dependency(info: Path<DependencyPath>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    client
+        .execute(
+            "DELETE FROM public.task_dependency WHERE task_id=$1 AND blocked_by=$2",
+            &[&info.id, &info.blocked_by],
+        )
+        .await
+        .expect("error deleting dependency");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
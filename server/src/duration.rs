@@ -1,34 +1,606 @@
-use crate::connect::connect_to_db;
+use crate::{
+    blackout, connect::connect_to_db, exception, feed, recurrence::Recurrence, schedule, time_entry,
+};
 use actix_web::{
     web::{self, Path},
     HttpRequest, HttpResponse, Responder,
 };
-use serde::{Deserialize, Serialize};
+use chrono::{Datelike, Duration as Days, Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio_postgres::{GenericClient, Row};
 // mod connect;
 #[derive(Serialize, Deserialize)]
 pub struct InfoPathId {
     pub id: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Duration {
     pub id: i32,
     pub owner_id: i32,
     pub category_id: i32,
     pub start_hour: i32,
     pub end_hour: i32,
-    pub recurring_days:Vec<i32>,
+    #[serde(default)]
+    pub recurring_days: Vec<i32>,
     pub color: String,
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub dtstart: Option<NaiveDate>,
+    #[serde(default)]
+    pub schedule_id: Option<i32>,
+    // Kept by the server.
+    #[serde(default)]
+    pub created_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub updated_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deleted_at: Option<NaiveDateTime>,
+    // When the last block ends, for a series with a COUNT or UNTIL. It can still be ahead.
+    #[serde(default)]
+    pub completed_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DurationReq {
-    owner_id: i32,
-    category_id: i32,
+    pub owner_id: i32,
+    pub category_id: i32,
+    pub start_hour: i32,
+    pub end_hour: i32,
+    #[serde(default)]
+    pub recurring_days: Vec<i32>,
+    pub color: String,
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub dtstart: Option<NaiveDate>,
+    #[serde(default)]
+    pub schedule_id: Option<i32>,
+}
+
+// The body of `PUT /duration`. Recurrence fields that are left out keep their stored
+// values; `schedule_id: null` takes the duration off its schedule.
+#[derive(Deserialize)]
+pub struct DurationUpdate {
+    pub id: i32,
+    pub owner_id: i32,
+    pub category_id: i32,
+    pub start_hour: i32,
+    pub end_hour: i32,
+    #[serde(default)]
+    pub recurring_days: Vec<i32>,
+    pub color: String,
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub dtstart: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "present")]
+    pub schedule_id: Option<Option<i32>>,
+}
+
+// Tells a field sent as null apart from one that wasn't sent at all.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<i32>>, D::Error> {
+    return Option::deserialize(deserializer).map(Some);
+}
+
+// One concrete day a duration lands on.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Occurrence {
+    pub duration_id: i32,
+    pub owner_id: i32,
+    pub category_id: i32,
+    pub date: NaiveDate,
+    pub start_hour: i32,
+    pub end_hour: i32,
+    pub color: String,
+    pub exception_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OccurrenceQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub owner_id: Option<i32>,
+}
+
+// What to do when a new or updated duration shares an hour with an existing one.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Reject,
+    Allow,
+    Trim,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConflictQuery {
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Serialize)]
+pub struct ConflictResponse {
+    message: String,
+    conflicts: Vec<Duration>,
+}
+
+#[derive(Serialize)]
+pub struct DurationConflict {
+    first: Duration,
+    second: Duration,
+    days: Vec<i32>,
     start_hour: i32,
     end_hour: i32,
-    recurring_days: Vec<i32>,
-    color: String,
+}
+
+// How far ahead two irregular series are compared when looking for a shared date.
+const CONFLICT_HORIZON_DAYS: i64 = 366 * 2;
+
+pub const DEFAULT_COLOR: &str = "#3366ff";
+
+pub fn duration_from_row(row: &Row) -> Duration {
+    return Duration {
+        id: row.get(0),
+        owner_id: row.get(1),
+        category_id: row.get(2),
+        start_hour: row.get(3),
+        end_hour: row.get(4),
+        recurring_days: row.get(5),
+        color: row.get(6),
+        rrule: row.get(7),
+        dtstart: row.get(8),
+        schedule_id: row.get(9),
+        created_at: row.get(10),
+        updated_at: row.get(11),
+        deleted_at: row.get(12),
+        completed_at: row.get(13),
+    };
+}
+
+impl Duration {
+    // Rows written before rrules existed only have `recurring_days`. Their rule is plain
+    // weekly, which looks the same from any anchor, so `fallback_start` is safe to use.
+    pub fn recurrence(&self, fallback_start: NaiveDate) -> (Recurrence, NaiveDate) {
+        let rule = match &self.rrule {
+            Some(rrule) => Recurrence::parse(rrule)
+                .unwrap_or_else(|_| Recurrence::from_weekdays(&self.recurring_days)),
+            None => Recurrence::from_weekdays(&self.recurring_days),
+        };
+        return (rule, self.dtstart.unwrap_or(fallback_start));
+    }
+
+    // The end of the series' last block, or None when it goes on forever.
+    pub fn last_block_end(&self) -> Option<NaiveDateTime> {
+        let (rule, dtstart) = self.recurrence(Local::now().date_naive());
+        let last_day = match (rule.count, rule.until) {
+            (_, Some(until)) => until,
+            (Some(count), None) => dtstart
+                .checked_add_signed(Days::days(366 * count as i64 * rule.interval as i64))
+                .unwrap_or(NaiveDate::MAX),
+            (None, None) => return None,
+        };
+        let last = *rule.occurrences(dtstart, dtstart, last_day).last()?;
+        return Some(feed::block_time(last, self.end_hour));
+    }
+
+    pub fn occurrences(&self, from: NaiveDate, to: NaiveDate) -> Vec<Occurrence> {
+        let (rule, dtstart) = self.recurrence(from);
+
+        return rule
+            .occurrences(dtstart, from, to)
+            .into_iter()
+            .map(|date| Occurrence {
+                duration_id: self.id,
+                owner_id: self.owner_id,
+                category_id: self.category_id,
+                date,
+                start_hour: self.start_hour,
+                end_hour: self.end_hour,
+                color: self.color.to_owned(),
+                exception_id: None,
+            })
+            .collect();
+    }
+}
+
+// Fills in whichever of `rrule` and `recurring_days` the request left out so both stay in sync.
+// `recurring_days` keeps the weekly calendar view working for rules it can't fully describe.
+pub fn normalize_recurrence(
+    recurring_days: &[i32],
+    rrule: &Option<String>,
+    dtstart: Option<NaiveDate>,
+) -> Result<(Vec<i32>, String, NaiveDate), String> {
+    let dtstart = dtstart.unwrap_or(Local::now().date_naive());
+
+    let rule = match rrule {
+        Some(rrule) => Recurrence::parse(rrule)?,
+        None => {
+            if recurring_days.len() != 7 {
+                return Err("recurring_days must have one entry per weekday".to_string());
+            }
+            Recurrence::from_weekdays(recurring_days)
+        }
+    };
+
+    return Ok((rule.weekday_mask(dtstart), rule.to_string(), dtstart));
+}
+
+pub fn validate_block(start_hour: i32, end_hour: i32) -> Result<(), String> {
+    if !(0..24).contains(&start_hour) || !(1..=24).contains(&end_hour) {
+        return Err("start_hour and end_hour must be within 0-24".to_string());
+    }
+    if start_hour >= end_hour {
+        return Err("start_hour must be before end_hour".to_string());
+    }
+    return Ok(());
+}
+
+// Weekday indexes (0 = Sunday) that both day arrays are switched on for.
+fn shared_days(a: &[i32], b: &[i32]) -> Vec<i32> {
+    let mut days = Vec::new();
+    for (day, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        if *x == 1 && *y == 1 {
+            days.push(day as i32);
+        }
+    }
+    return days;
+}
+
+// Hours are half open, so a block ending at 10 doesn't clash with one starting at 10.
+fn hours_overlap(a_start: i32, a_end: i32, b_start: i32, b_end: i32) -> bool {
+    return a_start < b_end && b_start < a_end;
+}
+
+fn blocks_overlap(a: &Duration, b: &Duration) -> bool {
+    // Schedules never share a date, so their blocks can't clash.
+    if a.schedule_id.is_some() && b.schedule_id.is_some() && a.schedule_id != b.schedule_id {
+        return false;
+    }
+    if !hours_overlap(a.start_hour, a.end_hour, b.start_hour, b.end_hour)
+        || shared_days(&a.recurring_days, &b.recurring_days).is_empty()
+    {
+        return false;
+    }
+
+    let today = Local::now().date_naive();
+    let (rule_a, start_a) = a.recurrence(today);
+    let (rule_b, start_b) = b.recurrence(today);
+    if rule_a.is_plain_weekly() && rule_b.is_plain_weekly() {
+        return true;
+    }
+
+    // Sharing a weekday isn't enough for e.g. alternating weeks, so look for an actual date.
+    let from = start_a.max(start_b);
+    let to = from + Days::days(CONFLICT_HORIZON_DAYS);
+    let dates_b = rule_b.occurrences(start_b, from, to);
+    return rule_a
+        .occurrences(start_a, from, to)
+        .iter()
+        .any(|date| dates_b.contains(date));
+}
+
+// `candidate.id` is skipped so an updated block doesn't conflict with its own row. New blocks
+// are checked with an id of 0, which SERIAL never hands out.
+fn find_conflicts(existing: &[Duration], candidate: &Duration) -> Vec<Duration> {
+    let mut conflicts = Vec::new();
+
+    for dur in existing {
+        if dur.id == candidate.id {
+            continue;
+        }
+        if blocks_overlap(dur, candidate) {
+            conflicts.push(dur.clone());
+        }
+    }
+    return conflicts;
+}
+
+pub async fn get_owner_durations<C: GenericClient>(client: &C, owner_id: i32) -> Vec<Duration> {
+    let mut durations = Vec::new();
+
+    for row in client
+        .query(
+            "SELECT * FROM public.duration WHERE owner_id=$1 AND deleted_at IS NULL",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        durations.push(duration_from_row(&row));
+    }
+    return durations;
+}
+
+// Keeps completed_at in step with the rule. Called wherever a duration's rule is written.
+pub async fn stamp_completed<C: GenericClient>(
+    client: &C,
+    id: i32,
+) -> Result<Option<NaiveDateTime>, tokio_postgres::Error> {
+    let row = client
+        .query_one("SELECT * FROM public.duration WHERE id=$1", &[&id])
+        .await?;
+    let completed_at = duration_from_row(&row).last_block_end();
+    client
+        .execute(
+            "UPDATE public.duration SET completed_at = $1 WHERE id = $2",
+            &[&completed_at, &id],
+        )
+        .await?;
+    return Ok(completed_at);
+}
+
+pub async fn insert_duration<C: GenericClient>(
+    client: &C,
+    dur: &DurationReq,
+) -> Result<i32, tokio_postgres::Error> {
+    let row = client
+        .query_one(
+            "INSERT INTO public.duration (
+                owner_id,
+                category_id,
+                start_hour,
+                end_hour,
+                recurring_days,
+                color,
+                rrule,
+                dtstart,
+                schedule_id
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+            &[
+                &dur.owner_id,
+                &dur.category_id,
+                &dur.start_hour,
+                &dur.end_hour,
+                &dur.recurring_days,
+                &dur.color,
+                &dur.rrule,
+                &dur.dtstart,
+                &dur.schedule_id,
+            ],
+        )
+        .await?;
+    stamp_completed(client, row.get(0)).await?;
+
+    return Ok(row.get(0));
+}
+
+// What's left of a block the new one is cut out of: its own row with the shared weekdays
+// taken out (None when there's nothing left, so it goes in the trash), and the hours on
+// either side of the new block on those weekdays as new rows.
+struct Trimmed {
+    id: i32,
+    remaining: Option<(Vec<i32>, String)>,
+    pieces: Vec<DurationReq>,
+}
+
+// Cuts the hours taken by the new block out of a conflicting block. On the shared weekdays
+// the old block keeps whatever is left before and after the new one as separate rows; on its
+// other weekdays it is left untouched. Taking whole weekdays out is only right when the new
+// block lands on those weekdays every week for good, and the old one repeats every week on
+// its days, so anything else is refused.
+fn trim_block(old: &Duration, candidate: &Duration, today: NaiveDate) -> Result<Trimmed, String> {
+    if !candidate.recurrence(today).0.is_plain_weekly() {
+        return Err("only a block that repeats every week can trim others".to_string());
+    }
+    let (rule, dtstart) = old.recurrence(today);
+    if rule.freq != crate::recurrence::Frequency::Weekly
+        || rule.interval != 1
+        || !rule.by_month_day.is_empty()
+        || !rule.by_month.is_empty()
+        || !rule.by_set_pos.is_empty()
+    {
+        return Err(format!(
+            "duration {} doesn't repeat every week and can't be trimmed",
+            old.id
+        ));
+    }
+
+    let days = shared_days(&old.recurring_days, &candidate.recurring_days);
+
+    let mut remaining_days = old.recurring_days.to_owned();
+    let mut split_days = vec![0; 7];
+    for day in days {
+        remaining_days[day as usize] = 0;
+        split_days[day as usize] = 1;
+    }
+
+    let mut remaining_rule = rule.clone();
+    remaining_rule.by_day = Recurrence::from_weekdays(&remaining_days).by_day;
+    let mut split_rule = rule.clone();
+    split_rule.by_day = Recurrence::from_weekdays(&split_days).by_day;
+
+    // A COUNT can't be copied into both halves without doubling the series. The half
+    // that stays ends with UNTIL at the original last date, and the split off half gets
+    // the count of the original dates that fall on its days.
+    if let Some(count) = rule.count {
+        let horizon = dtstart + Days::weeks((count * rule.interval) as i64 + 1);
+        let series = rule.occurrences(dtstart, dtstart, horizon);
+        remaining_rule.count = None;
+        remaining_rule.until = series.last().copied();
+        let split_count = series
+            .iter()
+            .filter(|date| split_days[date.weekday().num_days_from_sunday() as usize] == 1)
+            .count() as u32;
+        split_rule.count = Some(split_count).filter(|count| *count > 0);
+        if split_rule.count.is_none() {
+            split_days = vec![0; 7];
+        }
+    }
+
+    let remaining =
+        Some((remaining_days, remaining_rule.to_string())).filter(|(days, _)| days.contains(&1));
+
+    let mut hours = Vec::new();
+    if old.start_hour < candidate.start_hour {
+        hours.push((old.start_hour, candidate.start_hour));
+    }
+    if candidate.end_hour < old.end_hour {
+        hours.push((candidate.end_hour, old.end_hour));
+    }
+    if !split_days.contains(&1) {
+        hours.clear();
+    }
+    let pieces = hours
+        .into_iter()
+        .map(|(piece_start, piece_end)| DurationReq {
+            owner_id: old.owner_id,
+            category_id: old.category_id,
+            start_hour: piece_start,
+            end_hour: piece_end,
+            recurring_days: split_days.to_owned(),
+            color: old.color.to_owned(),
+            rrule: Some(split_rule.to_string()),
+            dtstart: Some(dtstart),
+            schedule_id: old.schedule_id,
+        })
+        .collect();
+
+    return Ok(Trimmed {
+        id: old.id,
+        remaining,
+        pieces,
+    });
+}
+
+// Trims every conflicting block, or none of them when one can't be.
+async fn trim_conflicts<C: GenericClient>(
+    client: &C,
+    conflicts: &[Duration],
+    candidate: &Duration,
+) -> Result<(), String> {
+    let today = Local::now().date_naive();
+    let trimmed = conflicts
+        .iter()
+        .map(|old| trim_block(old, candidate, today))
+        .collect::<Result<Vec<Trimmed>, String>>()?;
+
+    for old in trimmed {
+        match old.remaining {
+            Some((remaining_days, remaining_rule)) => {
+                client
+                    .execute(
+                        "UPDATE public.duration
+                        SET recurring_days = $1, rrule = $2, updated_at = $4
+                        WHERE id = $3",
+                        &[
+                            &remaining_days,
+                            &remaining_rule,
+                            &old.id,
+                            &time_entry::now(),
+                        ],
+                    )
+                    .await
+                    .map_err(|err| err.to_string())?;
+                stamp_completed(client, old.id)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+            None => {
+                // Nothing left of it, so it goes in the trash.
+                client
+                    .execute(
+                        "UPDATE public.duration SET deleted_at = $2 WHERE id = $1",
+                        &[&old.id, &time_entry::now()],
+                    )
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        for piece in &old.pieces {
+            insert_duration(client, piece)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    return Ok(());
+}
+
+// Applies the request's conflict policy. Returns the response to send when the write
+// shouldn't go ahead.
+pub async fn resolve_conflicts<C: GenericClient>(
+    client: &C,
+    candidate: &Duration,
+    policy: ConflictPolicy,
+) -> Option<HttpResponse> {
+    let existing = get_owner_durations(client, candidate.owner_id).await;
+    let conflicts = find_conflicts(&existing, candidate);
+
+    if conflicts.is_empty() {
+        return None;
+    }
+
+    match policy {
+        ConflictPolicy::Reject => {
+            return Some(
+                HttpResponse::Conflict()
+                    .content_type("application/json")
+                    .json(ConflictResponse {
+                        message: "duration overlaps existing blocks".to_string(),
+                        conflicts,
+                    }),
+            );
+        }
+        ConflictPolicy::Allow => return None,
+        ConflictPolicy::Trim => match trim_conflicts(client, &conflicts, candidate).await {
+            Ok(()) => return None,
+            Err(err) => {
+                return Some(
+                    HttpResponse::Conflict()
+                        .content_type("application/json")
+                        .json(err),
+                );
+            }
+        },
+    }
+}
+
+// Every occurrence between `from` and `to` with exceptions, schedule ranges and blackouts
+// applied, optionally for one owner.
+pub async fn expand_occurrences<C: GenericClient>(
+    client: &C,
+    owner_id: Option<i32>,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Vec<Occurrence> {
+    let mut durations: Vec<Duration> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.duration
+            WHERE ($1::INT IS NULL OR owner_id = $1) AND deleted_at IS NULL",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        durations.push(duration_from_row(&row));
+    }
+
+    let ids: Vec<i32> = durations.iter().map(|dur| dur.id).collect();
+    let exceptions = exception::get_exceptions(client, &ids).await;
+
+    let schedule_ids: Vec<i32> = durations.iter().filter_map(|dur| dur.schedule_id).collect();
+    let schedules = schedule::get_schedules_by_ids(client, &schedule_ids).await;
+
+    let mut occurrences = exception::apply_exceptions(&durations, &exceptions, from, to);
+    occurrences.retain(|occ| {
+        let schedule_id = durations
+            .iter()
+            .find(|dur| dur.id == occ.duration_id)
+            .and_then(|dur| dur.schedule_id);
+        match schedule_id {
+            Some(schedule_id) => schedules
+                .iter()
+                .any(|schedule| schedule.id == schedule_id && schedule.contains(occ.date)),
+            None => true,
+        }
+    });
+
+    let blackouts = blackout::get_blackouts(client, owner_id).await;
+    let exempt = blackout::get_exempt_categories(client, owner_id).await;
+    blackout::apply_blackouts(&mut occurrences, &blackouts, &exempt);
+    return occurrences;
 }
 
 // get /durations
@@ -38,25 +610,65 @@ pub async fn get_durations() -> impl Responder {
     let mut durations: Vec<Duration> = Vec::new();
 
     for row in client
-        .query("SELECT * FROM public.duration", &[])
+        .query("SELECT * FROM public.duration WHERE deleted_at IS NULL", &[])
         .await
         .expect("error getting durations")
     {
-        let dur = Duration {
-            id: row.get(0),
-            owner_id: row.get(1),
-            category_id: row.get(2),
-            start_hour: row.get(3),
-            end_hour: row.get(4),
-            recurring_days: row.get(5),
-            color: row.get(6),
-        };
-
-        durations.push(dur);
+        durations.push(duration_from_row(&row));
     }
     return HttpResponse::Ok().json(durations);
 }
 
+// #[get("/durations/occurrences")]
+pub async fn get_occurrences(query: web::Query<OccurrenceQuery>) -> impl Responder {
+    if query.to < query.from {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("to must not be before from");
+    }
+
+    let client = connect_to_db().await;
+    let occurrences = expand_occurrences(&client, query.owner_id, query.from, query.to).await;
+
+    return HttpResponse::Ok().json(occurrences);
+}
+
+// #[get("/durations/conflicts")]
+pub async fn get_duration_conflicts() -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut durations: Vec<Duration> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.duration WHERE deleted_at IS NULL ORDER BY id ASC",
+            &[],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        durations.push(duration_from_row(&row));
+    }
+
+    let mut conflicts: Vec<DurationConflict> = Vec::new();
+    for (i, first) in durations.iter().enumerate() {
+        for second in durations.iter().skip(i + 1) {
+            if first.owner_id != second.owner_id || !blocks_overlap(first, second) {
+                continue;
+            }
+
+            conflicts.push(DurationConflict {
+                first: first.clone(),
+                second: second.clone(),
+                days: shared_days(&first.recurring_days, &second.recurring_days),
+                start_hour: first.start_hour.max(second.start_hour),
+                end_hour: first.end_hour.min(second.end_hour),
+            });
+        }
+    }
+
+    return HttpResponse::Ok().json(conflicts);
+}
+
 // #[get("/duration/{id}")]
 pub async fn get_duration_by_id(info: Path<InfoPathId>) -> impl Responder {
     let id = info.id;
@@ -67,79 +679,129 @@ pub async fn get_duration_by_id(info: Path<InfoPathId>) -> impl Responder {
         .await
         .expect("error getting duration");
 
-    let duration= Duration {
-        id: row.get(0),
-        owner_id: row.get(1),
-        category_id: row.get(2),
-        start_hour: row.get(3),
-        end_hour: row.get(4),
-        recurring_days: row.get(5),
-        color: row.get(6)
-    };
+    let duration = duration_from_row(&row);
 
     return HttpResponse::Ok().json(duration);
 }
 
-
 // #[post("/duration")
 pub async fn create_duration(
     _req: HttpRequest,
     params: web::Json<DurationReq>,
+    query: web::Query<ConflictQuery>,
 ) -> impl Responder {
-    let client = connect_to_db().await;
+    let mut client = connect_to_db().await;
 
-    let duration = params.0;
+    let mut dur = params.0;
 
-    // for i in 0..durations.len() {
-        let stmt = client
-            .prepare(
-                "INSERT INTO public.duration (
-                owner_id,
-                category_id,
-                start_hour,
-                end_hour,
-                recurring_days,
-                color
-            ) VALUES ($1, $2, $3, $4, $5, $6)",
-            )
+    if let Err(msg) = validate_block(dur.start_hour, dur.end_hour) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+    // Series in a schedule start with the schedule unless told otherwise.
+    if let (None, Some(schedule_id)) = (dur.dtstart, dur.schedule_id) {
+        dur.dtstart = schedule::get_schedules_by_ids(&client, &[schedule_id])
             .await
-            .expect("error preparing create statement");
-
-        let dur = DurationReq {
-            owner_id: duration.owner_id,
-            category_id: duration.category_id,
-            start_hour: duration.start_hour,
-            end_hour: duration.end_hour,
-            recurring_days: duration.recurring_days,
-            color: duration.color.to_owned(),
-        };
+            .first()
+            .map(|schedule| schedule.starts_on);
+    }
+    match normalize_recurrence(&dur.recurring_days, &dur.rrule, dur.dtstart) {
+        Ok((recurring_days, rrule, dtstart)) => {
+            dur.recurring_days = recurring_days;
+            dur.rrule = Some(rrule);
+            dur.dtstart = Some(dtstart);
+        }
+        Err(msg) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    }
 
-        client
-            .execute(
-                &stmt,
-                &[
-                    &dur.owner_id,
-                    &dur.category_id,
-                    &dur.start_hour,
-                    &dur.end_hour,
-                    &dur.recurring_days,
-                    &dur.color,
-                ],
-            )
-            .await
-            .expect("Error creating duration");
-    //}
-    return HttpResponse::Ok()
-        .content_type("application/json")
-        .json("{success: 200}");
-}
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let candidate = Duration {
+        id: 0,
+        owner_id: dur.owner_id,
+        category_id: dur.category_id,
+        start_hour: dur.start_hour,
+        end_hour: dur.end_hour,
+        recurring_days: dur.recurring_days.to_owned(),
+        color: dur.color.to_owned(),
+        rrule: dur.rrule.to_owned(),
+        dtstart: dur.dtstart,
+        schedule_id: dur.schedule_id,
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        completed_at: None,
+    };
+    if let Some(response) = resolve_conflicts(&transaction, &candidate, query.on_conflict).await {
+        return response;
+    }
 
+    let result = insert_duration(&transaction, &dur).await;
+    match result {
+        Ok(_id) => {
+            transaction
+                .commit()
+                .await
+                .expect("Error creating duration");
+            return HttpResponse::Ok()
+                .content_type("application/json")
+                .json("{success: 200}");
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
 
 // #[put("/duration")]
-pub async fn update_duration(_req: HttpRequest, params: web::Json<Duration>) -> impl Responder {
-    let client = connect_to_db().await;
+pub async fn update_duration(
+    _req: HttpRequest,
+    params: web::Json<DurationUpdate>,
+    query: web::Query<ConflictQuery>,
+) -> impl Responder {
+    let mut client = connect_to_db().await;
+
+    let Some(stored) = client
+        .query_opt(
+            "SELECT * FROM public.duration WHERE id=$1 AND deleted_at IS NULL",
+            &[&params.id],
+        )
+        .await
+        .expect("error getting duration")
+        .map(|row| duration_from_row(&row))
+    else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json("no such duration");
+    };
+
+    // Without an rrule, the weekdays sent replace those of a weekly rule and anything else
+    // keeps its stored rule, so a client that only knows `recurring_days` can't flatten a
+    // richer rule into a plain weekly one.
+    let rrule = match &params.rrule {
+        Some(rrule) => Some(rrule.to_owned()),
+        None => {
+            let (mut rule, _) = stored.recurrence(Local::now().date_naive());
+            if rule.freq == crate::recurrence::Frequency::Weekly
+                && params.recurring_days.len() == 7
+            {
+                rule.by_day = Recurrence::from_weekdays(&params.recurring_days).by_day;
+            }
+            Some(rule.to_string())
+        }
+    };
 
-    let dur = Duration {
+    let mut dur = Duration {
         id: params.id,
         owner_id: params.owner_id,
         category_id: params.category_id,
@@ -147,9 +809,43 @@ pub async fn update_duration(_req: HttpRequest, params: web::Json<Duration>) ->
         end_hour: params.end_hour,
         recurring_days: params.recurring_days.to_owned(),
         color: params.color.to_owned(),
+        rrule,
+        dtstart: params.dtstart.or(stored.dtstart),
+        schedule_id: params.schedule_id.unwrap_or(stored.schedule_id),
+        created_at: stored.created_at,
+        updated_at: Some(time_entry::now()),
+        deleted_at: None,
+        completed_at: None,
     };
 
-    match client
+    if let Err(msg) = validate_block(dur.start_hour, dur.end_hour) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+    match normalize_recurrence(&dur.recurring_days, &dur.rrule, dur.dtstart) {
+        Ok((recurring_days, rrule, dtstart)) => {
+            dur.recurring_days = recurring_days;
+            dur.rrule = Some(rrule);
+            dur.dtstart = Some(dtstart);
+        }
+        Err(msg) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    }
+
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    if let Some(response) = resolve_conflicts(&transaction, &dur, query.on_conflict).await {
+        return response;
+    }
+
+    let result = transaction
         .execute(
             "UPDATE public.duration
             SET owner_id = $1,
@@ -157,8 +853,12 @@ pub async fn update_duration(_req: HttpRequest, params: web::Json<Duration>) ->
                 start_hour= $3,
                 end_hour= $4,
                 recurring_days= $5,
-                color = $6
-             WHERE id = $7",
+                color = $6,
+                rrule = $7,
+                dtstart = $8,
+                schedule_id = $9,
+                updated_at = $11
+             WHERE id = $10",
             &[
                 &dur.owner_id,
                 &dur.category_id,
@@ -166,15 +866,32 @@ pub async fn update_duration(_req: HttpRequest, params: web::Json<Duration>) ->
                 &dur.end_hour,
                 &dur.recurring_days,
                 &dur.color,
-                &dur.id
+                &dur.rrule,
+                &dur.dtstart,
+                &dur.schedule_id,
+                &dur.id,
+                &dur.updated_at,
             ],
         )
-        .await
-    {
+        .await;
+
+    match result {
         Ok(_data) => {
+            stamp_completed(&transaction, dur.id)
+                .await
+                .expect("error updating duration");
+            // Answer with the row as saved rather than the request echoed back.
+            let saved = transaction
+                .query_one("SELECT * FROM public.duration WHERE id=$1", &[&dur.id])
+                .await
+                .expect("error getting duration");
+            transaction
+                .commit()
+                .await
+                .expect("error updating duration");
             return HttpResponse::Created()
                 .content_type("application/json")
-                .json(dur);
+                .json(duration_from_row(&saved));
         }
         Err(err) => {
             return HttpResponse::Conflict()
@@ -189,10 +906,259 @@ pub async fn delete_duration_by_id(info: Path<InfoPathId>) -> impl Responder {
     let id = info.id;
     let client = connect_to_db().await;
 
+    // Into the trash; see `trash`.
     client
-        .execute("DELETE FROM public.duration WHERE id=$1", &[&id])
+        .execute(
+            "UPDATE public.duration SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL",
+            &[&id, &time_entry::now()],
+        )
         .await
         .expect("error deleting duration");
 
     return HttpResponse::Ok().json("Deleted Item");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn date(value: &str) -> NaiveDate {
+        return NaiveDate::parse_from_str(value, "%Y-%m-%d").expect("valid date");
+    }
+
+    fn block(
+        id: i32,
+        hours: (i32, i32),
+        recurring_days: [i32; 7],
+        rrule: &str,
+        dtstart: &str,
+    ) -> Duration {
+        return serde_json::from_value(json!({
+            "id": id,
+            "owner_id": 1,
+            "category_id": 1,
+            "start_hour": hours.0,
+            "end_hour": hours.1,
+            "recurring_days": recurring_days,
+            "color": DEFAULT_COLOR,
+            "rrule": rrule,
+            "dtstart": dtstart,
+        }))
+        .expect("valid duration");
+    }
+
+    const MON: [i32; 7] = [0, 1, 0, 0, 0, 0, 0];
+    const WED: [i32; 7] = [0, 0, 0, 1, 0, 0, 0];
+    const MON_WED: [i32; 7] = [0, 1, 0, 1, 0, 0, 0];
+
+    #[test]
+    fn blocks_overlap_on_a_shared_hour_of_a_shared_day() {
+        let morning = block(1, (9, 12), MON_WED, "FREQ=WEEKLY;BYDAY=MO,WE", "2026-11-02");
+        let noon = block(2, (11, 13), WED, "FREQ=WEEKLY;BYDAY=WE", "2026-11-04");
+        let after = block(3, (12, 14), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        let tuesday = block(
+            4,
+            (9, 12),
+            [0, 0, 1, 0, 0, 0, 0],
+            "FREQ=WEEKLY;BYDAY=TU",
+            "2026-11-03",
+        );
+
+        assert!(blocks_overlap(&morning, &noon));
+        assert!(blocks_overlap(&noon, &morning));
+        // Hours are half open, so back to back blocks are fine.
+        assert!(!blocks_overlap(&morning, &after));
+        assert!(!blocks_overlap(&morning, &tuesday));
+    }
+
+    #[test]
+    fn blocks_on_different_schedules_never_overlap() {
+        let mut first = block(1, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        let mut second = block(2, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        first.schedule_id = Some(1);
+        second.schedule_id = Some(2);
+        assert!(!blocks_overlap(&first, &second));
+
+        second.schedule_id = Some(1);
+        assert!(blocks_overlap(&first, &second));
+        second.schedule_id = None;
+        assert!(blocks_overlap(&first, &second));
+    }
+
+    #[test]
+    fn alternating_weeks_only_overlap_when_they_share_a_date() {
+        let even = block(
+            1,
+            (9, 12),
+            MON,
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO",
+            "2026-11-02",
+        );
+        let odd = block(
+            2,
+            (9, 12),
+            MON,
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO",
+            "2026-11-09",
+        );
+        let also_even = block(
+            3,
+            (9, 12),
+            MON,
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO",
+            "2026-11-16",
+        );
+
+        assert!(!blocks_overlap(&even, &odd));
+        assert!(blocks_overlap(&even, &also_even));
+    }
+
+    #[test]
+    fn a_series_that_ended_doesnt_overlap_one_that_starts_later() {
+        let ended = block(
+            1,
+            (9, 12),
+            MON,
+            "FREQ=WEEKLY;COUNT=2;BYDAY=MO",
+            "2026-11-02",
+        );
+        let later = block(2, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-12-07");
+        let sooner = block(3, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-09");
+
+        assert!(!blocks_overlap(&ended, &later));
+        assert!(blocks_overlap(&ended, &sooner));
+    }
+
+    #[test]
+    fn find_conflicts_skips_the_block_being_updated() {
+        let existing = vec![
+            block(1, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02"),
+            block(2, (13, 15), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02"),
+            block(3, (10, 11), WED, "FREQ=WEEKLY;BYDAY=WE", "2026-11-04"),
+        ];
+
+        let moved = block(
+            1,
+            (10, 14),
+            MON_WED,
+            "FREQ=WEEKLY;BYDAY=MO,WE",
+            "2026-11-02",
+        );
+        let ids: Vec<i32> = find_conflicts(&existing, &moved)
+            .iter()
+            .map(|dur| dur.id)
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        let new = block(
+            0,
+            (10, 14),
+            MON_WED,
+            "FREQ=WEEKLY;BYDAY=MO,WE",
+            "2026-11-02",
+        );
+        let ids: Vec<i32> = find_conflicts(&existing, &new)
+            .iter()
+            .map(|dur| dur.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let clear = block(
+            0,
+            (17, 18),
+            MON_WED,
+            "FREQ=WEEKLY;BYDAY=MO,WE",
+            "2026-11-02",
+        );
+        assert!(find_conflicts(&existing, &clear).is_empty());
+    }
+
+    #[test]
+    fn trimming_keeps_the_hours_around_the_new_block() {
+        let old = block(1, (9, 17), MON_WED, "FREQ=WEEKLY;BYDAY=MO,WE", "2026-11-02");
+        let lunch = block(0, (12, 13), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+
+        let trimmed = trim_block(&old, &lunch, date("2026-11-02")).expect("trimmable");
+        assert_eq!(trimmed.id, 1);
+        assert_eq!(
+            trimmed.remaining,
+            Some((WED.to_vec(), "FREQ=WEEKLY;BYDAY=WE".to_string()))
+        );
+        let pieces: Vec<(i32, i32, Vec<i32>, Option<String>)> = trimmed
+            .pieces
+            .into_iter()
+            .map(|piece| {
+                (
+                    piece.start_hour,
+                    piece.end_hour,
+                    piece.recurring_days,
+                    piece.rrule,
+                )
+            })
+            .collect();
+        let monday = Some("FREQ=WEEKLY;BYDAY=MO".to_string());
+        assert_eq!(
+            pieces,
+            vec![
+                (9, 12, MON.to_vec(), monday.clone()),
+                (13, 17, MON.to_vec(), monday),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_block_covered_entirely_goes_in_the_trash() {
+        let old = block(1, (10, 11), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        let wider = block(0, (9, 12), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+
+        let trimmed = trim_block(&old, &wider, date("2026-11-02")).expect("trimmable");
+        assert_eq!(trimmed.remaining, None);
+        assert!(trimmed.pieces.is_empty());
+    }
+
+    #[test]
+    fn trimming_a_counted_series_splits_the_count() {
+        // Mon 11-02, Wed 11-04, Mon 11-09, Wed 11-11.
+        let old = block(
+            1,
+            (9, 17),
+            MON_WED,
+            "FREQ=WEEKLY;COUNT=4;BYDAY=MO,WE",
+            "2026-11-02",
+        );
+        let lunch = block(0, (12, 13), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+
+        let trimmed = trim_block(&old, &lunch, date("2026-11-02")).expect("trimmable");
+        assert_eq!(
+            trimmed.remaining,
+            Some((
+                WED.to_vec(),
+                "FREQ=WEEKLY;UNTIL=20261111;BYDAY=WE".to_string()
+            ))
+        );
+        assert_eq!(trimmed.pieces.len(), 2);
+        for piece in &trimmed.pieces {
+            assert_eq!(piece.rrule.as_deref(), Some("FREQ=WEEKLY;COUNT=2;BYDAY=MO"));
+            assert_eq!(piece.dtstart, Some(date("2026-11-02")));
+        }
+    }
+
+    #[test]
+    fn trimming_is_refused_unless_both_repeat_every_week() {
+        let weekly = block(1, (9, 17), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        let alternating = block(
+            0,
+            (12, 13),
+            MON,
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO",
+            "2026-11-02",
+        );
+        let monthly = block(2, (9, 17), MON, "FREQ=MONTHLY;BYDAY=1MO", "2026-11-02");
+        let lunch = block(0, (12, 13), MON, "FREQ=WEEKLY;BYDAY=MO", "2026-11-02");
+        let today = date("2026-11-02");
+
+        assert!(trim_block(&weekly, &alternating, today).is_err());
+        assert!(trim_block(&monthly, &lunch, today).is_err());
+    }
+}
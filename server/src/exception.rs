@@ -0,0 +1,251 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{duration_from_row, validate_block, Duration, Occurrence},
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{GenericClient, Row};
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathId {
+    pub id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathDate {
+    pub id: i32,
+    pub date: NaiveDate,
+}
+
+// A change to a single occurrence of a recurring duration. The series itself is left alone;
+// unset fields keep the value from the series.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DurationException {
+    pub id: i32,
+    pub duration_id: i32,
+    pub occurrence_date: NaiveDate,
+    pub is_cancelled: bool,
+    pub new_date: Option<NaiveDate>,
+    pub start_hour: Option<i32>,
+    pub end_hour: Option<i32>,
+    pub category_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DurationExceptionReq {
+    occurrence_date: NaiveDate,
+    #[serde(default)]
+    is_cancelled: bool,
+    new_date: Option<NaiveDate>,
+    start_hour: Option<i32>,
+    end_hour: Option<i32>,
+    category_id: Option<i32>,
+}
+
+pub fn exception_from_row(row: &Row) -> DurationException {
+    return DurationException {
+        id: row.get(0),
+        duration_id: row.get(1),
+        occurrence_date: row.get(2),
+        is_cancelled: row.get(3),
+        new_date: row.get(4),
+        start_hour: row.get(5),
+        end_hour: row.get(6),
+        category_id: row.get(7),
+    };
+}
+
+pub async fn get_exceptions<C: GenericClient>(
+    client: &C,
+    duration_ids: &[i32],
+) -> Vec<DurationException> {
+    let mut exceptions = Vec::new();
+
+    for row in client
+        .query(
+            "SELECT * FROM public.duration_exception WHERE duration_id = ANY($1)",
+            &[&duration_ids],
+        )
+        .await
+        .expect("error getting duration exceptions")
+    {
+        exceptions.push(exception_from_row(&row));
+    }
+    return exceptions;
+}
+
+fn apply_exception(occ: &Occurrence, exception: &DurationException) -> Occurrence {
+    return Occurrence {
+        duration_id: occ.duration_id,
+        owner_id: occ.owner_id,
+        category_id: exception.category_id.unwrap_or(occ.category_id),
+        date: exception.new_date.unwrap_or(occ.date),
+        start_hour: exception.start_hour.unwrap_or(occ.start_hour),
+        end_hour: exception.end_hour.unwrap_or(occ.end_hour),
+        color: occ.color.to_owned(),
+        exception_id: Some(exception.id),
+    };
+}
+
+// Expands `durations` over `from..=to` with their exceptions applied. Occurrences moved into
+// the range from a date outside of it are picked up too, and ones moved out are dropped.
+pub fn apply_exceptions(
+    durations: &[Duration],
+    exceptions: &[DurationException],
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Vec<Occurrence> {
+    let mut occurrences = Vec::new();
+
+    for dur in durations {
+        let dur_exceptions: Vec<&DurationException> = exceptions
+            .iter()
+            .filter(|exception| exception.duration_id == dur.id)
+            .collect();
+
+        for occ in dur.occurrences(from, to) {
+            match dur_exceptions
+                .iter()
+                .find(|exception| exception.occurrence_date == occ.date)
+            {
+                Some(exception) if exception.is_cancelled => {}
+                Some(exception) => {
+                    let moved = apply_exception(&occ, exception);
+                    if moved.date >= from && moved.date <= to {
+                        occurrences.push(moved);
+                    }
+                }
+                None => occurrences.push(occ),
+            }
+        }
+
+        for exception in dur_exceptions {
+            let new_date = match exception.new_date {
+                Some(date) => date,
+                None => continue,
+            };
+            let outside = exception.occurrence_date < from || exception.occurrence_date > to;
+            if exception.is_cancelled || !outside || new_date < from || new_date > to {
+                continue;
+            }
+            if let Some(occ) = dur
+                .occurrences(exception.occurrence_date, exception.occurrence_date)
+                .first()
+            {
+                occurrences.push(apply_exception(occ, exception));
+            }
+        }
+    }
+
+    occurrences.sort_by_key(|occ| (occ.date, occ.start_hour));
+    return occurrences;
+}
+
+// #[get("/duration/{id}/exceptions")]
+pub async fn get_duration_exceptions(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut exceptions = get_exceptions(&client, &[info.id]).await;
+    exceptions.sort_by_key(|exception| exception.occurrence_date);
+
+    return HttpResponse::Ok().json(exceptions);
+}
+
+// #[post("/duration/{id}/exception")]
+pub async fn create_duration_exception(
+    _req: HttpRequest,
+    params: web::Json<DurationExceptionReq>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+    let exception = params.0;
+
+    let row = match client
+        .query_opt("SELECT * FROM public.duration WHERE id=$1", &[&info.id])
+        .await
+        .expect("error getting duration")
+    {
+        Some(row) => row,
+        None => return HttpResponse::NotFound().json("duration not found"),
+    };
+    let dur = duration_from_row(&row);
+
+    if dur
+        .occurrences(exception.occurrence_date, exception.occurrence_date)
+        .is_empty()
+    {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("duration doesn't occur on occurrence_date");
+    }
+    if let Err(msg) = validate_block(
+        exception.start_hour.unwrap_or(dur.start_hour),
+        exception.end_hour.unwrap_or(dur.end_hour),
+    ) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    // One exception per occurrence; posting again for the same date replaces it.
+    match client
+        .query_one(
+            "INSERT INTO public.duration_exception (
+                duration_id,
+                occurrence_date,
+                is_cancelled,
+                new_date,
+                start_hour,
+                end_hour,
+                category_id
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (duration_id, occurrence_date) DO UPDATE
+            SET is_cancelled = EXCLUDED.is_cancelled,
+                new_date = EXCLUDED.new_date,
+                start_hour = EXCLUDED.start_hour,
+                end_hour = EXCLUDED.end_hour,
+                category_id = EXCLUDED.category_id
+            RETURNING *",
+            &[
+                &dur.id,
+                &exception.occurrence_date,
+                &exception.is_cancelled,
+                &exception.new_date,
+                &exception.start_hour,
+                &exception.end_hour,
+                &exception.category_id,
+            ],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(exception_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[delete("/duration/{id}/exception/{date}")]
+pub async fn delete_duration_exception(info: Path<InfoPathDate>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    client
+        .execute(
+            "DELETE FROM public.duration_exception WHERE duration_id=$1 AND occurrence_date=$2",
+            &[&info.id, &info.date],
+        )
+        .await
+        .expect("error deleting duration exception");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
@@ -0,0 +1,529 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{duration_from_row, Duration},
+    blackout, color,
+    exception::{self, DurationException},
+    ical::{self, Component},
+    schedule::{self, Schedule},
+    subtask,
+    task::{task_from_row, Task},
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration as Days, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const PRODID: &str = "-//chaos-manager//schedule//EN";
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenResponse {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FeedQuery {
+    token: String,
+    #[serde(default)]
+    tasks: bool,
+}
+
+pub fn calendar() -> Component {
+    let mut cal = Component::new("VCALENDAR");
+    cal.push("VERSION", "2.0");
+    cal.push("PRODID", PRODID);
+    cal.push("CALSCALE", "GREGORIAN");
+    return cal;
+}
+
+pub fn dtstamp() -> String {
+    return Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+}
+
+// Hours are whole and end exclusive, so an end of 24 is midnight of the next day.
+pub fn block_time(date: NaiveDate, hour: i32) -> NaiveDateTime {
+    return (date + Days::days((hour / 24) as i64))
+        .and_hms_opt((hour % 24) as u32, 0, 0)
+        .expect("valid hour");
+}
+
+pub fn duration_uid(id: i32) -> String {
+    return format!("duration-{}@chaos-manager", id);
+}
+
+pub fn task_uid(id: i32) -> String {
+    return format!("task-{}@chaos-manager", id);
+}
+
+fn task_list(tasks: &[&Task]) -> String {
+    return tasks
+        .iter()
+        .map(|task| format!("- {}", task.title))
+        .collect::<Vec<String>>()
+        .join("\n");
+}
+
+// A recurring VEVENT for the series, with cancelled and blacked out occurrences as EXDATEs
+// and moved or recategorised ones as their own VEVENT carrying a RECURRENCE-ID.
+pub fn duration_events(
+    dur: &Duration,
+    schedule: Option<&Schedule>,
+    exceptions: &[&DurationException],
+    blackout_dates: &[NaiveDate],
+    category_titles: &HashMap<i32, String>,
+    open_tasks: &[&Task],
+) -> Vec<Component> {
+    let today = Utc::now().date_naive();
+    let (mut rule, dtstart) = dur.recurrence(today);
+
+    // A schedule's end date ends the series, unless a count already does.
+    if let Some(ends_on) = schedule.and_then(|schedule| schedule.ends_on) {
+        if rule.count.is_none() {
+            rule.until = Some(rule.until.map_or(ends_on, |until| until.min(ends_on)));
+        }
+    }
+
+    let title = |category_id: i32| {
+        return category_titles
+            .get(&category_id)
+            .cloned()
+            .unwrap_or("Untitled".to_string());
+    };
+    let tasks_for = |category_id: i32| {
+        let tasks: Vec<&Task> = open_tasks
+            .iter()
+            .filter(|task| task.category_id == category_id)
+            .cloned()
+            .collect();
+        return task_list(&tasks);
+    };
+
+    let mut series = Component::new("VEVENT");
+    series.push("UID", &duration_uid(dur.id));
+    series.push("DTSTAMP", &dtstamp());
+    series.push("DTSTART", &ical::format_date_time(block_time(dtstart, dur.start_hour)));
+    series.push("DTEND", &ical::format_date_time(block_time(dtstart, dur.end_hour)));
+    series.push("RRULE", &rule.to_string_until_at(block_time(dtstart, dur.start_hour).time()));
+    series.push_text("SUMMARY", &title(dur.category_id));
+    if let Some(name) = color::nearest_css_name(&dur.color) {
+        series.push("COLOR", name);
+    }
+    let description = tasks_for(dur.category_id);
+    if !description.is_empty() {
+        series.push_text("DESCRIPTION", &description);
+    }
+
+    let mut events = Vec::new();
+    for exception in exceptions {
+        let original = ical::format_date_time(block_time(exception.occurrence_date, dur.start_hour));
+        if exception.is_cancelled {
+            series.push("EXDATE", &original);
+            continue;
+        }
+
+        let date = exception.new_date.unwrap_or(exception.occurrence_date);
+        let category_id = exception.category_id.unwrap_or(dur.category_id);
+
+        let mut event = Component::new("VEVENT");
+        event.push("UID", &duration_uid(dur.id));
+        event.push("DTSTAMP", &dtstamp());
+        event.push("RECURRENCE-ID", &original);
+        event.push(
+            "DTSTART",
+            &ical::format_date_time(block_time(
+                date,
+                exception.start_hour.unwrap_or(dur.start_hour),
+            )),
+        );
+        event.push(
+            "DTEND",
+            &ical::format_date_time(block_time(date, exception.end_hour.unwrap_or(dur.end_hour))),
+        );
+        event.push_text("SUMMARY", &title(category_id));
+        if let Some(name) = color::nearest_css_name(&dur.color) {
+            event.push("COLOR", name);
+        }
+        let description = tasks_for(category_id);
+        if !description.is_empty() {
+            event.push_text("DESCRIPTION", &description);
+        }
+        events.push(event);
+    }
+
+    for date in blackout_dates {
+        if exceptions
+            .iter()
+            .all(|exception| exception.occurrence_date != *date)
+        {
+            series.push(
+                "EXDATE",
+                &ical::format_date_time(block_time(*date, dur.start_hour)),
+            );
+        }
+    }
+
+    events.insert(0, series);
+    return events;
+}
+
+pub fn task_todo(task: &Task, category_title: Option<&String>) -> Component {
+    let mut todo = Component::new("VTODO");
+    todo.push("UID", &task_uid(task.id));
+    todo.push("DTSTAMP", &dtstamp());
+    todo.push_text("SUMMARY", &task.title);
+    if let Some(description) = task.description.as_ref().filter(|d| !d.is_empty()) {
+        todo.push_text("DESCRIPTION", description);
+    }
+    if let Some(title) = category_title {
+        todo.push_text("CATEGORIES", title);
+    }
+    if let Some(start_at) = task.start_at {
+        todo.push("DTSTART", &ical::format_date_time(start_at));
+    }
+    if let Some(due_at) = task.due_at {
+        todo.push("DUE", &ical::format_date_time(due_at));
+    }
+    if let Some(parent_id) = task.parent_id {
+        todo.push("RELATED-TO", &task_uid(parent_id));
+    }
+    // iCalendar priorities run 1 (highest) to 9; 0 means undefined.
+    todo.push("PRIORITY", &task.priority.clamp(0, 9).to_string());
+    todo.push(
+        "STATUS",
+        if task.is_complete {
+            "COMPLETED"
+        } else {
+            "NEEDS-ACTION"
+        },
+    );
+    return todo;
+}
+
+// VTODOs for the tasks that have a due date. Ones without have nowhere to show up in a
+// calendar, so they're left out.
+pub fn task_todos(tasks: &[&Task], category_titles: &HashMap<i32, String>) -> Vec<Component> {
+    return tasks
+        .iter()
+        .filter(|task| task.due_at.is_some())
+        .map(|task| task_todo(task, category_titles.get(&task.category_id)))
+        .collect();
+}
+
+// #[post("/calendar/token")]
+// Issues a new feed token for the owner. Any previous token stops working.
+pub async fn create_feed_token(
+    _req: HttpRequest,
+    params: web::Json<TokenRequest>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    match client
+        .query_one(
+            "INSERT INTO public.calendar_feed (owner_id, token)
+            VALUES ($1, replace(gen_random_uuid()::TEXT || gen_random_uuid()::TEXT, '-', ''))
+            ON CONFLICT (owner_id) DO UPDATE SET token = EXCLUDED.token
+            RETURNING token",
+            &[&params.owner_id],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(TokenResponse { token: row.get(0) });
+        }
+        Err(err) => {
+            return HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[get("/calendar.ics")]
+pub async fn get_calendar_feed(query: web::Query<FeedQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let owner_id: i32 = match client
+        .query_opt(
+            "SELECT owner_id FROM public.calendar_feed WHERE token=$1",
+            &[&query.token],
+        )
+        .await
+        .expect("error checking feed token")
+    {
+        Some(row) => row.get(0),
+        None => return HttpResponse::NotFound().body("unknown calendar token"),
+    };
+
+    let mut category_titles: HashMap<i32, String> = HashMap::new();
+    for row in client
+        .query(
+            "SELECT id, title FROM public.category WHERE owner_id=$1",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        category_titles.insert(row.get(0), row.get(1));
+    }
+    let blackouts = blackout::get_blackouts(&client, Some(owner_id)).await;
+    let exempt = blackout::get_exempt_categories(&client, Some(owner_id)).await;
+
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE owner_id=$1 AND deleted_at IS NULL ORDER BY priority ASC",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    subtask::hide_trashed_parents(&client, &mut tasks).await;
+    let open_tasks: Vec<&Task> = tasks.iter().filter(|task| !task.is_complete).collect();
+
+    let mut durations: Vec<Duration> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.duration WHERE owner_id=$1 AND deleted_at IS NULL",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        durations.push(duration_from_row(&row));
+    }
+    let ids: Vec<i32> = durations.iter().map(|dur| dur.id).collect();
+    let exceptions = exception::get_exceptions(&client, &ids).await;
+    let schedule_ids: Vec<i32> = durations.iter().filter_map(|dur| dur.schedule_id).collect();
+    let schedules = schedule::get_schedules_by_ids(&client, &schedule_ids).await;
+
+    let mut cal = calendar();
+    cal.push_text("X-WR-CALNAME", "chaos-manager");
+    for dur in &durations {
+        let schedule = schedules
+            .iter()
+            .find(|schedule| Some(schedule.id) == dur.schedule_id);
+        let dur_exceptions: Vec<&DurationException> = exceptions
+            .iter()
+            .filter(|exception| exception.duration_id == dur.id)
+            .collect();
+
+        let dur_blackouts = if exempt.contains(&dur.category_id) {
+            Vec::new()
+        } else {
+            blackout::blackout_dates(dur, &blackouts)
+        };
+
+        cal.components.append(&mut duration_events(
+            dur,
+            schedule,
+            &dur_exceptions,
+            &dur_blackouts,
+            &category_titles,
+            &open_tasks,
+        ));
+    }
+
+    if query.tasks {
+        cal.components
+            .append(&mut task_todos(&open_tasks, &category_titles));
+    }
+
+    return HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(cal.write());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recurrence::Recurrence;
+    use serde_json::json;
+
+    fn date(value: &str) -> NaiveDate {
+        return NaiveDate::parse_from_str(value, "%Y-%m-%d").expect("valid date");
+    }
+
+    fn task(id: i32, title: &str, description: &str, due_at: Option<&str>) -> Task {
+        return serde_json::from_value(json!({
+            "id": id,
+            "title": title,
+            "description": description,
+            "is_complete": false,
+            "priority": 2,
+            "owner_id": 1,
+            "category_id": 1,
+            "due_at": due_at,
+        }))
+        .expect("valid task");
+    }
+
+    #[test]
+    fn feed_round_trips_through_the_parser() {
+        let dur: Duration = serde_json::from_value(json!({
+            "id": 7,
+            "owner_id": 1,
+            "category_id": 1,
+            "start_hour": 9,
+            "end_hour": 11,
+            "recurring_days": [0, 1, 0, 1, 0, 0, 0],
+            "color": "#3366ff",
+            "rrule": "FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20261231",
+            "dtstart": "2026-11-02",
+        }))
+        .expect("valid duration");
+        let cancelled = DurationException {
+            id: 1,
+            duration_id: 7,
+            occurrence_date: date("2026-11-04"),
+            is_cancelled: true,
+            new_date: None,
+            start_hour: None,
+            end_hour: None,
+            category_id: None,
+        };
+        let moved = DurationException {
+            id: 2,
+            duration_id: 7,
+            occurrence_date: date("2026-11-09"),
+            is_cancelled: false,
+            new_date: Some(date("2026-11-10")),
+            start_hour: Some(13),
+            end_hour: Some(15),
+            category_id: None,
+        };
+        let titles = HashMap::from([(1, "Deep work".to_string())]);
+        let long_description = "Read the whole chapter on scheduling, take notes on every \
+            example and write up the questions that come out of it";
+        let due = task(
+            3,
+            "Chapter notes",
+            long_description,
+            Some("2026-11-06T17:00:00"),
+        );
+        let undated = task(4, "Someday", "", None);
+        let open_tasks = vec![&due, &undated];
+
+        let mut cal = calendar();
+        cal.components.append(&mut duration_events(
+            &dur,
+            None,
+            &[&cancelled, &moved],
+            &[date("2026-11-16")],
+            &titles,
+            &open_tasks,
+        ));
+        cal.components.append(&mut task_todos(&open_tasks, &titles));
+        let written = cal.write();
+
+        assert!(written.split("\r\n").all(|line| line.len() <= 75));
+        assert!(
+            written.contains("\r\n "),
+            "the long description should be folded"
+        );
+
+        let parsed = ical::parse(&written).expect("feed parses");
+        assert_eq!(parsed.len(), 1);
+        let cal = &parsed[0];
+        assert_eq!(cal.name, "VCALENDAR");
+        assert_eq!(cal.value("VERSION").as_deref(), Some("2.0"));
+        let names: Vec<&str> = cal.components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["VEVENT", "VEVENT", "VTODO"]);
+
+        let series = &cal.components[0];
+        assert_eq!(series.value("DTSTART").as_deref(), Some("20261102T090000"));
+        let rrule = series.value("RRULE").expect("series has an RRULE");
+        assert!(rrule.contains("UNTIL=20261231T090000"));
+        let rule = Recurrence::parse(&rrule).expect("valid RRULE");
+        assert_eq!(
+            rule,
+            Recurrence::parse(dur.rrule.as_ref().unwrap()).unwrap()
+        );
+        let exdates: Vec<String> = series
+            .properties_named("EXDATE")
+            .map(|prop| prop.value.to_owned())
+            .collect();
+        assert_eq!(exdates, ["20261104T090000", "20261116T090000"]);
+        assert_eq!(series.value("COLOR").as_deref(), Some("royalblue"));
+        assert!(series.property("X-APPLE-CALENDAR-COLOR").is_none());
+        assert_eq!(
+            series.value("DESCRIPTION").as_deref(),
+            Some("- Chapter notes\n- Someday")
+        );
+
+        let moved = &cal.components[1];
+        assert_eq!(
+            moved.value("RECURRENCE-ID").as_deref(),
+            Some("20261109T090000")
+        );
+        assert_eq!(moved.value("DTSTART").as_deref(), Some("20261110T130000"));
+
+        let todo = &cal.components[2];
+        assert_eq!(todo.value("UID"), Some(task_uid(3)));
+        assert_eq!(todo.value("DESCRIPTION").as_deref(), Some(long_description));
+        assert_eq!(todo.value("DUE").as_deref(), Some("20261106T170000"));
+    }
+
+    // Reads the feed with an independent RFC 5545 parser, so a mistake made the same way on
+    // both our writing and reading side doesn't go unnoticed.
+    #[test]
+    fn feed_reads_in_another_parser() {
+        let dur: Duration = serde_json::from_value(json!({
+            "id": 7,
+            "owner_id": 1,
+            "category_id": 1,
+            "start_hour": 9,
+            "end_hour": 11,
+            "color": "#e9967a",
+            "rrule": "FREQ=MONTHLY;BYDAY=1MO;COUNT=6",
+            "dtstart": "2026-11-02",
+        }))
+        .expect("valid duration");
+        let titles = HashMap::from([(1, "Planning, review; notes".to_string())]);
+        let due = task(3, "Plan", "Line one\nline two", Some("2026-11-06T17:00:00"));
+
+        let mut cal = calendar();
+        cal.components
+            .append(&mut duration_events(&dur, None, &[], &[], &titles, &[&due]));
+        cal.components.append(&mut task_todos(&[&due], &titles));
+        let written = cal.write();
+
+        let mut calendars = ::ical::IcalParser::new(written.as_bytes());
+        let parsed = calendars
+            .next()
+            .expect("one calendar")
+            .expect("feed parses");
+        assert!(calendars.next().is_none());
+        assert_eq!(parsed.events.len(), 1);
+        assert_eq!(parsed.todos.len(), 1);
+
+        let value = |properties: &[::ical::property::Property], name: &str| {
+            properties
+                .iter()
+                .find(|prop| prop.name == name)
+                .and_then(|prop| prop.value.to_owned())
+        };
+        let event = &parsed.events[0].properties;
+        assert_eq!(value(event, "COLOR").as_deref(), Some("darksalmon"));
+        assert_eq!(value(event, "DTSTART").as_deref(), Some("20261102T090000"));
+        assert_eq!(
+            value(event, "RRULE").as_deref(),
+            Some("FREQ=MONTHLY;COUNT=6;BYDAY=1MO")
+        );
+        assert_eq!(
+            value(event, "SUMMARY").as_deref(),
+            Some("Planning\\, review\\; notes")
+        );
+        let todo = &parsed.todos[0].properties;
+        assert_eq!(value(todo, "DESCRIPTION").as_deref(), Some("Line one\\nline two"));
+        assert_eq!(value(todo, "DUE").as_deref(), Some("20261106T170000"));
+    }
+}
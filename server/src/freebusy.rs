@@ -0,0 +1,235 @@
+use crate::{
+    connect::connect_to_db,
+    duration::expand_occurrences,
+    feed::{self, block_time},
+    ical::Component,
+};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct FreeBusyQuery {
+    owner_id: i32,
+    from: String,
+    to: String,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BusyInterval {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+// Accepts a plain date (midnight) or a date and time.
+fn parse_bound(value: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(date_time);
+    }
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M") {
+        return Ok(date_time);
+    }
+    return NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).expect("valid time"))
+        .map_err(|_| format!("invalid date '{}'", value));
+}
+
+// Sorts the intervals and joins any that overlap or touch.
+pub fn merge_intervals(mut intervals: Vec<BusyInterval>) -> Vec<BusyInterval> {
+    intervals.sort_by_key(|interval| interval.start);
+
+    let mut merged: Vec<BusyInterval> = Vec::new();
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if interval.start <= last.end => {
+                last.end = last.end.max(interval.end);
+            }
+            _ => merged.push(interval),
+        }
+    }
+    return merged;
+}
+
+// Busy time between `from` and `to` from the owner's expanded durations, clipped to the range.
+// Categories with `counts_as_busy` switched off are left out, so they read as free.
+pub async fn busy_intervals<C: tokio_postgres::GenericClient>(
+    client: &C,
+    owner_id: i32,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Vec<BusyInterval> {
+    let mut free_categories: Vec<i32> = Vec::new();
+    for row in client
+        .query(
+            "SELECT id FROM public.category WHERE owner_id=$1 AND NOT counts_as_busy",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        free_categories.push(row.get(0));
+    }
+
+    let mut intervals = Vec::new();
+    for occ in expand_occurrences(client, Some(owner_id), from.date(), to.date()).await {
+        if free_categories.contains(&occ.category_id) {
+            continue;
+        }
+        let start = block_time(occ.date, occ.start_hour).max(from);
+        let end = block_time(occ.date, occ.end_hour).min(to);
+        if start < end {
+            intervals.push(BusyInterval { start, end });
+        }
+    }
+    return merge_intervals(intervals);
+}
+
+// FREEBUSY values have to be in UTC, while blocks are in the server's local time.
+fn utc(date_time: NaiveDateTime) -> String {
+    let utc = match Local.from_local_datetime(&date_time).earliest() {
+        Some(local) => local.with_timezone(&Utc).naive_utc(),
+        None => date_time,
+    };
+    return utc.format("%Y%m%dT%H%M%SZ").to_string();
+}
+
+fn vfreebusy(
+    owner_id: i32,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    busy: &[BusyInterval],
+) -> String {
+    let mut cal = feed::calendar();
+    cal.push("METHOD", "PUBLISH");
+
+    let mut fb = Component::new("VFREEBUSY");
+    fb.push(
+        "UID",
+        &format!("freebusy-{}-{}@chaos-manager", owner_id, utc(from)),
+    );
+    fb.push("DTSTAMP", &feed::dtstamp());
+    fb.push("DTSTART", &utc(from));
+    fb.push("DTEND", &utc(to));
+    for interval in busy {
+        fb.push_with_params(
+            "FREEBUSY",
+            &[("FBTYPE", "BUSY")],
+            &format!("{}/{}", utc(interval.start), utc(interval.end)),
+        );
+    }
+    cal.components.push(fb);
+
+    return cal.write();
+}
+
+// #[get("/freebusy")]
+pub async fn get_freebusy(query: web::Query<FreeBusyQuery>) -> impl Responder {
+    let (from, to) = match (parse_bound(&query.from), parse_bound(&query.to)) {
+        (Ok(from), Ok(to)) if from < to => (from, to),
+        (Err(err), _) | (_, Err(err)) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(err);
+        }
+        _ => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json("from must be before to");
+        }
+    };
+
+    let client = connect_to_db().await;
+    let busy = busy_intervals(&client, query.owner_id, from, to).await;
+
+    match query.format.as_deref() {
+        Some("ics") => {
+            return HttpResponse::Ok()
+                .content_type("text/calendar; charset=utf-8")
+                .body(vfreebusy(query.owner_id, from, to, &busy));
+        }
+        _ => return HttpResponse::Ok().json(busy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").expect("valid time");
+    }
+
+    fn busy(start: &str, end: &str) -> BusyInterval {
+        return BusyInterval {
+            start: at(start),
+            end: at(end),
+        };
+    }
+
+    fn spans(intervals: &[BusyInterval]) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        return intervals
+            .iter()
+            .map(|interval| (interval.start, interval.end))
+            .collect();
+    }
+
+    #[test]
+    fn overlapping_and_touching_intervals_are_joined() {
+        let merged = merge_intervals(vec![
+            busy("2026-11-02 13:00", "2026-11-02 15:00"),
+            busy("2026-11-02 09:00", "2026-11-02 11:00"),
+            busy("2026-11-02 10:00", "2026-11-02 12:00"),
+            busy("2026-11-02 12:00", "2026-11-02 13:00"),
+        ]);
+
+        assert_eq!(
+            spans(&merged),
+            vec![(at("2026-11-02 09:00"), at("2026-11-02 15:00"))]
+        );
+    }
+
+    #[test]
+    fn an_interval_inside_another_doesnt_shorten_it() {
+        let merged = merge_intervals(vec![
+            busy("2026-11-02 09:00", "2026-11-02 17:00"),
+            busy("2026-11-02 10:00", "2026-11-02 11:00"),
+        ]);
+
+        assert_eq!(
+            spans(&merged),
+            vec![(at("2026-11-02 09:00"), at("2026-11-02 17:00"))]
+        );
+    }
+
+    #[test]
+    fn gaps_are_kept_and_the_result_is_sorted() {
+        let merged = merge_intervals(vec![
+            busy("2026-11-03 09:00", "2026-11-03 10:00"),
+            busy("2026-11-02 22:00", "2026-11-03 01:00"),
+            busy("2026-11-02 09:00", "2026-11-02 10:00"),
+        ]);
+
+        assert_eq!(
+            spans(&merged),
+            vec![
+                (at("2026-11-02 09:00"), at("2026-11-02 10:00")),
+                (at("2026-11-02 22:00"), at("2026-11-03 01:00")),
+                (at("2026-11-03 09:00"), at("2026-11-03 10:00")),
+            ]
+        );
+        assert!(merge_intervals(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn bounds_are_dates_or_date_times() {
+        assert_eq!(parse_bound("2026-11-02"), Ok(at("2026-11-02 00:00")));
+        assert_eq!(parse_bound("2026-11-02T09:30"), Ok(at("2026-11-02 09:30")));
+        assert_eq!(
+            parse_bound("2026-11-02T09:30:00"),
+            Ok(at("2026-11-02 09:30"))
+        );
+        assert!(parse_bound("next tuesday").is_err());
+    }
+}
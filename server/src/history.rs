@@ -0,0 +1,175 @@
+use crate::{
+    connect::connect_to_db,
+    tag,
+    task::{task_from_row, InfoPathId, Task},
+    time_entry,
+};
+use actix_web::{web::Path, HttpRequest, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient, Row};
+
+// Set by clients on requests that change tasks, so the history can say who made the change.
+const ACTOR_HEADER: &str = "X-Actor-Id";
+
+// One field of a task changing. Values are kept as text, the way they'd be shown; a
+// missing value is null. `actor_id` is null for changes the server made by itself.
+#[derive(Serialize, Deserialize)]
+pub struct TaskChange {
+    pub id: i32,
+    pub task_id: i32,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub actor_id: Option<i32>,
+    pub changed_at: NaiveDateTime,
+}
+
+pub fn change_from_row(row: &Row) -> TaskChange {
+    return TaskChange {
+        id: row.get(0),
+        task_id: row.get(1),
+        field: row.get(2),
+        old_value: row.get(3),
+        new_value: row.get(4),
+        actor_id: row.get(5),
+        changed_at: row.get(6),
+    };
+}
+
+// Who's making the request, falling back to `owner_id` when the client doesn't say.
+pub fn actor(req: &HttpRequest, owner_id: i32) -> i32 {
+    return req
+        .headers()
+        .get(ACTOR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(owner_id);
+}
+
+fn text<T: ToString>(value: Option<T>) -> Option<String> {
+    return value.map(|value| value.to_string());
+}
+
+// The fields that differ between two versions of a task, with their old and new values.
+pub fn changes(before: &Task, after: &Task) -> Vec<(&'static str, Option<String>, Option<String>)> {
+    let fields = [
+        ("title", text(Some(&before.title)), text(Some(&after.title))),
+        (
+            "description",
+            before.description.to_owned(),
+            after.description.to_owned(),
+        ),
+        (
+            "is_complete",
+            text(Some(before.is_complete)),
+            text(Some(after.is_complete)),
+        ),
+        (
+            "priority",
+            text(Some(before.priority)),
+            text(Some(after.priority)),
+        ),
+        (
+            "owner_id",
+            text(Some(before.owner_id)),
+            text(Some(after.owner_id)),
+        ),
+        (
+            "category_id",
+            text(Some(before.category_id)),
+            text(Some(after.category_id)),
+        ),
+        (
+            "estimate_minutes",
+            text(before.estimate_minutes),
+            text(after.estimate_minutes),
+        ),
+        ("due_at", text(before.due_at), text(after.due_at)),
+        ("start_at", text(before.start_at), text(after.start_at)),
+        ("rrule", before.rrule.to_owned(), after.rrule.to_owned()),
+        (
+            "repeat_after_days",
+            text(before.repeat_after_days),
+            text(after.repeat_after_days),
+        ),
+        ("parent_id", text(before.parent_id), text(after.parent_id)),
+        ("status_id", text(before.status_id), text(after.status_id)),
+        ("rank", text(before.rank), text(after.rank)),
+        (
+            "tags",
+            before.tags.as_ref().map(|tags| tags.join(", ")),
+            after.tags.as_ref().map(|tags| tags.join(", ")),
+        ),
+    ];
+    return fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .collect();
+}
+
+// The task as it's stored, tags included, for comparing before and after a change.
+pub async fn snapshot<C: GenericClient>(client: &C, task_id: i32) -> Result<Option<Task>, Error> {
+    let Some(row) = client
+        .query_opt("SELECT * FROM public.task WHERE id=$1", &[&task_id])
+        .await?
+    else {
+        return Ok(None);
+    };
+    let mut task = [task_from_row(&row)];
+    tag::load_tags(client, &mut task).await;
+    let [task] = task;
+    return Ok(Some(task));
+}
+
+// Writes a history row for every field that changed and bumps the task's updated_at.
+pub async fn record<C: GenericClient>(
+    client: &C,
+    before: &Task,
+    after: &Task,
+    actor_id: Option<i32>,
+) -> Result<(), Error> {
+    let changes = changes(before, after);
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let now = time_entry::now();
+    for (field, old_value, new_value) in changes {
+        client
+            .execute(
+                "INSERT INTO public.task_history
+                    (task_id, field, old_value, new_value, actor_id, changed_at)
+                VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&after.id, &field, &old_value, &new_value, &actor_id, &now],
+            )
+            .await?;
+    }
+    client
+        .execute(
+            "UPDATE public.task SET updated_at = $1 WHERE id = $2",
+            &[&now, &after.id],
+        )
+        .await?;
+    return Ok(());
+}
+
+// #[get("/task/{id}/history")]
+// Every recorded change to the task, oldest first.
+pub async fn get_task_history(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut changes: Vec<TaskChange> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task_history WHERE task_id=$1 ORDER BY changed_at, id",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting history")
+    {
+        changes.push(change_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(changes);
+}
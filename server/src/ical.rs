@@ -0,0 +1,325 @@
+use chrono::{Duration as Days, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+// Just enough of RFC 5545 to read and write the calendars we exchange with other apps.
+#[derive(Clone, Debug)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Property {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        return self
+            .params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+    }
+
+    // The value as a DATE or DATE-TIME, honouring the property's TZID.
+    pub fn date_time(&self) -> Result<(NaiveDateTime, bool), String> {
+        return parse_date_time_in(&self.value, self.param("TZID"));
+    }
+}
+
+impl Component {
+    pub fn new(name: &str) -> Component {
+        return Component {
+            name: name.to_string(),
+            properties: Vec::new(),
+            components: Vec::new(),
+        };
+    }
+
+    // Adds a property whose value is already in iCalendar form (dates, rules, numbers).
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.properties.push(Property {
+            name: name.to_string(),
+            params: Vec::new(),
+            value: value.to_string(),
+        });
+    }
+
+    pub fn push_with_params(&mut self, name: &str, params: &[(&str, &str)], value: &str) {
+        self.properties.push(Property {
+            name: name.to_string(),
+            params: params
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            value: value.to_string(),
+        });
+    }
+
+    // Adds a TEXT property, escaping it on the way in.
+    pub fn push_text(&mut self, name: &str, value: &str) {
+        self.push(name, &escape_text(value));
+    }
+
+    pub fn write(&self) -> String {
+        let mut out = String::new();
+        write_component(self, &mut out);
+        return out;
+    }
+
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        return self
+            .properties
+            .iter()
+            .find(|prop| prop.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn properties_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Property> {
+        return self
+            .properties
+            .iter()
+            .filter(move |prop| prop.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn value(&self, name: &str) -> Option<String> {
+        return self.property(name).map(|prop| unescape_text(&prop.value));
+    }
+}
+
+// Long lines are folded onto continuation lines that start with a space or tab.
+fn unfold(input: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in input.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+                continue;
+            }
+        }
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    return lines;
+}
+
+fn parse_line(line: &str) -> Result<Property, String> {
+    // The value starts at the first colon that isn't inside a quoted parameter value.
+    let mut in_quotes = false;
+    let mut split = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ':' if !in_quotes => {
+                split = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let split = split.ok_or(format!("content line without a value: '{}'", line))?;
+    let (head, value) = (&line[..split], &line[split + 1..]);
+
+    let mut parts = head.split(';');
+    let name = parts.next().unwrap_or("").trim().to_uppercase();
+    if name.is_empty() {
+        return Err(format!("content line without a name: '{}'", line));
+    }
+
+    let mut params = Vec::new();
+    for param in parts {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        params.push((key.to_uppercase(), value.trim_matches('"').to_string()));
+    }
+
+    return Ok(Property {
+        name,
+        params,
+        value: value.to_string(),
+    });
+}
+
+// Parses a stream into its top level components, normally a single VCALENDAR.
+pub fn parse(input: &str) -> Result<Vec<Component>, String> {
+    let mut stack: Vec<Component> = Vec::new();
+    let mut done: Vec<Component> = Vec::new();
+
+    for line in unfold(input) {
+        let prop = parse_line(&line)?;
+
+        match prop.name.as_str() {
+            "BEGIN" => stack.push(Component::new(&prop.value.trim().to_uppercase())),
+            "END" => {
+                let component = stack
+                    .pop()
+                    .ok_or(format!("END:{} without BEGIN", prop.value))?;
+                if !component.name.eq_ignore_ascii_case(prop.value.trim()) {
+                    return Err(format!(
+                        "END:{} doesn't close BEGIN:{}",
+                        prop.value, component.name
+                    ));
+                }
+                match stack.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None => done.push(component),
+                }
+            }
+            _ => match stack.last_mut() {
+                Some(component) => component.properties.push(prop),
+                None => return Err(format!("property {} outside a component", prop.name)),
+            },
+        }
+    }
+
+    if let Some(open) = stack.last() {
+        return Err(format!("BEGIN:{} is never closed", open.name));
+    }
+    return Ok(done);
+}
+
+pub fn unescape_text(value: &str) -> String {
+    let mut text = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => text.push('\n'),
+            Some(other) => text.push(other),
+            None => {}
+        }
+    }
+    return text;
+}
+
+// DATE values come back as midnight, with the flag telling them apart from DATE-TIMEs.
+// Floating times are taken as they are; UTC ones are moved to the server's local time, which
+// is what everything we store is in.
+pub fn parse_date_time(value: &str) -> Result<(NaiveDateTime, bool), String> {
+    return parse_date_time_in(value, None);
+}
+
+// The same, with times given in the `tzid` time zone also moved to local time. Zones have to
+// be IANA names like Europe/Berlin; anything else is an error rather than a silent shift.
+pub fn parse_date_time_in(
+    value: &str,
+    tzid: Option<&str>,
+) -> Result<(NaiveDateTime, bool), String> {
+    let value = value.trim();
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+
+    if value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map_err(|_| format!("invalid date '{}'", value))?;
+        return Ok((date.and_hms_opt(0, 0, 0).expect("valid time"), true));
+    }
+    let date_time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .map_err(|_| format!("invalid date-time '{}'", value))?;
+
+    if utc {
+        let local = Utc.from_utc_datetime(&date_time).with_timezone(&Local);
+        return Ok((local.naive_local(), false));
+    }
+    let Some(tzid) = tzid else {
+        return Ok((date_time, false));
+    };
+    let tz: Tz = tzid
+        .trim_matches('"')
+        .parse()
+        .map_err(|_| format!("unknown time zone '{}'", tzid))?;
+    let zoned = tz
+        .from_local_datetime(&date_time)
+        .earliest()
+        .ok_or(format!("{} doesn't exist in {}", value, tzid))?;
+    return Ok((zoned.with_timezone(&Local).naive_local(), false));
+}
+
+// DURATION values like PT1H30M or P1W. Negative durations aren't meaningful for our blocks.
+pub fn parse_duration(value: &str) -> Result<Days, String> {
+    let invalid = || format!("invalid duration '{}'", value);
+    let rest = value.trim().trim_start_matches('+');
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut total = Days::zero();
+    let mut number = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' {
+            continue;
+        }
+        let n: i64 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        total += match c {
+            'W' => Days::weeks(n),
+            'D' => Days::days(n),
+            'H' => Days::hours(n),
+            'M' => Days::minutes(n),
+            'S' => Days::seconds(n),
+            _ => return Err(invalid()),
+        };
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    return Ok(total);
+}
+
+pub fn escape_text(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n");
+}
+
+// Lines longer than 75 octets are folded, never splitting a UTF-8 character.
+fn fold_line(line: &str, out: &mut String) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn write_component(component: &Component, out: &mut String) {
+    fold_line(&format!("BEGIN:{}", component.name), out);
+    for prop in &component.properties {
+        let mut line = prop.name.to_owned();
+        for (key, value) in &prop.params {
+            line.push_str(&format!(";{}={}", key, value));
+        }
+        line.push(':');
+        line.push_str(&prop.value);
+        fold_line(&line, out);
+    }
+    for child in &component.components {
+        write_component(child, out);
+    }
+    fold_line(&format!("END:{}", component.name), out);
+}
+
+// Floating local time, matching how durations store plain hours.
+pub fn format_date_time(date_time: NaiveDateTime) -> String {
+    return date_time.format("%Y%m%dT%H%M%S").to_string();
+}
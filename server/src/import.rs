@@ -0,0 +1,504 @@
+use crate::{
+    color,
+    connect::connect_to_db,
+    duration::{self, ConflictPolicy, Duration, DEFAULT_COLOR},
+    ical::{self, Component},
+    rank,
+    recurrence::Recurrence,
+    status,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration as Days, NaiveDate, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio_postgres::GenericClient;
+
+const DEFAULT_TASK_CATEGORY: &str = "Imported";
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportQuery {
+    owner_id: i32,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PlannedDuration {
+    pub category: String,
+    pub start_hour: i32,
+    pub end_hour: i32,
+    pub recurring_days: Vec<i32>,
+    pub rrule: String,
+    pub dtstart: NaiveDate,
+    pub color: String,
+    pub cancelled_dates: Vec<NaiveDate>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PlannedTask {
+    pub category: String,
+    pub title: String,
+    pub description: String,
+    pub is_complete: bool,
+    pub priority: i32,
+    pub due_at: Option<NaiveDateTime>,
+    pub start_at: Option<NaiveDateTime>,
+}
+
+// What an import creates. `warnings` are things imported with some loss, `unsupported` are
+// things that were skipped entirely and `conflicts` are events left out because they overlap
+// blocks the owner already has.
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub categories_created: Vec<String>,
+    pub durations: Vec<PlannedDuration>,
+    pub tasks: Vec<PlannedTask>,
+    pub warnings: Vec<String>,
+    pub unsupported: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+fn describe(component: &Component) -> String {
+    return match component.value("SUMMARY") {
+        Some(summary) => format!("{} '{}'", component.name, summary),
+        None => component.name.to_owned(),
+    };
+}
+
+// Reads a VEVENT into a duration. `Err` means the event can't be represented at all; lossy
+// conversions are added to `warnings`. Overrides of single occurrences (RECURRENCE-ID) are
+// left to the caller.
+pub fn read_event(event: &Component, warnings: &mut Vec<String>) -> Result<PlannedDuration, String> {
+    let name = describe(event);
+
+    let category = match event.value("SUMMARY") {
+        Some(summary) if !summary.trim().is_empty() => summary.trim().to_string(),
+        _ => return Err(format!("{}: no SUMMARY to use as category", name)),
+    };
+    if event.property("RECURRENCE-ID").is_some() {
+        return Err(format!("{}: overrides of single occurrences", name));
+    }
+    if event.property("EXRULE").is_some() || event.property("RDATE").is_some() {
+        return Err(format!("{}: EXRULE and RDATE", name));
+    }
+
+    let (start, end) = read_event_times(event).map_err(|err| format!("{}: {}", name, err))?;
+    let (start_hour, end_hour) =
+        whole_hours(start, end).map_err(|err| format!("{}: {}", name, err))?;
+    if start.minute() > 0 || end.minute() > 0 {
+        warnings.push(format!("{}: rounded out to whole hours", name));
+    }
+
+    let rules: Vec<&ical::Property> = event.properties_named("RRULE").collect();
+    let rule = match rules.as_slice() {
+        [] => Recurrence::parse("FREQ=DAILY;COUNT=1"),
+        [rule] => Recurrence::parse(&rule.value),
+        _ => Err("more than one RRULE".to_string()),
+    };
+    let rule = rule.map_err(|err| format!("{}: {}", name, err))?;
+
+    let mut cancelled_dates = Vec::new();
+    for exdate in event.properties_named("EXDATE") {
+        for value in exdate.value.split(',') {
+            match ical::parse_date_time_in(value, exdate.param("TZID")) {
+                Ok((date, _)) => cancelled_dates.push(date.date()),
+                Err(err) => warnings.push(format!("{}: EXDATE {}", name, err)),
+            }
+        }
+    }
+
+    let dtstart = start.date();
+    return Ok(PlannedDuration {
+        category,
+        start_hour,
+        end_hour,
+        recurring_days: rule.weekday_mask(dtstart),
+        rrule: rule.to_string(),
+        dtstart,
+        color: event
+            .value("X-APPLE-CALENDAR-COLOR")
+            .or(event.value("COLOR").and_then(|name| color::css_name_to_hex(&name)))
+            .unwrap_or(DEFAULT_COLOR.to_string()),
+        cancelled_dates,
+    });
+}
+
+// Start and end of a VEVENT, from DTEND or DURATION. All day events last the whole day.
+pub fn read_event_times(event: &Component) -> Result<(NaiveDateTime, NaiveDateTime), String> {
+    let dtstart = event.property("DTSTART").ok_or("no DTSTART".to_string())?;
+    let (start, is_date) = dtstart.date_time()?;
+    let all_day = is_date || dtstart.param("VALUE") == Some("DATE");
+
+    let end = if let Some(prop) = event.property("DTEND") {
+        prop.date_time()?.0
+    } else if let Some(prop) = event.property("DURATION") {
+        start + ical::parse_duration(&prop.value)?
+    } else if all_day {
+        start + Days::days(1)
+    } else {
+        return Err("no DTEND or DURATION".to_string());
+    };
+    return Ok((start, end));
+}
+
+// Blocks are whole hours within a single day, so partial hours are rounded outwards.
+pub fn whole_hours(start: NaiveDateTime, end: NaiveDateTime) -> Result<(i32, i32), String> {
+    let start_hour = start.hour() as i32;
+    let end_hour = if end.date() == start.date() {
+        end.hour() as i32 + if end.minute() > 0 { 1 } else { 0 }
+    } else if end == (start.date() + Days::days(1)).and_hms_opt(0, 0, 0).expect("valid time") {
+        24
+    } else {
+        return Err("events spanning more than one day".to_string());
+    };
+    if end_hour <= start_hour {
+        return Err("ends before it starts".to_string());
+    }
+    return Ok((start_hour, end_hour));
+}
+
+pub fn read_todo(todo: &Component, warnings: &mut Vec<String>) -> Result<PlannedTask, String> {
+    let name = describe(todo);
+
+    let title = match todo.value("SUMMARY") {
+        Some(summary) if !summary.trim().is_empty() => summary.trim().to_string(),
+        _ => return Err(format!("{}: no SUMMARY", name)),
+    };
+
+    if todo.property("RRULE").is_some() {
+        warnings.push(format!("{}: RRULE isn't imported", name));
+    }
+
+    // A DATE due date means by the end of that day.
+    let mut read_time = |prop: &str, date_offset: Days| {
+        let value = todo.property(prop)?;
+        match value.date_time() {
+            Ok((date_time, true)) => return Some(date_time + date_offset),
+            Ok((date_time, false)) => return Some(date_time),
+            Err(err) => {
+                warnings.push(format!("{}: {} {}", name, prop, err));
+                return None;
+            }
+        }
+    };
+    let due_at = read_time("DUE", Days::days(1) - Days::seconds(1));
+    let start_at = read_time("DTSTART", Days::zero());
+
+    // CATEGORIES holds a comma separated list; the first one picks the category.
+    let category = todo
+        .property("CATEGORIES")
+        .and_then(|prop| prop.value.split(',').next().map(ical::unescape_text))
+        .map(|category| category.trim().to_string())
+        .filter(|category| !category.is_empty())
+        .unwrap_or(DEFAULT_TASK_CATEGORY.to_string());
+
+    let status = todo.value("STATUS").unwrap_or_default().to_uppercase();
+
+    return Ok(PlannedTask {
+        category,
+        title,
+        description: todo.value("DESCRIPTION").unwrap_or_default(),
+        is_complete: status == "COMPLETED" || todo.property("COMPLETED").is_some(),
+        priority: todo
+            .value("PRIORITY")
+            .and_then(|priority| priority.trim().parse().ok())
+            .unwrap_or(0),
+        due_at,
+        start_at,
+    });
+}
+
+// Works out everything the calendar would create without touching the database.
+pub fn plan_import(input: &str) -> Result<ImportReport, String> {
+    let mut report = ImportReport::default();
+
+    for calendar in ical::parse(input)? {
+        if calendar.name != "VCALENDAR" {
+            report.unsupported.push(calendar.name.to_owned());
+            continue;
+        }
+        for component in &calendar.components {
+            match component.name.as_str() {
+                "VEVENT" => match read_event(component, &mut report.warnings) {
+                    Ok(dur) => report.durations.push(dur),
+                    Err(err) => report.unsupported.push(err),
+                },
+                "VTODO" => match read_todo(component, &mut report.warnings) {
+                    Ok(task) => report.tasks.push(task),
+                    Err(err) => report.unsupported.push(err),
+                },
+                "VTIMEZONE" => {}
+                _ => report.unsupported.push(describe(component)),
+            }
+        }
+    }
+    return Ok(report);
+}
+
+// Category ids by lower cased title, creating the categories the plan needs but doesn't have.
+async fn resolve_categories<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    report: &mut ImportReport,
+) -> Result<HashMap<String, i32>, tokio_postgres::Error> {
+    let mut categories = HashMap::new();
+
+    for row in client
+        .query(
+            "SELECT id, title FROM public.category WHERE owner_id=$1 AND deleted_at IS NULL",
+            &[&owner_id],
+        )
+        .await?
+    {
+        let title: String = row.get(1);
+        categories.insert(title.to_lowercase(), row.get(0));
+    }
+
+    let wanted: Vec<String> = report
+        .durations
+        .iter()
+        .map(|dur| dur.category.to_owned())
+        .chain(report.tasks.iter().map(|task| task.category.to_owned()))
+        .collect();
+
+    for title in wanted {
+        if categories.contains_key(&title.to_lowercase()) {
+            continue;
+        }
+        let id: i32 = client
+            .query_one(
+                "INSERT INTO public.category (
+                    title,
+                    description,
+                    priority,
+                    owner_id
+                ) VALUES ($1, '', 0, $2) RETURNING id",
+                &[&title, &owner_id],
+            )
+            .await?
+            .get(0);
+        categories.insert(title.to_lowercase(), id);
+        report.categories_created.push(title);
+    }
+    return Ok(categories);
+}
+
+// Events go through the same overlap check as a new duration, against what the owner had
+// and what the import has written so far. Ones the policy turns down move to `conflicts`.
+async fn apply_import<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    report: &mut ImportReport,
+    categories: &HashMap<String, i32>,
+    policy: ConflictPolicy,
+) -> Result<(), tokio_postgres::Error> {
+    for dur in std::mem::take(&mut report.durations) {
+        let category_id = categories[&dur.category.to_lowercase()];
+
+        let candidate = Duration {
+            id: 0,
+            owner_id,
+            category_id,
+            start_hour: dur.start_hour,
+            end_hour: dur.end_hour,
+            recurring_days: dur.recurring_days.to_owned(),
+            color: dur.color.to_owned(),
+            rrule: Some(dur.rrule.to_owned()),
+            dtstart: Some(dur.dtstart),
+            schedule_id: None,
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            completed_at: None,
+        };
+        if duration::resolve_conflicts(client, &candidate, policy)
+            .await
+            .is_some()
+        {
+            report.conflicts.push(format!(
+                "VEVENT '{}' at {}-{}: overlaps existing blocks",
+                dur.category, dur.start_hour, dur.end_hour
+            ));
+            continue;
+        }
+
+        let row = client
+            .query_one(
+                "INSERT INTO public.duration (
+                    owner_id,
+                    category_id,
+                    start_hour,
+                    end_hour,
+                    recurring_days,
+                    color,
+                    rrule,
+                    dtstart
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+                &[
+                    &owner_id,
+                    &category_id,
+                    &dur.start_hour,
+                    &dur.end_hour,
+                    &dur.recurring_days,
+                    &dur.color,
+                    &dur.rrule,
+                    &dur.dtstart,
+                ],
+            )
+            .await?;
+        let duration_id: i32 = row.get(0);
+        duration::stamp_completed(client, duration_id).await?;
+
+        for date in &dur.cancelled_dates {
+            client
+                .execute(
+                    "INSERT INTO public.duration_exception (
+                        duration_id,
+                        occurrence_date,
+                        is_cancelled
+                    ) VALUES ($1, $2, true)
+                    ON CONFLICT (duration_id, occurrence_date) DO NOTHING",
+                    &[&duration_id, date],
+                )
+                .await?;
+        }
+        report.durations.push(dur);
+    }
+
+    for task in &report.tasks {
+        let category_id = categories[&task.category.to_lowercase()];
+
+        let row = client
+            .query_one(
+                "INSERT INTO public.task (
+                    title,
+                    description,
+                    is_complete,
+                    priority,
+                    owner_id,
+                    category_id,
+                    due_at,
+                    start_at,
+                    rank
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8,
+                    (SELECT COALESCE(MAX(rank), 0) + $9 FROM public.task WHERE category_id = $6)
+                ) RETURNING id",
+                &[
+                    &task.title,
+                    &task.description,
+                    &task.is_complete,
+                    &task.priority,
+                    &owner_id,
+                    &category_id,
+                    &task.due_at,
+                    &task.start_at,
+                    &rank::RANK_STEP,
+                ],
+            )
+            .await?;
+        status::reconcile(client, row.get(0), None).await?;
+    }
+    return Ok(());
+}
+
+// Shared by the endpoint and the CLI. Everything is written in one transaction so a failed
+// import leaves nothing behind; a dry run writes it all the same and rolls it back, so its
+// report shows the same conflicts a real import would run into.
+pub async fn import_calendar(
+    input: &str,
+    owner_id: i32,
+    dry_run: bool,
+    policy: ConflictPolicy,
+) -> Result<ImportReport, String> {
+    let mut report = plan_import(input)?;
+    report.dry_run = dry_run;
+
+    let mut client = connect_to_db().await;
+    let transaction = client.transaction().await.map_err(|err| err.to_string())?;
+
+    let categories = resolve_categories(&transaction, owner_id, &mut report)
+        .await
+        .map_err(|err| err.to_string())?;
+    apply_import(&transaction, owner_id, &mut report, &categories, policy)
+        .await
+        .map_err(|err| err.to_string())?;
+    if dry_run {
+        transaction.rollback().await.map_err(|err| err.to_string())?;
+        return Ok(report);
+    }
+    transaction.commit().await.map_err(|err| err.to_string())?;
+
+    return Ok(report);
+}
+
+// #[post("/import/ics")]
+pub async fn import_ics(
+    _req: HttpRequest,
+    body: String,
+    query: web::Query<ImportQuery>,
+) -> impl Responder {
+    match import_calendar(&body, query.owner_id, query.dry_run, query.on_conflict).await {
+        Ok(report) if report.dry_run => return HttpResponse::Ok().json(report),
+        Ok(report) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(report);
+        }
+        Err(err) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(err);
+        }
+    }
+}
+
+// `server import <file.ics> --owner <id> [--dry-run] [--on-conflict reject|allow|trim]`
+pub async fn run_cli(args: &[String]) -> std::io::Result<()> {
+    let usage = "usage: server import <file.ics> --owner <id> [--dry-run] \
+        [--on-conflict reject|allow|trim]";
+    let mut path = None;
+    let mut owner_id = None;
+    let mut dry_run = false;
+    let mut policy = Some(ConflictPolicy::Reject);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--owner" => owner_id = args.next().and_then(|id| id.parse::<i32>().ok()),
+            "--on-conflict" => {
+                policy = args
+                    .next()
+                    .and_then(|policy| serde_json::from_value(serde_json::json!(policy)).ok())
+            }
+            _ => path = Some(arg.to_owned()),
+        }
+    }
+
+    let (path, owner_id, policy) = match (path, owner_id, policy) {
+        (Some(path), Some(owner_id), Some(policy)) => (path, owner_id, policy),
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    };
+
+    let input = std::fs::read_to_string(&path)?;
+    match import_calendar(&input, owner_id, dry_run, policy).await {
+        Ok(report) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).expect("error writing report")
+            );
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,9 +1,36 @@
+// Every function in the crate ends in an explicit `return`, which is the house style.
+#![allow(clippy::needless_return)]
 use actix_cors::Cors;
 use actix_web::{http, web, App, HttpServer};
+mod blackout;
+mod caldav;
+mod capacity;
 mod category;
+mod color;
 mod connect;
+mod dependency;
 mod duration;
+mod exception;
+mod feed;
+mod freebusy;
+mod history;
+mod ical;
+mod import;
+mod notify;
+mod planner;
+mod pomodoro;
+mod rank;
+mod recurrence;
+mod report;
+mod schedule;
+mod series;
+mod solver;
+mod status;
+mod subtask;
+mod tag;
 mod task;
+mod time_entry;
+mod trash;
 
 fn routes(app: &mut web::ServiceConfig) {
     app.service(
@@ -23,14 +50,63 @@ fn routes(app: &mut web::ServiceConfig) {
                     .route(web::get().to(task::get_task_by_id))
                     .route(web::delete().to(task::delete_task_by_id)),
             )
+            .service(
+                web::resource("task/{id}/series").route(web::get().to(series::get_task_series)),
+            )
+            .service(web::resource("task/{id}/tree").route(web::get().to(subtask::get_task_tree)))
+            .service(web::resource("task/{id}/move").route(web::post().to(rank::move_task)))
+            .service(
+                web::resource("task/{id}/restore").route(web::post().to(trash::restore_task)),
+            )
+            .service(
+                web::resource("task/{id}/history").route(web::get().to(history::get_task_history)),
+            )
+            .service(
+                web::resource("task/{id}/transitions")
+                    .route(web::get().to(status::get_transitions)),
+            )
+            .service(
+                web::resource("task/{id}/dependencies")
+                    .route(web::get().to(dependency::get_dependencies))
+                    .route(web::post().to(dependency::add_dependency)),
+            )
+            .service(
+                web::resource("task/{id}/dependency/{blocked_by}")
+                    .route(web::delete().to(dependency::delete_dependency)),
+            )
+            .service(web::resource("tags").route(web::get().to(tag::get_tags)))
+            .service(web::resource("tag").route(web::post().to(tag::create_tag)))
+            .service(
+                web::resource("tag/{id}")
+                    .route(web::put().to(tag::update_tag))
+                    .route(web::delete().to(tag::delete_tag)),
+            )
+            .service(web::resource("tag/{id}/merge").route(web::post().to(tag::merge_tag)))
             .service(web::resource("categories").to(category::get_categories))
             .service(web::resource("category").route(web::post().to(category::create_category)))
+            .service(
+                web::resource("category/{id}/statuses")
+                    .route(web::get().to(status::get_category_statuses)),
+            )
+            .service(
+                web::resource("category/{id}/status").route(web::post().to(status::create_status)),
+            )
+            .service(web::resource("category/{id}/board").route(web::get().to(status::get_board)))
+            .service(
+                web::resource("status/{id}")
+                    .route(web::put().to(status::update_status))
+                    .route(web::delete().to(status::delete_status)),
+            )
             .service(
                 web::resource("category/{id}")
                     .route(web::get().to(category::get_category_by_id))
                     .route(web::put().to(category::update_category))
                     .route(web::delete().to(category::delete_category_by_id)),
             )
+            .service(
+                web::resource("category/{id}/restore")
+                    .route(web::post().to(trash::restore_category)),
+            )
             .service(
                 web::resource("duration")
                     .route(web::post().to(duration::create_duration))
@@ -41,23 +117,164 @@ fn routes(app: &mut web::ServiceConfig) {
                     .route(web::get().to(duration::get_duration_by_id))
                     .route(web::delete().to(duration::delete_duration_by_id)),
             )
+            .service(
+                web::resource("duration/{id}/restore")
+                    .route(web::post().to(trash::restore_duration)),
+            )
+            .service(web::resource("trash").route(web::get().to(trash::get_trash)))
+            .service(
+                web::resource("duration/{id}/exceptions")
+                    .route(web::get().to(exception::get_duration_exceptions)),
+            )
+            .service(
+                web::resource("duration/{id}/exception")
+                    .route(web::post().to(exception::create_duration_exception)),
+            )
+            .service(
+                web::resource("duration/{id}/exception/{date}")
+                    .route(web::delete().to(exception::delete_duration_exception)),
+            )
             .service(
                 web::resource("durations")
                     .route(web::get().to(duration::get_durations)),
-            ),
+            )
+            .service(
+                web::resource("durations/occurrences")
+                    .route(web::get().to(duration::get_occurrences)),
+            )
+            .service(
+                web::resource("durations/conflicts")
+                    .route(web::get().to(duration::get_duration_conflicts)),
+            )
+            .service(web::resource("schedules").route(web::get().to(schedule::get_schedules)))
+            .service(
+                web::resource("schedules/active")
+                    .route(web::get().to(schedule::get_active_schedule)),
+            )
+            .service(web::resource("schedule").route(web::post().to(schedule::create_schedule)))
+            .service(web::resource("schedule/solve").route(web::post().to(solver::solve_schedule)))
+            .service(
+                web::resource("schedule/{id}")
+                    .route(web::get().to(schedule::get_schedule_by_id))
+                    .route(web::put().to(schedule::update_schedule))
+                    .route(web::delete().to(schedule::delete_schedule_by_id)),
+            )
+            .service(
+                web::resource("schedule/{id}/clone")
+                    .route(web::post().to(schedule::clone_schedule)),
+            )
+            .service(
+                web::resource("schedule/{id}/restore")
+                    .route(web::post().to(trash::restore_schedule)),
+            )
+            .service(web::resource("import/ics").route(web::post().to(import::import_ics)))
+            .service(
+                web::resource("calendar/token").route(web::post().to(feed::create_feed_token)),
+            )
+            .service(web::resource("freebusy").route(web::get().to(freebusy::get_freebusy)))
+            .service(
+                web::resource("plan")
+                    .route(web::get().to(planner::get_plan))
+                    .route(web::post().to(planner::generate_plan)),
+            )
+            .service(web::resource("timer").route(web::get().to(time_entry::get_timer)))
+            .service(web::resource("timer/start").route(web::post().to(time_entry::start_timer)))
+            .service(web::resource("timer/stop").route(web::post().to(time_entry::stop_timer)))
+            .service(
+                web::resource("time_entries").route(web::get().to(time_entry::get_time_entries)),
+            )
+            .service(
+                web::resource("time_entries/report")
+                    .route(web::get().to(time_entry::get_time_report)),
+            )
+            .service(
+                web::resource("time_entry").route(web::post().to(time_entry::create_time_entry)),
+            )
+            .service(
+                web::resource("time_entry/{id}")
+                    .route(web::put().to(time_entry::update_time_entry))
+                    .route(web::delete().to(time_entry::delete_time_entry)),
+            )
+            .service(web::resource("capacity").route(web::get().to(capacity::get_capacity)))
+            .service(
+                web::resource("reports/planned_vs_actual")
+                    .route(web::get().to(report::get_planned_vs_actual)),
+            )
+            .service(web::resource("pomodoro").route(web::get().to(pomodoro::get_pomodoro)))
+            .service(
+                web::resource("pomodoro/start").route(web::post().to(pomodoro::start_pomodoro)),
+            )
+            .service(
+                web::resource("pomodoro/next").route(web::post().to(pomodoro::next_pomodoro)),
+            )
+            .service(
+                web::resource("pomodoro/stop").route(web::post().to(pomodoro::stop_pomodoro)),
+            )
+            .service(
+                web::resource("pomodoro/history")
+                    .route(web::get().to(pomodoro::get_pomodoro_history)),
+            )
+            .service(
+                web::resource("notifications").route(web::get().to(notify::get_notifications)),
+            )
+            .service(
+                web::resource("notifications/stream")
+                    .route(web::get().to(notify::stream_notifications)),
+            )
+            .service(
+                web::resource("notifications/preferences")
+                    .route(web::get().to(notify::get_notification_preferences))
+                    .route(web::put().to(notify::update_notification_preferences)),
+            )
+            .service(
+                web::resource("blackouts").route(web::get().to(blackout::get_owner_blackouts)),
+            )
+            .service(
+                web::resource("blackouts/import")
+                    .route(web::post().to(blackout::import_holiday_list)),
+            )
+            .service(web::resource("blackout").route(web::post().to(blackout::create_blackout)))
+            .service(
+                web::resource("blackout/{id}").route(web::delete().to(blackout::delete_blackout)),
+            )
+            .service(web::resource("calendar.ics").route(web::get().to(feed::get_calendar_feed)))
+            .service(
+                web::resource("caldav-password").route(web::post().to(caldav::create_password)),
+            )
+            .service(web::resource(".well-known/caldav").to(caldav::well_known))
+            .service(web::resource("caldav{tail:.*}").to(caldav::dispatch)),
     );
 }
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    connect::migrate(&connect::connect_to_db().await).await;
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("import") {
+        return import::run_cli(&args[2..]).await;
+    }
+    if args.get(1).map(String::as_str) == Some("holidays") {
+        return blackout::run_cli(&args[2..]).await;
+    }
+
+    let hub = web::Data::new(notify::Hub::default());
+    actix_web::rt::spawn(notify::run_scheduler(hub.clone()));
+    actix_web::rt::spawn(rank::run_rebalancer());
+    actix_web::rt::spawn(trash::run_purger());
+
+    HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://localhost:3000")
             .allowed_origin_fn(|origin, _req_head| origin.as_bytes().ends_with(b".rust-lang.org"))
             .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
+            .allowed_header(http::header::HeaderName::from_static("x-actor-id"))
             .max_age(3600);
-        return App::new().wrap(cors).configure(routes);
+        return App::new()
+            .wrap(cors)
+            .app_data(hub.clone())
+            .configure(routes);
     })
     .bind(("127.0.0.1", 8080))?
     .run()
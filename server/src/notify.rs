@@ -0,0 +1,543 @@
+use crate::{
+    connect::{connect_to_db, try_connect_to_db},
+    duration::expand_occurrences,
+    feed::block_time,
+    task::{task_from_row, Task},
+    time_entry,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration as Days, NaiveDateTime, Timelike};
+use futures_util::stream;
+use lettre::{message::Message, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_postgres::{Error, GenericClient, Row};
+
+const SCHEDULER_INTERVAL_SECONDS: u64 = 60;
+const DEFAULT_BLOCK_MINUTES_BEFORE: i32 = 10;
+const DEFAULT_TASK_MINUTES_BEFORE: i32 = 60;
+// Reminders further ahead than a day aren't reminders any more.
+const MAX_MINUTES_BEFORE: i32 = 24 * 60;
+
+// Fans notifications out to every open event stream. Each stream picks its owner's out.
+pub struct Hub {
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Default for Hub {
+    fn default() -> Hub {
+        let (sender, _) = broadcast::channel(256);
+        return Hub { sender };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notification {
+    pub id: i32,
+    pub owner_id: i32,
+    pub kind: String,
+    pub reference: String,
+    pub title: String,
+    pub body: String,
+    pub fire_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub suppressed: bool,
+}
+
+// A lead time of `None` switches that kind of reminder off. Quiet hours run from
+// `quiet_start` up to `quiet_end` and may wrap past midnight, e.g. 22 to 7.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Preferences {
+    pub owner_id: i32,
+    pub block_minutes_before: Option<i32>,
+    pub task_minutes_before: Option<i32>,
+    pub sse_enabled: bool,
+    pub webhook_url: Option<String>,
+    pub email: Option<String>,
+    pub quiet_start: Option<i32>,
+    pub quiet_end: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerQuery {
+    owner_id: i32,
+}
+
+// The ways a notification can leave the server.
+pub enum Channel {
+    Sse,
+    Webhook(String),
+    Email(String),
+}
+
+pub fn notification_from_row(row: &Row) -> Notification {
+    return Notification {
+        id: row.get(0),
+        owner_id: row.get(1),
+        kind: row.get(2),
+        reference: row.get(3),
+        title: row.get(4),
+        body: row.get(5),
+        fire_at: row.get(6),
+        delivered_at: row.get(7),
+        suppressed: row.get(8),
+    };
+}
+
+pub fn preferences_from_row(row: &Row) -> Preferences {
+    return Preferences {
+        owner_id: row.get(0),
+        block_minutes_before: row.get(1),
+        task_minutes_before: row.get(2),
+        sse_enabled: row.get(3),
+        webhook_url: row.get(4),
+        email: row.get(5),
+        quiet_start: row.get(6),
+        quiet_end: row.get(7),
+    };
+}
+
+impl Preferences {
+    pub fn default_for(owner_id: i32) -> Preferences {
+        return Preferences {
+            owner_id,
+            block_minutes_before: Some(DEFAULT_BLOCK_MINUTES_BEFORE),
+            task_minutes_before: Some(DEFAULT_TASK_MINUTES_BEFORE),
+            sse_enabled: true,
+            webhook_url: None,
+            email: None,
+            quiet_start: None,
+            quiet_end: None,
+        };
+    }
+
+    pub fn is_quiet(&self, at: NaiveDateTime) -> bool {
+        let (Some(start), Some(end)) = (self.quiet_start, self.quiet_end) else {
+            return false;
+        };
+        let hour = at.hour() as i32;
+        if start <= end {
+            return start <= hour && hour < end;
+        }
+        return hour >= start || hour < end;
+    }
+
+    pub fn channels(&self) -> Vec<Channel> {
+        let mut channels = Vec::new();
+        if self.sse_enabled {
+            channels.push(Channel::Sse);
+        }
+        if let Some(url) = self.webhook_url.as_ref().filter(|url| !url.is_empty()) {
+            channels.push(Channel::Webhook(url.to_owned()));
+        }
+        if let Some(email) = self.email.as_ref().filter(|email| !email.is_empty()) {
+            channels.push(Channel::Email(email.to_owned()));
+        }
+        return channels;
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for minutes in [self.block_minutes_before, self.task_minutes_before]
+            .into_iter()
+            .flatten()
+        {
+            if !(0..=MAX_MINUTES_BEFORE).contains(&minutes) {
+                return Err(format!(
+                    "reminders can be 0-{} minutes ahead",
+                    MAX_MINUTES_BEFORE
+                ));
+            }
+        }
+        for hour in [self.quiet_start, self.quiet_end].into_iter().flatten() {
+            if !(0..24).contains(&hour) {
+                return Err("quiet hours must be within 0-23".to_string());
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Channel {
+    pub async fn deliver(&self, hub: &Hub, notification: &Notification) -> Result<(), String> {
+        match self {
+            Channel::Sse => {
+                // Nobody listening isn't a failure; the notification is in the log either way.
+                let _ = hub.sender.send(notification.clone());
+                return Ok(());
+            }
+            Channel::Webhook(url) => {
+                reqwest::Client::new()
+                    .post(url)
+                    .json(notification)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|err| err.to_string())?;
+                return Ok(());
+            }
+            Channel::Email(address) => return send_email(address, notification).await,
+        }
+    }
+}
+
+// SMTP_HOST and SMTP_PORT point at the mail server, which can be a local stand-in like
+// MailHog. The connection is plain, so use a relay you trust.
+async fn send_email(address: &str, notification: &Notification) -> Result<(), String> {
+    dotenv::dotenv().ok();
+    let host = env::var("SMTP_HOST").unwrap_or("localhost".to_string());
+    let port = env::var("SMTP_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(25);
+    let from = env::var("SMTP_FROM").unwrap_or("chaos-manager@localhost".to_string());
+
+    let message = Message::builder()
+        .from(
+            from.parse()
+                .map_err(|_| format!("invalid SMTP_FROM '{}'", from))?,
+        )
+        .to(address
+            .parse()
+            .map_err(|_| format!("invalid email '{}'", address))?)
+        .subject(&notification.title)
+        .body(notification.body.to_owned())
+        .map_err(|err| err.to_string())?;
+
+    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(port)
+        .build()
+        .send(message)
+        .await
+        .map_err(|err| err.to_string())?;
+    return Ok(());
+}
+
+async fn get_preferences<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+) -> Result<Preferences, Error> {
+    return Ok(client
+        .query_opt(
+            "SELECT * FROM public.notification_preference WHERE owner_id=$1",
+            &[&owner_id],
+        )
+        .await?
+        .map(|row| preferences_from_row(&row))
+        .unwrap_or(Preferences::default_for(owner_id)));
+}
+
+// Records the notification unless one with the same kind and reference already exists, so
+// each reminder fires once however often the scheduler runs. Returns it when it's new.
+pub async fn record<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    kind: &str,
+    reference: &str,
+    title: &str,
+    body: &str,
+    fire_at: NaiveDateTime,
+) -> Result<Option<Notification>, Error> {
+    return Ok(client
+        .query_opt(
+            "INSERT INTO public.notification (owner_id, kind, reference, title, body, fire_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (owner_id, kind, reference) DO NOTHING
+            RETURNING *",
+            &[&owner_id, &kind, &reference, &title, &body, &fire_at],
+        )
+        .await?
+        .map(|row| notification_from_row(&row)));
+}
+
+// Sends a new notification on the owner's channels, or marks it suppressed in quiet hours.
+pub async fn dispatch<C: GenericClient>(
+    client: &C,
+    hub: &Hub,
+    prefs: &Preferences,
+    mut notification: Notification,
+) -> Result<(), Error> {
+    let now = time_entry::now();
+    if prefs.is_quiet(now) {
+        client
+            .execute(
+                "UPDATE public.notification SET suppressed = true WHERE id = $1",
+                &[&notification.id],
+            )
+            .await?;
+        return Ok(());
+    }
+
+    notification.delivered_at = Some(now);
+    for channel in prefs.channels() {
+        if let Err(err) = channel.deliver(hub, &notification).await {
+            eprintln!("notification {} not delivered: {}", notification.id, err);
+        }
+    }
+    client
+        .execute(
+            "UPDATE public.notification SET delivered_at = $1 WHERE id = $2",
+            &[&now, &notification.id],
+        )
+        .await?;
+    return Ok(());
+}
+
+// Reminders for blocks starting within the owner's lead time.
+async fn remind_blocks<C: GenericClient>(
+    client: &C,
+    hub: &Hub,
+    prefs: &Preferences,
+    titles: &HashMap<i32, String>,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    let Some(minutes_before) = prefs.block_minutes_before else {
+        return Ok(());
+    };
+    let horizon = now + Days::minutes(minutes_before as i64);
+
+    for occ in expand_occurrences(client, Some(prefs.owner_id), now.date(), horizon.date()).await {
+        let starts_at = block_time(occ.date, occ.start_hour);
+        if starts_at <= now || starts_at > horizon {
+            continue;
+        }
+
+        let title = titles
+            .get(&occ.category_id)
+            .cloned()
+            .unwrap_or("Untitled".to_string());
+        let notification = record(
+            client,
+            prefs.owner_id,
+            "block_start",
+            &format!("duration:{}:{}", occ.duration_id, occ.date),
+            &format!("{} starts at {}", title, starts_at.format("%H:%M")),
+            &format!(
+                "{} is scheduled from {} to {}.",
+                title,
+                starts_at.format("%H:%M"),
+                block_time(occ.date, occ.end_hour).format("%H:%M")
+            ),
+            starts_at - Days::minutes(minutes_before as i64),
+        )
+        .await?;
+        if let Some(notification) = notification {
+            dispatch(client, hub, prefs, notification).await?;
+        }
+    }
+    return Ok(());
+}
+
+// Open tasks coming due within the owner's lead time. The due date is part of the
+// reference, so moving it gives a fresh reminder.
+async fn remind_tasks<C: GenericClient>(
+    client: &C,
+    hub: &Hub,
+    prefs: &Preferences,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    let Some(minutes_before) = prefs.task_minutes_before else {
+        return Ok(());
+    };
+    let horizon = now + Days::minutes(minutes_before as i64);
+
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE owner_id=$1 AND NOT is_complete AND deleted_at IS NULL
+                AND due_at > $2 AND due_at <= $3",
+            &[&prefs.owner_id, &now, &horizon],
+        )
+        .await?
+    {
+        tasks.push(task_from_row(&row));
+    }
+
+    for task in &tasks {
+        let Some(due_at) = task.due_at else {
+            continue;
+        };
+        let notification = record(
+            client,
+            prefs.owner_id,
+            "task_due",
+            &format!("task:{}:{}", task.id, due_at.format("%Y%m%dT%H%M")),
+            &format!("{} is due at {}", task.title, due_at.format("%H:%M")),
+            &format!(
+                "{} is due on {}.",
+                task.title,
+                due_at.format("%Y-%m-%d at %H:%M")
+            ),
+            due_at - Days::minutes(minutes_before as i64),
+        )
+        .await?;
+        if let Some(notification) = notification {
+            dispatch(client, hub, prefs, notification).await?;
+        }
+    }
+    return Ok(());
+}
+
+// One pass over every user, firing whatever is due.
+pub async fn run_reminders(hub: &Hub) -> Result<(), Error> {
+    let client = try_connect_to_db().await?;
+    let now = time_entry::now();
+
+    let mut preferences: Vec<Preferences> = Vec::new();
+    for row in client
+        .query("SELECT id FROM public.user", &[])
+        .await?
+    {
+        preferences.push(get_preferences(&client, row.get(0)).await?);
+    }
+
+    for prefs in &preferences {
+        let mut titles: HashMap<i32, String> = HashMap::new();
+        for row in client
+            .query(
+                "SELECT id, title FROM public.category WHERE owner_id=$1",
+                &[&prefs.owner_id],
+            )
+            .await?
+        {
+            titles.insert(row.get(0), row.get(1));
+        }
+
+        remind_blocks(&client, hub, prefs, &titles, now).await?;
+        remind_tasks(&client, hub, prefs, now).await?;
+    }
+    return Ok(());
+}
+
+// The background scheduler, started once next to the HTTP server. A failed pass is logged
+// and tried again on the next tick. Each pass runs as its own task so a panic in the shared
+// occurrence helpers only costs that pass.
+pub async fn run_scheduler(hub: web::Data<Hub>) {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(SCHEDULER_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let hub = hub.clone();
+        match actix_web::rt::spawn(async move { run_reminders(&hub).await }).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("error sending reminders: {}", err),
+            Err(err) => eprintln!("reminder pass failed: {}", err),
+        }
+    }
+}
+
+// #[get("/notifications")]
+pub async fn get_notifications(query: web::Query<OwnerQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut notifications: Vec<Notification> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.notification WHERE owner_id=$1 ORDER BY fire_at DESC LIMIT 100",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting notifications")
+    {
+        notifications.push(notification_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(notifications);
+}
+
+// #[get("/notifications/stream")]
+// Server-sent events, one `notification` event per reminder for the owner.
+pub async fn stream_notifications(
+    hub: web::Data<Hub>,
+    query: web::Query<OwnerQuery>,
+) -> impl Responder {
+    let owner_id = query.owner_id;
+    let receiver = hub.sender.subscribe();
+
+    let events = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(notification) if notification.owner_id == owner_id => {
+                    let event = format!(
+                        "event: notification\ndata: {}\n\n",
+                        serde_json::to_string(&notification).expect("serializable notification")
+                    );
+                    return Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), receiver));
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    return HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events);
+}
+
+// #[get("/notifications/preferences")]
+pub async fn get_notification_preferences(query: web::Query<OwnerQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let prefs = get_preferences(&client, query.owner_id)
+        .await
+        .expect("error getting notification preferences");
+    return HttpResponse::Ok().json(prefs);
+}
+
+// #[put("/notifications/preferences")]
+pub async fn update_notification_preferences(
+    _req: HttpRequest,
+    params: web::Json<Preferences>,
+) -> impl Responder {
+    if let Err(msg) = params.validate() {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    let client = connect_to_db().await;
+
+    match client
+        .execute(
+            "INSERT INTO public.notification_preference (
+                owner_id,
+                block_minutes_before,
+                task_minutes_before,
+                sse_enabled,
+                webhook_url,
+                email,
+                quiet_start,
+                quiet_end
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (owner_id) DO UPDATE SET
+                block_minutes_before = EXCLUDED.block_minutes_before,
+                task_minutes_before = EXCLUDED.task_minutes_before,
+                sse_enabled = EXCLUDED.sse_enabled,
+                webhook_url = EXCLUDED.webhook_url,
+                email = EXCLUDED.email,
+                quiet_start = EXCLUDED.quiet_start,
+                quiet_end = EXCLUDED.quiet_end",
+            &[
+                &params.owner_id,
+                &params.block_minutes_before,
+                &params.task_minutes_before,
+                &params.sse_enabled,
+                &params.webhook_url,
+                &params.email,
+                &params.quiet_start,
+                &params.quiet_end,
+            ],
+        )
+        .await
+    {
+        Ok(_) => return HttpResponse::Ok().json(params.0),
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
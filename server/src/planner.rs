@@ -0,0 +1,410 @@
+use crate::{
+    connect::connect_to_db,
+    dependency,
+    duration::{expand_occurrences, Occurrence},
+    feed::block_time,
+    task::{task_from_row, Task},
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration as Days, Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{GenericClient, Row};
+
+// Tasks without an estimate are planned as if they take an hour.
+pub const DEFAULT_ESTIMATE_MINUTES: i32 = 60;
+const DEFAULT_HORIZON_DAYS: i64 = 14;
+
+#[derive(Serialize, Deserialize)]
+pub struct PlanQuery {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlanRequest {
+    owner_id: i32,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    // Leaves out tasks still waiting on another task.
+    #[serde(default)]
+    skip_blocked: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlanItem {
+    pub task_id: i32,
+    pub task_title: String,
+    pub duration_id: i32,
+    pub category_id: i32,
+    pub date: NaiveDate,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    pub minutes: i64,
+}
+
+// A task that didn't fit into the blocks before the end of the plan.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnplannedTask {
+    pub task_id: i32,
+    pub task_title: String,
+    pub category_id: i32,
+    pub remaining_minutes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub items: Vec<PlanItem>,
+    pub unplanned: Vec<UnplannedTask>,
+}
+
+fn plan_item_from_row(row: &Row) -> PlanItem {
+    let starts_at: NaiveDateTime = row.get(5);
+    let ends_at: NaiveDateTime = row.get(6);
+    return PlanItem {
+        task_id: row.get(0),
+        task_title: row.get(1),
+        duration_id: row.get(2),
+        category_id: row.get(3),
+        date: row.get(4),
+        starts_at,
+        ends_at,
+        minutes: (ends_at - starts_at).num_minutes(),
+    };
+}
+
+pub fn estimate(task: &Task) -> i64 {
+    return task
+        .estimate_minutes
+        .filter(|minutes| *minutes > 0)
+        .unwrap_or(DEFAULT_ESTIMATE_MINUTES) as i64;
+}
+
+// Walks each category's open tasks by urgency (priority, pulled forward as the due date
+// nears) and pours them into that category's block occurrences in time order. A task that
+// doesn't fit in what's left of a block carries on in the next one. Nothing is planned
+// before `now`, or before a task's start_at; the time a deferred task skips over stays free
+// for the tasks after it.
+pub fn fit_tasks(
+    tasks: &[Task],
+    occurrences: &[Occurrence],
+    now: NaiveDateTime,
+) -> (Vec<PlanItem>, Vec<UnplannedTask>) {
+    let mut open: Vec<&Task> = tasks.iter().filter(|task| !task.is_complete).collect();
+    open.sort_by_key(|task| {
+        (
+            task.urgency(now),
+            task.due_at.is_none(),
+            task.due_at,
+            task.id,
+        )
+    });
+
+    let mut blocks: Vec<&Occurrence> = occurrences.iter().collect();
+    blocks.sort_by_key(|occ| (occ.date, occ.start_hour));
+
+    let mut category_ids: Vec<i32> = open.iter().map(|task| task.category_id).collect();
+    category_ids.sort();
+    category_ids.dedup();
+
+    let mut items = Vec::new();
+    let mut unplanned = Vec::new();
+    for category_id in category_ids {
+        // The free stretches of each block, in time order.
+        let mut gaps: Vec<(&Occurrence, NaiveDateTime, NaiveDateTime)> = blocks
+            .iter()
+            .filter(|occ| occ.category_id == category_id)
+            .map(|occ| {
+                let start = block_time(occ.date, occ.start_hour).max(now);
+                return (*occ, start, block_time(occ.date, occ.end_hour));
+            })
+            .filter(|(_, start, end)| start < end)
+            .collect();
+
+        for task in open.iter().filter(|task| task.category_id == category_id) {
+            let mut remaining = estimate(task);
+            let not_before = task.start_at.unwrap_or(now);
+
+            let mut index = 0;
+            while index < gaps.len() && remaining > 0 {
+                let (occ, gap_start, gap_end) = gaps[index];
+                let starts_at = gap_start.max(not_before);
+                let free = (gap_end - starts_at).num_minutes();
+                if free <= 0 {
+                    index += 1;
+                    continue;
+                }
+
+                let minutes = free.min(remaining);
+                let ends_at = starts_at + Days::minutes(minutes);
+                items.push(PlanItem {
+                    task_id: task.id,
+                    task_title: task.title.to_owned(),
+                    duration_id: occ.duration_id,
+                    category_id,
+                    date: occ.date,
+                    starts_at,
+                    ends_at,
+                    minutes,
+                });
+                remaining -= minutes;
+
+                gaps[index] = (occ, ends_at, gap_end);
+                if gap_start < starts_at {
+                    gaps.insert(index, (occ, gap_start, starts_at));
+                    index += 1;
+                }
+                index += 1;
+            }
+
+            if remaining > 0 {
+                unplanned.push(UnplannedTask {
+                    task_id: task.id,
+                    task_title: task.title.to_owned(),
+                    category_id,
+                    remaining_minutes: remaining,
+                });
+            }
+        }
+    }
+
+    items.sort_by_key(|item| item.starts_at);
+    return (items, unplanned);
+}
+
+pub async fn get_open_tasks<C: GenericClient>(client: &C, owner_id: i32) -> Vec<Task> {
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE owner_id=$1 AND NOT is_complete AND deleted_at IS NULL",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    return tasks;
+}
+
+// #[get("/plan")]
+// The stored plan, leaving out tasks completed since it was generated.
+pub async fn get_plan(query: web::Query<PlanQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut items: Vec<PlanItem> = Vec::new();
+    for row in client
+        .query(
+            "SELECT p.task_id, t.title, p.duration_id, p.category_id,
+                p.occurrence_date, p.starts_at, p.ends_at
+            FROM public.plan_item p
+            JOIN public.task t ON t.id = p.task_id
+            WHERE p.owner_id=$1 AND NOT t.is_complete AND t.deleted_at IS NULL
+            ORDER BY p.starts_at",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting plan")
+    {
+        items.push(plan_item_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(items);
+}
+
+// #[post("/plan")]
+// Throws away the owner's stored plan and fits their open tasks into the blocks between
+// `from` (default today) and `to` (default two weeks later) again.
+pub async fn generate_plan(_req: HttpRequest, params: web::Json<PlanRequest>) -> impl Responder {
+    let now = Local::now().naive_local();
+    let from = params.from.unwrap_or(now.date());
+    let to = params.to.unwrap_or(from + Days::days(DEFAULT_HORIZON_DAYS));
+    if to < from {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("to must not be before from");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let mut tasks = get_open_tasks(&transaction, params.owner_id).await;
+    if params.skip_blocked {
+        dependency::mark_blocked(&transaction, &mut tasks).await;
+        tasks.retain(|task| !task.is_blocked);
+    }
+    let occurrences = expand_occurrences(&transaction, Some(params.owner_id), from, to).await;
+    let start = now.max(from.and_hms_opt(0, 0, 0).expect("valid time"));
+    let (items, unplanned) = fit_tasks(&tasks, &occurrences, start);
+
+    transaction
+        .execute(
+            "DELETE FROM public.plan_item WHERE owner_id=$1",
+            &[&params.owner_id],
+        )
+        .await
+        .expect("error clearing plan");
+    for item in &items {
+        transaction
+            .execute(
+                "INSERT INTO public.plan_item (
+                    owner_id,
+                    task_id,
+                    duration_id,
+                    category_id,
+                    occurrence_date,
+                    starts_at,
+                    ends_at
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &params.owner_id,
+                    &item.task_id,
+                    &item.duration_id,
+                    &item.category_id,
+                    &item.date,
+                    &item.starts_at,
+                    &item.ends_at,
+                ],
+            )
+            .await
+            .expect("error saving plan");
+    }
+    transaction.commit().await.expect("error committing plan");
+
+    return HttpResponse::Created()
+        .content_type("application/json")
+        .json(Plan {
+            from,
+            to,
+            items,
+            unplanned,
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(value: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").expect("valid time");
+    }
+
+    fn task(id: i32, category_id: i32, priority: i32, estimate: Option<i32>) -> Task {
+        return serde_json::from_value(json!({
+            "id": id,
+            "title": format!("task {}", id),
+            "description": "",
+            "is_complete": false,
+            "priority": priority,
+            "owner_id": 1,
+            "category_id": category_id,
+            "estimate_minutes": estimate,
+        }))
+        .expect("valid task");
+    }
+
+    fn block(duration_id: i32, category_id: i32, date: &str, hours: (i32, i32)) -> Occurrence {
+        return Occurrence {
+            duration_id,
+            owner_id: 1,
+            category_id,
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").expect("valid date"),
+            start_hour: hours.0,
+            end_hour: hours.1,
+            color: "#3366ff".to_string(),
+            exception_id: None,
+        };
+    }
+
+    fn spans(items: &[PlanItem]) -> Vec<(i32, NaiveDateTime, NaiveDateTime)> {
+        return items
+            .iter()
+            .map(|item| (item.task_id, item.starts_at, item.ends_at))
+            .collect();
+    }
+
+    // Sunday morning, before the week's blocks.
+    fn sunday() -> NaiveDateTime {
+        return at("2026-11-01 08:00");
+    }
+
+    #[test]
+    fn a_task_carries_on_into_the_next_block() {
+        let blocks = [
+            block(2, 1, "2026-11-03", (9, 10)),
+            block(1, 1, "2026-11-02", (9, 11)),
+        ];
+        let (items, unplanned) = fit_tasks(&[task(1, 1, 2, Some(150))], &blocks, sunday());
+
+        assert_eq!(
+            spans(&items),
+            vec![
+                (1, at("2026-11-02 09:00"), at("2026-11-02 11:00")),
+                (1, at("2026-11-03 09:00"), at("2026-11-03 09:30")),
+            ]
+        );
+        assert_eq!(items[0].minutes, 120);
+        assert_eq!(items[1].minutes, 30);
+        assert_eq!(items[1].duration_id, 2);
+        assert!(unplanned.is_empty());
+    }
+
+    #[test]
+    fn urgent_tasks_go_first() {
+        let mut overdue = task(3, 1, 3, Some(60));
+        overdue.due_at = Some(at("2026-10-30 17:00"));
+        let tasks = [task(1, 1, 3, Some(60)), task(2, 1, 2, Some(60)), overdue];
+        let blocks = [block(1, 1, "2026-11-02", (9, 12))];
+
+        let (items, _) = fit_tasks(&tasks, &blocks, sunday());
+        let order: Vec<i32> = items.iter().map(|item| item.task_id).collect();
+        assert_eq!(order, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn tasks_only_go_into_their_own_category_and_the_rest_is_unplanned() {
+        let mut done = task(3, 1, 1, Some(60));
+        done.is_complete = true;
+        let tasks = [task(1, 1, 2, Some(90)), task(2, 2, 2, None), done];
+        let blocks = [block(1, 1, "2026-11-02", (9, 10))];
+
+        let (items, unplanned) = fit_tasks(&tasks, &blocks, sunday());
+        assert_eq!(
+            spans(&items),
+            vec![(1, at("2026-11-02 09:00"), at("2026-11-02 10:00"))]
+        );
+        let left: Vec<(i32, i64)> = unplanned
+            .iter()
+            .map(|task| (task.task_id, task.remaining_minutes))
+            .collect();
+        // Task 2 has no estimate, so it's planned as an hour.
+        assert_eq!(left, vec![(1, 30), (2, DEFAULT_ESTIMATE_MINUTES as i64)]);
+    }
+
+    #[test]
+    fn nothing_is_planned_in_the_past_or_before_a_task_starts() {
+        let blocks = [
+            block(1, 1, "2026-11-02", (9, 12)),
+            block(1, 1, "2026-11-03", (9, 12)),
+        ];
+        let mut deferred = task(2, 1, 1, Some(60));
+        deferred.start_at = Some(at("2026-11-03 10:00"));
+        let tasks = [task(1, 1, 2, Some(60)), deferred];
+
+        let (items, unplanned) = fit_tasks(&tasks, &blocks, at("2026-11-02 11:30"));
+        assert_eq!(
+            spans(&items),
+            vec![
+                (1, at("2026-11-02 11:30"), at("2026-11-02 12:00")),
+                (1, at("2026-11-03 09:00"), at("2026-11-03 09:30")),
+                (2, at("2026-11-03 10:00"), at("2026-11-03 11:00")),
+            ]
+        );
+        assert!(unplanned.is_empty());
+    }
+}
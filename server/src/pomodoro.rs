@@ -0,0 +1,556 @@
+use crate::{
+    connect::connect_to_db,
+    duration::expand_occurrences,
+    time_entry::{self, day_range},
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration as Days, NaiveDate, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio_postgres::{GenericClient, Row};
+
+const DEFAULT_WORK_MINUTES: i32 = 25;
+const DEFAULT_BREAK_MINUTES: i32 = 5;
+
+// A work interval runs into its break on its own. Once the break is over the session waits
+// in `Ready` until someone starts the next interval, so a forgotten session doesn't keep
+// logging time.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Work,
+    Break,
+    Ready,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Work => return "work",
+            Phase::Break => return "break",
+            Phase::Ready => return "ready",
+        }
+    }
+
+    fn from_str(value: &str) -> Phase {
+        match value {
+            "work" => return Phase::Work,
+            "break" => return Phase::Break,
+            _ => return Phase::Ready,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PomodoroSession {
+    pub id: i32,
+    pub owner_id: i32,
+    pub task_id: Option<i32>,
+    pub category_id: i32,
+    pub work_minutes: i32,
+    pub break_minutes: i32,
+    pub phase: Phase,
+    pub phase_started_at: NaiveDateTime,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+// The session as every device should show it right now.
+#[derive(Serialize, Deserialize)]
+pub struct PomodoroState {
+    pub session: PomodoroSession,
+    pub phase_ends_at: Option<NaiveDateTime>,
+    pub remaining_seconds: Option<i64>,
+    pub completed_intervals: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StartRequest {
+    owner_id: i32,
+    #[serde(default)]
+    task_id: Option<i32>,
+    #[serde(default)]
+    work_minutes: Option<i32>,
+    #[serde(default)]
+    break_minutes: Option<i32>,
+    // When picking the task, passes over ones still waiting on another task.
+    #[serde(default)]
+    skip_blocked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerRequest {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerQuery {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryQuery {
+    owner_id: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CompletedInterval {
+    pub session_id: i32,
+    pub task_id: Option<i32>,
+    pub category_id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DayCount {
+    pub date: NaiveDate,
+    pub completed: i64,
+    pub minutes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PomodoroHistory {
+    pub intervals: Vec<CompletedInterval>,
+    pub days: Vec<DayCount>,
+}
+
+pub fn session_from_row(row: &Row) -> PomodoroSession {
+    let phase: String = row.get(6);
+    return PomodoroSession {
+        id: row.get(0),
+        owner_id: row.get(1),
+        task_id: row.get(2),
+        category_id: row.get(3),
+        work_minutes: row.get(4),
+        break_minutes: row.get(5),
+        phase: Phase::from_str(&phase),
+        phase_started_at: row.get(7),
+        started_at: row.get(8),
+        ended_at: row.get(9),
+    };
+}
+
+fn phase_end(session: &PomodoroSession) -> Option<NaiveDateTime> {
+    let minutes = match session.phase {
+        Phase::Work => session.work_minutes,
+        Phase::Break => session.break_minutes,
+        Phase::Ready => return None,
+    };
+    return Some(session.phase_started_at + Days::minutes(minutes as i64));
+}
+
+// Moves the session through every phase that has run out by `now`, returning the work
+// intervals that were completed on the way.
+pub fn advance(
+    session: &mut PomodoroSession,
+    now: NaiveDateTime,
+) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut completed = Vec::new();
+
+    while let Some(end) = phase_end(session).filter(|end| *end <= now) {
+        if session.phase == Phase::Work {
+            completed.push((session.phase_started_at, end));
+            session.phase = Phase::Break;
+        } else {
+            session.phase = Phase::Ready;
+        }
+        session.phase_started_at = end;
+    }
+    return completed;
+}
+
+// Brings the owner's active session up to date, logging each completed work interval as a
+// time entry on the session's task. The row is locked so two devices polling at once can't
+// log the same interval twice.
+async fn sync_session<C: GenericClient>(client: &C, owner_id: i32) -> Option<PomodoroSession> {
+    let mut session = client
+        .query_opt(
+            "SELECT * FROM public.pomodoro_session
+            WHERE owner_id=$1 AND ended_at IS NULL
+            FOR UPDATE",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting pomodoro session")
+        .map(|row| session_from_row(&row))?;
+
+    let completed = advance(&mut session, time_entry::now());
+    if completed.is_empty() {
+        return Some(session);
+    }
+
+    for (started_at, ended_at) in &completed {
+        let time_entry_id: i32 = client
+            .query_one(
+                "INSERT INTO public.time_entry (
+                    owner_id,
+                    task_id,
+                    category_id,
+                    started_at,
+                    ended_at,
+                    note
+                ) VALUES ($1, $2, $3, $4, $5, 'Pomodoro') RETURNING id",
+                &[
+                    &session.owner_id,
+                    &session.task_id,
+                    &session.category_id,
+                    started_at,
+                    ended_at,
+                ],
+            )
+            .await
+            .expect("error logging pomodoro")
+            .get(0);
+        client
+            .execute(
+                "INSERT INTO public.pomodoro_interval (
+                    session_id,
+                    time_entry_id,
+                    started_at,
+                    ended_at
+                ) VALUES ($1, $2, $3, $4)",
+                &[&session.id, &time_entry_id, started_at, ended_at],
+            )
+            .await
+            .expect("error logging pomodoro");
+    }
+    client
+        .execute(
+            "UPDATE public.pomodoro_session SET phase = $1, phase_started_at = $2 WHERE id = $3",
+            &[
+                &session.phase.as_str(),
+                &session.phase_started_at,
+                &session.id,
+            ],
+        )
+        .await
+        .expect("error updating pomodoro session");
+
+    return Some(session);
+}
+
+async fn session_state<C: GenericClient>(client: &C, session: PomodoroSession) -> PomodoroState {
+    let completed_intervals: i64 = client
+        .query_one(
+            "SELECT COUNT(*) FROM public.pomodoro_interval WHERE session_id=$1",
+            &[&session.id],
+        )
+        .await
+        .expect("error counting pomodoros")
+        .get(0);
+
+    let phase_ends_at = match session.ended_at {
+        Some(_) => None,
+        None => phase_end(&session),
+    };
+    let now = time_entry::now();
+    return PomodoroState {
+        phase_ends_at,
+        remaining_seconds: phase_ends_at.map(|end| (end - now).num_seconds().max(0)),
+        completed_intervals,
+        session,
+    };
+}
+
+// The task to focus on when none was picked: whatever the plan has scheduled for now,
+// otherwise the most important open task of the block that's on right now. With
+// `skip_blocked`, tasks waiting on an open task are passed over.
+async fn current_focus<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    now: NaiveDateTime,
+    skip_blocked: bool,
+) -> Option<(Option<i32>, i32)> {
+    if let Some(row) = client
+        .query_opt(
+            "SELECT p.task_id, p.category_id
+            FROM public.plan_item p
+            JOIN public.task t ON t.id = p.task_id
+            WHERE p.owner_id=$1 AND p.starts_at <= $2 AND p.ends_at > $2 AND NOT t.is_complete
+                AND t.deleted_at IS NULL
+                AND NOT ($3 AND EXISTS (
+                    SELECT 1 FROM public.task_dependency d
+                    JOIN public.task blocker ON blocker.id = d.blocked_by
+                    WHERE d.task_id = t.id AND NOT blocker.is_complete
+                        AND blocker.deleted_at IS NULL
+                ))
+            LIMIT 1",
+            &[&owner_id, &now, &skip_blocked],
+        )
+        .await
+        .expect("error getting plan")
+    {
+        return Some((Some(row.get(0)), row.get(1)));
+    }
+
+    let hour = now.hour() as i32;
+    let block = expand_occurrences(client, Some(owner_id), now.date(), now.date())
+        .await
+        .into_iter()
+        .find(|occ| occ.start_hour <= hour && hour < occ.end_hour)?;
+
+    let task_id = client
+        .query_opt(
+            "SELECT t.id FROM public.task t
+            WHERE t.category_id=$1 AND NOT t.is_complete AND t.deleted_at IS NULL
+                AND NOT ($2 AND EXISTS (
+                    SELECT 1 FROM public.task_dependency d
+                    JOIN public.task blocker ON blocker.id = d.blocked_by
+                    WHERE d.task_id = t.id AND NOT blocker.is_complete
+                        AND blocker.deleted_at IS NULL
+                ))
+            ORDER BY t.priority ASC, t.id ASC
+            LIMIT 1",
+            &[&block.category_id, &skip_blocked],
+        )
+        .await
+        .expect("error getting tasks")
+        .map(|row| row.get(0));
+    return Some((task_id, block.category_id));
+}
+
+// #[get("/pomodoro")]
+pub async fn get_pomodoro(query: web::Query<OwnerQuery>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let state = match sync_session(&transaction, query.owner_id).await {
+        Some(session) => Some(session_state(&transaction, session).await),
+        None => None,
+    };
+    transaction
+        .commit()
+        .await
+        .expect("error updating pomodoro session");
+
+    return HttpResponse::Ok().json(state);
+}
+
+// #[post("/pomodoro/start")]
+// Starts a session on the given task, or on the task the current block points to.
+pub async fn start_pomodoro(_req: HttpRequest, params: web::Json<StartRequest>) -> impl Responder {
+    let work_minutes = params.work_minutes.unwrap_or(DEFAULT_WORK_MINUTES);
+    let break_minutes = params.break_minutes.unwrap_or(DEFAULT_BREAK_MINUTES);
+    if !(1..=240).contains(&work_minutes) || !(0..=240).contains(&break_minutes) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("work_minutes must be within 1-240 and break_minutes within 0-240");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    if let Some(session) = sync_session(&transaction, params.owner_id).await {
+        let state = session_state(&transaction, session).await;
+        transaction
+            .commit()
+            .await
+            .expect("error updating pomodoro session");
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(state);
+    }
+
+    let now = time_entry::now();
+    let (task_id, category_id) = match params.task_id {
+        Some(task_id) => {
+            match transaction
+                .query_opt(
+                    "SELECT category_id FROM public.task WHERE id=$1",
+                    &[&task_id],
+                )
+                .await
+                .expect("error getting task")
+            {
+                Some(row) => (Some(task_id), row.get(0)),
+                None => return HttpResponse::NotFound().json("task not found"),
+            }
+        }
+        None => match current_focus(&transaction, params.owner_id, now, params.skip_blocked)
+            .await
+        {
+            Some(focus) => focus,
+            None => {
+                return HttpResponse::NotFound()
+                    .json("no task given and no block is scheduled right now")
+            }
+        },
+    };
+
+    let row = transaction
+        .query_one(
+            "INSERT INTO public.pomodoro_session (
+                owner_id,
+                task_id,
+                category_id,
+                work_minutes,
+                break_minutes,
+                phase,
+                phase_started_at,
+                started_at
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $7) RETURNING *",
+            &[
+                &params.owner_id,
+                &task_id,
+                &category_id,
+                &work_minutes,
+                &break_minutes,
+                &Phase::Work.as_str(),
+                &now,
+            ],
+        )
+        .await
+        .expect("error starting pomodoro");
+    let state = session_state(&transaction, session_from_row(&row)).await;
+    transaction.commit().await.expect("error starting pomodoro");
+
+    return HttpResponse::Created()
+        .content_type("application/json")
+        .json(state);
+}
+
+// #[post("/pomodoro/next")]
+// Starts the next work interval, cutting a break short if one is running.
+pub async fn next_pomodoro(_req: HttpRequest, params: web::Json<OwnerRequest>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let mut session = match sync_session(&transaction, params.owner_id).await {
+        Some(session) => session,
+        None => return HttpResponse::NotFound().json("no pomodoro session is running"),
+    };
+    if session.phase == Phase::Work {
+        let state = session_state(&transaction, session).await;
+        transaction
+            .commit()
+            .await
+            .expect("error updating pomodoro session");
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(state);
+    }
+
+    session.phase = Phase::Work;
+    session.phase_started_at = time_entry::now();
+    transaction
+        .execute(
+            "UPDATE public.pomodoro_session SET phase = $1, phase_started_at = $2 WHERE id = $3",
+            &[
+                &session.phase.as_str(),
+                &session.phase_started_at,
+                &session.id,
+            ],
+        )
+        .await
+        .expect("error updating pomodoro session");
+    let state = session_state(&transaction, session).await;
+    transaction
+        .commit()
+        .await
+        .expect("error updating pomodoro session");
+
+    return HttpResponse::Ok().json(state);
+}
+
+// #[post("/pomodoro/stop")]
+// Ends the session. A work interval that hadn't finished isn't logged.
+pub async fn stop_pomodoro(_req: HttpRequest, params: web::Json<OwnerRequest>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let mut session = match sync_session(&transaction, params.owner_id).await {
+        Some(session) => session,
+        None => return HttpResponse::NotFound().json("no pomodoro session is running"),
+    };
+    let now = time_entry::now();
+    transaction
+        .execute(
+            "UPDATE public.pomodoro_session SET ended_at = $1 WHERE id = $2",
+            &[&now, &session.id],
+        )
+        .await
+        .expect("error stopping pomodoro");
+    session.ended_at = Some(now);
+    let state = session_state(&transaction, session).await;
+    transaction.commit().await.expect("error stopping pomodoro");
+
+    return HttpResponse::Ok().json(state);
+}
+
+// #[get("/pomodoro/history")]
+pub async fn get_pomodoro_history(query: web::Query<HistoryQuery>) -> impl Responder {
+    if query.to < query.from {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("to must not be before from");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+    // Intervals that finished since the last poll belong in the history too.
+    sync_session(&transaction, query.owner_id).await;
+
+    let (from, to) = day_range(query.from, query.to);
+    let mut intervals = Vec::new();
+    for row in transaction
+        .query(
+            "SELECT i.session_id, s.task_id, s.category_id, i.started_at, i.ended_at
+            FROM public.pomodoro_interval i
+            JOIN public.pomodoro_session s ON s.id = i.session_id
+            WHERE s.owner_id=$1 AND i.started_at >= $2 AND i.started_at < $3
+            ORDER BY i.started_at",
+            &[&query.owner_id, &from, &to],
+        )
+        .await
+        .expect("error getting pomodoros")
+    {
+        intervals.push(CompletedInterval {
+            session_id: row.get(0),
+            task_id: row.get(1),
+            category_id: row.get(2),
+            started_at: row.get(3),
+            ended_at: row.get(4),
+        });
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error updating pomodoro session");
+
+    let mut days: BTreeMap<NaiveDate, (i64, i64)> = BTreeMap::new();
+    for interval in &intervals {
+        let day = days.entry(interval.started_at.date()).or_default();
+        day.0 += 1;
+        day.1 += (interval.ended_at - interval.started_at).num_minutes();
+    }
+
+    return HttpResponse::Ok().json(PomodoroHistory {
+        intervals,
+        days: days
+            .into_iter()
+            .map(|(date, (completed, minutes))| DayCount {
+                date,
+                completed,
+                minutes,
+            })
+            .collect(),
+    });
+}
@@ -0,0 +1,389 @@
+use crate::{
+    connect::{connect_to_db, try_connect_to_db},
+    history, status, subtask,
+    task::{task_from_row, InfoPathId, Task},
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient};
+
+// Ranks are handed out this far apart, so there's room for a long run of moves between
+// the same two tasks before they need spreading out again. New tasks go RANK_STEP past the
+// last one in their category.
+pub const RANK_STEP: f64 = 1024.0;
+// Neighbours closer than this get the category renumbered.
+const MIN_RANK_GAP: f64 = 1e-6;
+const REBALANCE_INTERVAL_SECONDS: u64 = 60 * 60;
+
+// Where to drop the task: right after `after`, right before `before`, or between the two.
+// With neither it goes to the end of the category. `category_id` moves it to another
+// category, and is implied by a neighbour from another category.
+#[derive(Serialize, Deserialize)]
+pub struct MoveRequest {
+    #[serde(default)]
+    before: Option<i32>,
+    #[serde(default)]
+    after: Option<i32>,
+    #[serde(default)]
+    category_id: Option<i32>,
+}
+
+// Renumbers the category's tasks RANK_STEP apart in their current order. Tasks that
+// haven't been placed yet go at the end, oldest first.
+pub async fn rebalance<C: GenericClient>(client: &C, category_id: i32) -> Result<u64, Error> {
+    return client
+        .execute(
+            "UPDATE public.task SET rank = ordered.position * $2
+            FROM (
+                SELECT id, ROW_NUMBER() OVER (ORDER BY rank NULLS LAST, id) AS position
+                FROM public.task WHERE category_id = $1
+            ) AS ordered
+            WHERE task.id = ordered.id",
+            &[&category_id, &RANK_STEP],
+        )
+        .await;
+}
+
+// Categories with unplaced tasks or ranks packed too tightly to split again.
+async fn crowded_categories<C: GenericClient>(client: &C) -> Result<Vec<i32>, Error> {
+    let mut ids = Vec::new();
+    for row in client
+        .query(
+            "SELECT DISTINCT category_id FROM (
+                SELECT category_id, rank,
+                    rank - LAG(rank) OVER (PARTITION BY category_id ORDER BY rank) AS gap
+                FROM public.task
+            ) AS gaps
+            WHERE rank IS NULL OR gap < $1",
+            &[&MIN_RANK_GAP],
+        )
+        .await?
+    {
+        ids.push(row.get(0));
+    }
+    return Ok(ids);
+}
+
+// One pass of the background rebalancing.
+pub async fn rebalance_crowded() -> Result<(), Error> {
+    let client = try_connect_to_db().await?;
+    for category_id in crowded_categories(&client).await? {
+        rebalance(&client, category_id).await?;
+    }
+    return Ok(());
+}
+
+// Started once next to the HTTP server, like the reminder scheduler. A failed pass is
+// logged and tried again on the next tick.
+pub async fn run_rebalancer() {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(REBALANCE_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(err) = rebalance_crowded().await {
+            eprintln!("error rebalancing ranks: {}", err);
+        }
+    }
+}
+
+async fn get_task<C: GenericClient>(client: &C, id: i32) -> Result<Option<Task>, Error> {
+    return Ok(client
+        .query_opt("SELECT * FROM public.task WHERE id=$1", &[&id])
+        .await?
+        .map(|row| task_from_row(&row)));
+}
+
+// The rank of the closest task on one side of `rank` in the category, leaving out the
+// task being moved.
+async fn neighbour_rank<C: GenericClient>(
+    client: &C,
+    category_id: i32,
+    moving: i32,
+    rank: Option<f64>,
+    below: bool,
+) -> Result<Option<f64>, Error> {
+    let sql = if below {
+        "SELECT MAX(rank) FROM public.task
+        WHERE category_id = $1 AND id <> $2 AND deleted_at IS NULL
+            AND ($3::FLOAT8 IS NULL OR rank < $3)"
+    } else {
+        "SELECT MIN(rank) FROM public.task
+        WHERE category_id = $1 AND id <> $2 AND deleted_at IS NULL AND rank > $3"
+    };
+    let row = client
+        .query_one(sql, &[&category_id, &moving, &rank])
+        .await?;
+    return Ok(row.get(0));
+}
+
+// The bounds the new rank has to fall between, from the neighbours' current ranks.
+async fn bounds<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+    category_id: i32,
+    after: Option<&Task>,
+    before: Option<&Task>,
+) -> Result<(Option<f64>, Option<f64>), Error> {
+    match (after, before) {
+        (Some(after), Some(before)) => return Ok((after.rank, before.rank)),
+        (Some(after), None) => {
+            let above = neighbour_rank(client, category_id, task_id, after.rank, false).await?;
+            return Ok((after.rank, above));
+        }
+        (None, Some(before)) => {
+            let below = neighbour_rank(client, category_id, task_id, before.rank, true).await?;
+            return Ok((below, before.rank));
+        }
+        (None, None) => {
+            let last = neighbour_rank(client, category_id, task_id, None, true).await?;
+            return Ok((last, None));
+        }
+    }
+}
+
+// What to do with the bounds a moved task has to land between.
+#[derive(PartialEq, Debug)]
+enum Placement {
+    At(f64),
+    // The neighbours are too close to split; renumber the category and look again.
+    Rebalance,
+    Misordered,
+}
+
+// A category is only renumbered once per move; after that even a tight gap gets split.
+fn place_between(low: Option<f64>, high: Option<f64>, rebalanced: bool) -> Placement {
+    match (low, high) {
+        (Some(low), Some(high)) if low >= high => return Placement::Misordered,
+        (Some(low), Some(high)) if high - low < MIN_RANK_GAP && !rebalanced => {
+            return Placement::Rebalance;
+        }
+        _ => return Placement::At(rank_between(low, high)),
+    }
+}
+
+pub fn rank_between(low: Option<f64>, high: Option<f64>) -> f64 {
+    match (low, high) {
+        (Some(low), Some(high)) => return (low + high) / 2.0,
+        (Some(low), None) => return low + RANK_STEP,
+        (None, Some(high)) => return high - RANK_STEP,
+        (None, None) => return RANK_STEP,
+    }
+}
+
+// #[post("/task/{id}/move")]
+// Drops the task between its new neighbours by giving it a rank between theirs, so only
+// this task changes. Moving to another category takes its subtasks along.
+pub async fn move_task(
+    req: HttpRequest,
+    info: Path<InfoPathId>,
+    params: web::Json<MoveRequest>,
+) -> impl Responder {
+    let bad_request = |msg: &str| {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    };
+    if params.before == Some(info.id) || params.after == Some(info.id) {
+        return bad_request("a task can't move next to itself");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(mut task) = get_task(&transaction, info.id)
+        .await
+        .expect("error getting task")
+    else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json("no such task");
+    };
+
+    let mut neighbours: Vec<Task> = Vec::new();
+    for id in [params.after, params.before].into_iter().flatten() {
+        match get_task(&transaction, id)
+            .await
+            .expect("error getting task")
+        {
+            Some(neighbour) => neighbours.push(neighbour),
+            None => return bad_request(&format!("task {} doesn't exist", id)),
+        }
+    }
+    let category_id = params
+        .category_id
+        .or(neighbours.first().map(|neighbour| neighbour.category_id))
+        .unwrap_or(task.category_id);
+    if neighbours
+        .iter()
+        .any(|neighbour| neighbour.category_id != category_id)
+    {
+        return bad_request("the neighbours have to be in the category the task moves to");
+    }
+
+    // Unplaced neighbours, or ones packed too tightly, get the category renumbered first.
+    let mut rebalanced = neighbours.iter().any(|neighbour| neighbour.rank.is_none());
+    if rebalanced {
+        rebalance(&transaction, category_id)
+            .await
+            .expect("error rebalancing ranks");
+    }
+    let rank = loop {
+        let after = match params.after {
+            Some(id) => get_task(&transaction, id)
+                .await
+                .expect("error getting task"),
+            None => None,
+        };
+        let before = match params.before {
+            Some(id) => get_task(&transaction, id)
+                .await
+                .expect("error getting task"),
+            None => None,
+        };
+        let (low, high) = bounds(
+            &transaction,
+            task.id,
+            category_id,
+            after.as_ref(),
+            before.as_ref(),
+        )
+        .await
+        .expect("error getting ranks");
+
+        match place_between(low, high, rebalanced) {
+            Placement::At(rank) => break rank,
+            Placement::Rebalance => {
+                rebalance(&transaction, category_id)
+                    .await
+                    .expect("error rebalancing ranks");
+                rebalanced = true;
+            }
+            Placement::Misordered => {
+                return bad_request("`after` has to come before `before`");
+            }
+        }
+    };
+
+    let before = history::snapshot(&transaction, task.id)
+        .await
+        .expect("error getting task");
+    let moved_category = category_id != task.category_id;
+    // A subtask moved out of its parent's category leaves the parent behind.
+    if moved_category {
+        task.parent_id = None;
+    }
+    transaction
+        .execute(
+            "UPDATE public.task SET rank = $1, category_id = $2, parent_id = $3 WHERE id = $4",
+            &[&rank, &category_id, &task.parent_id, &task.id],
+        )
+        .await
+        .expect("error moving task");
+
+    if moved_category {
+        status::reconcile(&transaction, task.id, None)
+            .await
+            .expect("error setting status");
+        subtask::move_subtree(&transaction, task.id, category_id)
+            .await
+            .expect("error moving subtasks");
+        for id in subtask::descendants(&transaction, task.id)
+            .await
+            .expect("error getting subtasks")
+        {
+            status::reconcile(&transaction, id, None)
+                .await
+                .expect("error setting status");
+        }
+    }
+
+    let after = history::snapshot(&transaction, task.id)
+        .await
+        .expect("error getting task");
+    if let (Some(before), Some(after)) = (&before, &after) {
+        let actor_id = history::actor(&req, after.owner_id);
+        history::record(&transaction, before, after, Some(actor_id))
+            .await
+            .expect("error recording history");
+    }
+    let task = get_task(&transaction, task.id)
+        .await
+        .expect("error getting task");
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(task);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_between_splits_the_gap_or_steps_past_the_end() {
+        assert_eq!(rank_between(Some(1024.0), Some(2048.0)), 1536.0);
+        assert_eq!(rank_between(Some(2048.0), None), 2048.0 + RANK_STEP);
+        assert_eq!(rank_between(None, Some(1024.0)), 1024.0 - RANK_STEP);
+        assert_eq!(rank_between(None, None), RANK_STEP);
+    }
+
+    #[test]
+    fn neighbours_in_the_wrong_order_are_refused() {
+        assert_eq!(
+            place_between(Some(2048.0), Some(1024.0), false),
+            Placement::Misordered
+        );
+        assert_eq!(
+            place_between(Some(1024.0), Some(1024.0), true),
+            Placement::Misordered
+        );
+    }
+
+    #[test]
+    fn moves_between_the_same_two_tasks_end_in_a_rebalance() {
+        let low = RANK_STEP;
+        let mut high = 2.0 * RANK_STEP;
+        let mut moves = 0;
+        loop {
+            match place_between(Some(low), Some(high), false) {
+                Placement::At(rank) => {
+                    assert!(low < rank && rank < high);
+                    high = rank;
+                    moves += 1;
+                }
+                Placement::Rebalance => break,
+                Placement::Misordered => panic!("the gap closed before a rebalance"),
+            }
+        }
+        // RANK_STEP leaves room for a long run of moves first.
+        assert!(moves > 20, "only {} moves before a rebalance", moves);
+        assert!(high - low < MIN_RANK_GAP);
+
+        // Once the category has been renumbered the move goes ahead regardless.
+        let Placement::At(rank) = place_between(Some(low), Some(high), true) else {
+            panic!("a rebalanced category should place the task");
+        };
+        assert!(low < rank && rank < high);
+    }
+
+    #[test]
+    fn an_open_end_never_needs_a_rebalance() {
+        assert_eq!(
+            place_between(Some(RANK_STEP), None, false),
+            Placement::At(2.0 * RANK_STEP)
+        );
+        assert_eq!(
+            place_between(None, Some(RANK_STEP), false),
+            Placement::At(0.0)
+        );
+        assert_eq!(place_between(None, None, false), Placement::At(RANK_STEP));
+    }
+}
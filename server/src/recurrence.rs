@@ -0,0 +1,866 @@
+use chrono::{Datelike, Duration as Days, NaiveDate, NaiveTime, Weekday};
+
+// A subset of RFC 5545 RRULEs, enough for "every other week", "first Monday of the month",
+// "last weekday of the month", "weekdays until a date" and count limited series. Times of
+// day live on the duration itself, so everything here works on whole dates.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Recurrence {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<NaiveDate>,
+    // (ordinal, weekday), e.g. (Some(1), Mon) for 1MO and (None, Mon) for MO.
+    pub by_day: Vec<(Option<i32>, Weekday)>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    // Which of each period's dates to keep, e.g. -1 for the last one.
+    pub by_set_pos: Vec<i32>,
+    pub week_start: Weekday,
+}
+
+// Weekday index used by `recurring_days`, 0 = Sunday.
+pub fn weekday_from_index(day: usize) -> Weekday {
+    return match day {
+        0 => Weekday::Sun,
+        1 => Weekday::Mon,
+        2 => Weekday::Tue,
+        3 => Weekday::Wed,
+        4 => Weekday::Thu,
+        5 => Weekday::Fri,
+        _ => Weekday::Sat,
+    };
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    return match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    };
+}
+
+fn parse_weekday(code: &str) -> Result<Weekday, String> {
+    return match code {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _ => Err(format!("unknown weekday '{}'", code)),
+    };
+}
+
+// Accepts both DATE (20261231) and DATE-TIME (20261231T235959Z) values, keeping only the date.
+pub fn parse_ical_date(value: &str) -> Result<NaiveDate, String> {
+    let date = value.get(0..8).ok_or(format!("invalid date '{}'", value))?;
+    return NaiveDate::parse_from_str(date, "%Y%m%d").map_err(|_| format!("invalid date '{}'", value));
+}
+
+fn parse_list<T, F>(value: &str, parse: F) -> Result<Vec<T>, String>
+where
+    F: Fn(&str) -> Result<T, String>,
+{
+    return value.split(',').map(|part| parse(part.trim())).collect();
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    };
+    return next.expect("valid month").pred_opt().expect("valid date").day();
+}
+
+// The nth (or nth from last when negative) `weekday` among `days`.
+fn nth_weekday(days: &[NaiveDate], weekday: Weekday, n: i32) -> Option<NaiveDate> {
+    let matching: Vec<NaiveDate> = days
+        .iter()
+        .filter(|date| date.weekday() == weekday)
+        .cloned()
+        .collect();
+
+    if n > 0 {
+        return matching.get(n as usize - 1).cloned();
+    }
+    let from_end = (-n) as usize;
+    if from_end > matching.len() {
+        return None;
+    }
+    return matching.get(matching.len() - from_end).cloned();
+}
+
+impl Recurrence {
+    pub fn parse(rule: &str) -> Result<Recurrence, String> {
+        let rule = rule.trim();
+        let rule = rule.strip_prefix("RRULE:").unwrap_or(rule);
+
+        let mut freq = None;
+        let mut recurrence = Recurrence {
+            freq: Frequency::Weekly,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or(format!("malformed rule part '{}'", part))?;
+
+            match key.to_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => return Err(format!("unsupported FREQ '{}'", other)),
+                    })
+                }
+                "INTERVAL" => {
+                    recurrence.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or(format!("invalid INTERVAL '{}'", value))?;
+                }
+                "COUNT" => {
+                    recurrence.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or(format!("invalid COUNT '{}'", value))?,
+                    );
+                }
+                "UNTIL" => recurrence.until = Some(parse_ical_date(value)?),
+                "BYDAY" => {
+                    recurrence.by_day = parse_list(value, |day| {
+                        let day = day.to_uppercase();
+                        if !day.is_ascii() {
+                            return Err(format!("invalid BYDAY '{}'", day));
+                        }
+                        let split = day.len().saturating_sub(2);
+                        let weekday = parse_weekday(&day[split..])?;
+                        let ordinal = &day[..split];
+                        if ordinal.is_empty() {
+                            return Ok((None, weekday));
+                        }
+                        let ordinal: i32 = ordinal
+                            .parse()
+                            .ok()
+                            .filter(|n: &i32| *n != 0 && n.abs() <= 53)
+                            .ok_or(format!("invalid BYDAY '{}'", day))?;
+                        return Ok((Some(ordinal), weekday));
+                    })?;
+                }
+                "BYMONTHDAY" => {
+                    recurrence.by_month_day = parse_list(value, |day| {
+                        return day
+                            .parse()
+                            .ok()
+                            .filter(|n: &i32| *n != 0 && n.abs() <= 31)
+                            .ok_or(format!("invalid BYMONTHDAY '{}'", day));
+                    })?;
+                }
+                "BYMONTH" => {
+                    recurrence.by_month = parse_list(value, |month| {
+                        return month
+                            .parse()
+                            .ok()
+                            .filter(|n| (1..=12).contains(n))
+                            .ok_or(format!("invalid BYMONTH '{}'", month));
+                    })?;
+                }
+                "BYSETPOS" => {
+                    recurrence.by_set_pos = parse_list(value, |pos| {
+                        return pos
+                            .parse()
+                            .ok()
+                            .filter(|n: &i32| *n != 0 && n.abs() <= 366)
+                            .ok_or(format!("invalid BYSETPOS '{}'", pos));
+                    })?;
+                }
+                "WKST" => recurrence.week_start = parse_weekday(&value.to_uppercase())?,
+                other => return Err(format!("unsupported rule part '{}'", other)),
+            }
+        }
+
+        recurrence.freq = freq.ok_or("rule is missing FREQ".to_string())?;
+
+        if recurrence.count.is_some() && recurrence.until.is_some() {
+            return Err("COUNT and UNTIL can't be used together".to_string());
+        }
+        if recurrence.by_day.iter().any(|(n, _)| n.is_some())
+            && !matches!(recurrence.freq, Frequency::Monthly | Frequency::Yearly)
+        {
+            return Err("numbered BYDAY is only allowed with MONTHLY or YEARLY".to_string());
+        }
+        if !recurrence.by_set_pos.is_empty()
+            && recurrence.by_day.is_empty()
+            && recurrence.by_month_day.is_empty()
+            && recurrence.by_month.is_empty()
+        {
+            return Err("BYSETPOS needs BYDAY, BYMONTHDAY or BYMONTH".to_string());
+        }
+
+        return Ok(recurrence);
+    }
+
+    // The `recurring_days` shorthand: every week on the days switched on in the array.
+    pub fn from_weekdays(recurring_days: &[i32]) -> Recurrence {
+        let by_day = recurring_days
+            .iter()
+            .enumerate()
+            .filter(|(_, on)| **on == 1)
+            .map(|(day, _)| (None, weekday_from_index(day)))
+            .collect();
+
+        return Recurrence {
+            freq: Frequency::Weekly,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day,
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+    }
+
+    // Every week on the same days forever, i.e. what `recurring_days` alone can express.
+    pub fn is_plain_weekly(&self) -> bool {
+        return self.freq == Frequency::Weekly
+            && self.interval == 1
+            && self.count.is_none()
+            && self.until.is_none()
+            && self.by_month_day.is_empty()
+            && self.by_month.is_empty()
+            && self.by_set_pos.is_empty();
+    }
+
+    // The rule for a series whose DTSTART is a DATE-TIME, which UNTIL has to match. `time`
+    // is when the series starts each day, so the occurrence on the UNTIL date still counts.
+    pub fn to_string_until_at(&self, time: NaiveTime) -> String {
+        let Some(until) = self.until else {
+            return self.to_string();
+        };
+        let rule = Recurrence {
+            until: None,
+            ..self.clone()
+        };
+        return format!("{};UNTIL={}", rule, until.and_time(time).format("%Y%m%dT%H%M%S"));
+    }
+
+    // Spells out the days the rule otherwise takes from `dtstart`, so moving the series to a
+    // new DTSTART keeps it on the same weekday or day of the month.
+    pub fn pin_to(&mut self, dtstart: NaiveDate) {
+        match self.freq {
+            Frequency::Daily => {}
+            Frequency::Weekly => {
+                if self.by_day.is_empty() {
+                    self.by_day = vec![(None, dtstart.weekday())];
+                }
+            }
+            Frequency::Monthly => {
+                if self.by_day.is_empty() && self.by_month_day.is_empty() {
+                    self.by_month_day = vec![dtstart.day() as i32];
+                }
+            }
+            Frequency::Yearly => {
+                let whole_year = self.by_month.is_empty()
+                    && self.by_month_day.is_empty()
+                    && self.by_day.iter().any(|(n, _)| n.is_some());
+                if whole_year {
+                    return;
+                }
+                if self.by_month.is_empty() {
+                    self.by_month = vec![dtstart.month()];
+                }
+                if self.by_day.is_empty() && self.by_month_day.is_empty() {
+                    self.by_month_day = vec![dtstart.day() as i32];
+                }
+            }
+        }
+    }
+
+    // Which weekdays the rule can land on, in `recurring_days` form. A series that ends is
+    // read off its actual dates, so a one-off event only gets its own day. Otherwise, without
+    // BYDAY only a weekly rule is tied to dtstart's weekday; the others move through the week.
+    pub fn weekday_mask(&self, dtstart: NaiveDate) -> Vec<i32> {
+        let mut mask = vec![0; 7];
+
+        if self.count.is_some() || self.until.is_some() {
+            let last_day = self.until.unwrap_or(
+                dtstart
+                    .checked_add_signed(Days::days(
+                        366 * self.count.unwrap_or(1) as i64 * self.interval as i64,
+                    ))
+                    .unwrap_or(NaiveDate::MAX),
+            );
+            let dates = self.occurrences(dtstart, dtstart, last_day);
+            for date in &dates {
+                mask[date.weekday().num_days_from_sunday() as usize] = 1;
+            }
+            if !dates.is_empty() {
+                return mask;
+            }
+        }
+        if self.by_day.is_empty() {
+            if self.freq != Frequency::Weekly {
+                return vec![1; 7];
+            }
+            mask[dtstart.weekday().num_days_from_sunday() as usize] = 1;
+            return mask;
+        }
+        for (_, weekday) in &self.by_day {
+            mask[weekday.num_days_from_sunday() as usize] = 1;
+        }
+        return mask;
+    }
+
+    // Candidate dates for the period (day, week, month or year) containing `start`, sorted.
+    fn period_dates(&self, start: NaiveDate, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        let mut dates = match self.freq {
+            Frequency::Daily => vec![start],
+            Frequency::Weekly => {
+                let days: Vec<NaiveDate> = (0..7).map(|i| start + Days::days(i)).collect();
+                if self.by_day.is_empty() {
+                    days.into_iter()
+                        .filter(|date| date.weekday() == dtstart.weekday())
+                        .collect()
+                } else {
+                    days
+                }
+            }
+            Frequency::Monthly => self.month_dates(start.year(), start.month(), dtstart),
+            Frequency::Yearly => {
+                if self.by_month.is_empty()
+                    && self.by_month_day.is_empty()
+                    && self.by_day.iter().any(|(n, _)| n.is_some())
+                {
+                    // e.g. FREQ=YEARLY;BYDAY=20MO, the 20th Monday of the year.
+                    let year = start.year();
+                    let days: Vec<NaiveDate> = NaiveDate::from_ymd_opt(year, 1, 1)
+                        .expect("valid year")
+                        .iter_days()
+                        .take_while(|date| date.year() == year)
+                        .collect();
+                    self.by_day
+                        .iter()
+                        .filter_map(|(n, weekday)| nth_weekday(&days, *weekday, n.unwrap_or(1)))
+                        .collect()
+                } else {
+                    let months = if self.by_month.is_empty() {
+                        vec![dtstart.month()]
+                    } else {
+                        self.by_month.to_owned()
+                    };
+                    months
+                        .iter()
+                        .flat_map(|month| self.month_dates(start.year(), *month, dtstart))
+                        .collect()
+                }
+            }
+        };
+
+        if !self.by_day.is_empty() && self.freq != Frequency::Monthly {
+            dates.retain(|date| self.by_day.iter().any(|(_, day)| *day == date.weekday()));
+        }
+        if !self.by_month_day.is_empty() && self.freq != Frequency::Monthly {
+            dates.retain(|date| {
+                let last = days_in_month(date.year(), date.month()) as i32;
+                self.by_month_day.iter().any(|day| {
+                    let day = if *day < 0 { last + day + 1 } else { *day };
+                    day == date.day() as i32
+                })
+            });
+        }
+        if !self.by_month.is_empty() {
+            dates.retain(|date| self.by_month.contains(&date.month()));
+        }
+
+        dates.sort();
+        dates.dedup();
+        if !self.by_set_pos.is_empty() {
+            let set = dates;
+            dates = self
+                .by_set_pos
+                .iter()
+                .filter_map(|pos| match *pos > 0 {
+                    true => set.get(*pos as usize - 1),
+                    false => set.len().checked_sub(pos.unsigned_abs() as usize).map(|i| &set[i]),
+                })
+                .cloned()
+                .collect();
+            dates.sort();
+            dates.dedup();
+        }
+        return dates;
+    }
+
+    fn month_dates(&self, year: i32, month: u32, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        let last = days_in_month(year, month);
+        let days: Vec<NaiveDate> = (1..=last)
+            .filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+            .collect();
+
+        let mut dates: Vec<NaiveDate> = if !self.by_month_day.is_empty() {
+            self.by_month_day
+                .iter()
+                .filter_map(|day| {
+                    let day = if *day < 0 { last as i32 + day + 1 } else { *day };
+                    if day < 1 {
+                        return None;
+                    }
+                    NaiveDate::from_ymd_opt(year, month, day as u32)
+                })
+                .collect()
+        } else if !self.by_day.is_empty() {
+            self.by_day
+                .iter()
+                .flat_map(|(n, weekday)| match n {
+                    Some(n) => nth_weekday(&days, *weekday, *n).into_iter().collect(),
+                    None => days
+                        .iter()
+                        .filter(|date| date.weekday() == *weekday)
+                        .cloned()
+                        .collect::<Vec<NaiveDate>>(),
+                })
+                .collect()
+        } else {
+            NaiveDate::from_ymd_opt(year, month, dtstart.day())
+                .into_iter()
+                .collect()
+        };
+
+        // With both BYMONTHDAY and BYDAY the weekday only narrows the month days down.
+        if !self.by_month_day.is_empty() && !self.by_day.is_empty() {
+            dates.retain(|date| self.by_day.iter().any(|(_, day)| *day == date.weekday()));
+        }
+        return dates;
+    }
+
+    fn period_start(&self, dtstart: NaiveDate, index: u32) -> Option<NaiveDate> {
+        let step = index.checked_mul(self.interval)?;
+        return match self.freq {
+            Frequency::Daily => dtstart.checked_add_signed(Days::days(step as i64)),
+            Frequency::Weekly => {
+                let offset = (7 + dtstart.weekday().num_days_from_monday()
+                    - self.week_start.num_days_from_monday())
+                    % 7;
+                let week = dtstart - Days::days(offset as i64);
+                week.checked_add_signed(Days::weeks(step as i64))
+            }
+            Frequency::Monthly => {
+                let months = dtstart.month0() + step;
+                let year = dtstart.year() + (months / 12) as i32;
+                NaiveDate::from_ymd_opt(year, months % 12 + 1, 1)
+            }
+            Frequency::Yearly => NaiveDate::from_ymd_opt(dtstart.year() + step as i32, 1, 1),
+        };
+    }
+
+    // Every date of the series anchored at `dtstart` that falls within `from..=to`.
+    pub fn occurrences(&self, dtstart: NaiveDate, from: NaiveDate, to: NaiveDate) -> Vec<NaiveDate> {
+        let mut dates = Vec::new();
+        let mut emitted = 0;
+        let end = match self.until {
+            Some(until) => until.min(to),
+            None => to,
+        };
+
+        let mut index = 0;
+        while let Some(start) = self.period_start(dtstart, index) {
+            if start > end {
+                break;
+            }
+            for date in self.period_dates(start, dtstart) {
+                if date < dtstart {
+                    continue;
+                }
+                if date > end {
+                    return dates;
+                }
+                if let Some(count) = self.count {
+                    if emitted >= count {
+                        return dates;
+                    }
+                }
+                emitted += 1;
+                if date >= from {
+                    dates.push(date);
+                }
+            }
+            index += 1;
+        }
+        return dates;
+    }
+}
+
+impl std::fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let freq = match self.freq {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        };
+        write!(f, "FREQ={}", freq)?;
+
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until.format("%Y%m%d"))?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self
+                .by_day
+                .iter()
+                .map(|(n, day)| match n {
+                    Some(n) => format!("{}{}", n, weekday_code(*day)),
+                    None => weekday_code(*day).to_string(),
+                })
+                .collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if !self.by_month_day.is_empty() {
+            let days: Vec<String> = self.by_month_day.iter().map(|d| d.to_string()).collect();
+            write!(f, ";BYMONTHDAY={}", days.join(","))?;
+        }
+        if !self.by_month.is_empty() {
+            let months: Vec<String> = self.by_month.iter().map(|m| m.to_string()).collect();
+            write!(f, ";BYMONTH={}", months.join(","))?;
+        }
+        if !self.by_set_pos.is_empty() {
+            let positions: Vec<String> = self.by_set_pos.iter().map(|p| p.to_string()).collect();
+            write!(f, ";BYSETPOS={}", positions.join(","))?;
+        }
+        if self.week_start != Weekday::Mon {
+            write!(f, ";WKST={}", weekday_code(self.week_start))?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        return NaiveDate::parse_from_str(value, "%Y-%m-%d").expect("valid date");
+    }
+
+    fn dates(values: &[&str]) -> Vec<NaiveDate> {
+        return values.iter().map(|value| date(value)).collect();
+    }
+
+    fn rule(value: &str) -> Recurrence {
+        return Recurrence::parse(value).expect("valid rule");
+    }
+
+    // Everything the rule produces from `dtstart` on, up to `to`.
+    fn series(value: &str, dtstart: &str, to: &str) -> Vec<NaiveDate> {
+        return rule(value).occurrences(date(dtstart), date(dtstart), date(to));
+    }
+
+    #[test]
+    fn parse_reads_every_part_and_writes_it_back() {
+        let recurrence = rule("RRULE:FREQ=MONTHLY;INTERVAL=2;COUNT=5;BYDAY=1MO,-1FR;WKST=SU");
+        assert_eq!(recurrence.freq, Frequency::Monthly);
+        assert_eq!(recurrence.interval, 2);
+        assert_eq!(recurrence.count, Some(5));
+        assert_eq!(
+            recurrence.by_day,
+            vec![(Some(1), Weekday::Mon), (Some(-1), Weekday::Fri)]
+        );
+        assert_eq!(recurrence.week_start, Weekday::Sun);
+        assert_eq!(
+            recurrence.to_string(),
+            "FREQ=MONTHLY;INTERVAL=2;COUNT=5;BYDAY=1MO,-1FR;WKST=SU"
+        );
+
+        let recurrence = rule("freq=yearly;until=20301231T235959Z;bymonth=2,8;bymonthday=-1");
+        assert_eq!(recurrence.until, Some(date("2030-12-31")));
+        assert_eq!(recurrence.by_month, vec![2, 8]);
+        assert_eq!(recurrence.by_month_day, vec![-1]);
+        assert_eq!(
+            recurrence.to_string(),
+            "FREQ=YEARLY;UNTIL=20301231;BYMONTHDAY=-1;BYMONTH=2,8"
+        );
+
+        let recurrence = rule("FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1");
+        assert_eq!(recurrence.by_set_pos, vec![-1]);
+        assert_eq!(
+            recurrence.to_string(),
+            "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_what_it_cant_follow() {
+        for bad in [
+            "",
+            "INTERVAL=2",
+            "FREQ=HOURLY",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=DAILY;COUNT=0",
+            "FREQ=DAILY;COUNT=3;UNTIL=20261231",
+            "FREQ=WEEKLY;BYDAY=1MO",
+            "FREQ=WEEKLY;BYDAY=XX",
+            "FREQ=MONTHLY;BYDAY=0MO",
+            "FREQ=MONTHLY;BYDAY=1MÖ",
+            "FREQ=MONTHLY;BYMONTHDAY=32",
+            "FREQ=YEARLY;BYMONTH=13",
+            "FREQ=MONTHLY;BYSETPOS=-1",
+            "FREQ=MONTHLY;BYDAY=MO;BYSETPOS=0",
+            "FREQ=DAILY;BYHOUR=9",
+            "FREQ=DAILY;UNTIL=2026",
+            "FREQ",
+        ] {
+            assert!(
+                Recurrence::parse(bad).is_err(),
+                "{} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn count_is_counted_from_dtstart_not_from_the_window() {
+        let recurrence = rule("FREQ=DAILY;COUNT=3");
+        assert_eq!(
+            recurrence.occurrences(date("2026-11-02"), date("2026-11-03"), date("2026-12-01")),
+            dates(&["2026-11-03", "2026-11-04"])
+        );
+        assert!(recurrence
+            .occurrences(date("2026-11-02"), date("2026-11-05"), date("2026-12-01"))
+            .is_empty());
+    }
+
+    #[test]
+    fn until_includes_its_own_date() {
+        assert_eq!(
+            series("FREQ=WEEKLY;UNTIL=20261116", "2026-11-02", "2027-01-01"),
+            dates(&["2026-11-02", "2026-11-09", "2026-11-16"])
+        );
+        assert_eq!(
+            series("FREQ=WEEKLY;UNTIL=20261116", "2026-11-02", "2026-11-10"),
+            dates(&["2026-11-02", "2026-11-09"])
+        );
+    }
+
+    #[test]
+    fn weekly_interval_skips_whole_weeks() {
+        assert_eq!(
+            series(
+                "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH",
+                "2026-11-02",
+                "2026-11-30"
+            ),
+            dates(&[
+                "2026-11-02",
+                "2026-11-05",
+                "2026-11-16",
+                "2026-11-19",
+                "2026-11-30"
+            ])
+        );
+    }
+
+    #[test]
+    fn week_start_decides_which_days_share_a_week() {
+        // RFC 5545's own example, with both week starts.
+        assert_eq!(
+            series(
+                "FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=MO",
+                "1997-08-05",
+                "1997-12-31"
+            ),
+            dates(&["1997-08-05", "1997-08-10", "1997-08-19", "1997-08-24"])
+        );
+        assert_eq!(
+            series(
+                "FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=SU",
+                "1997-08-05",
+                "1997-12-31"
+            ),
+            dates(&["1997-08-05", "1997-08-17", "1997-08-19", "1997-08-31"])
+        );
+    }
+
+    #[test]
+    fn byday_earlier_in_the_first_week_is_skipped() {
+        assert_eq!(
+            series("FREQ=WEEKLY;BYDAY=MO,FR", "2026-11-04", "2026-11-13"),
+            dates(&["2026-11-06", "2026-11-09", "2026-11-13"])
+        );
+    }
+
+    #[test]
+    fn weekly_without_byday_keeps_to_dtstart_weekday() {
+        assert_eq!(
+            series("FREQ=WEEKLY", "2026-11-04", "2026-11-25"),
+            dates(&["2026-11-04", "2026-11-11", "2026-11-18", "2026-11-25"])
+        );
+    }
+
+    #[test]
+    fn numbered_byday_counts_from_either_end_of_the_month() {
+        assert_eq!(
+            series("FREQ=MONTHLY;BYDAY=-1FR", "2026-10-01", "2026-12-31"),
+            dates(&["2026-10-30", "2026-11-27", "2026-12-25"])
+        );
+        assert_eq!(
+            series("FREQ=MONTHLY;COUNT=6;BYDAY=1MO", "2026-11-02", "2027-12-31"),
+            dates(&[
+                "2026-11-02",
+                "2026-12-07",
+                "2027-01-04",
+                "2027-02-01",
+                "2027-03-01",
+                "2027-04-05",
+            ])
+        );
+    }
+
+    #[test]
+    fn month_days_that_dont_exist_are_skipped() {
+        assert_eq!(
+            series("FREQ=MONTHLY;BYMONTHDAY=31", "2026-10-31", "2027-03-31"),
+            dates(&["2026-10-31", "2026-12-31", "2027-01-31", "2027-03-31"])
+        );
+        assert_eq!(
+            series("FREQ=MONTHLY", "2026-10-31", "2027-01-31"),
+            dates(&["2026-10-31", "2026-12-31", "2027-01-31"])
+        );
+        assert_eq!(
+            series("FREQ=MONTHLY;BYMONTHDAY=-1", "2026-10-31", "2027-01-31"),
+            dates(&["2026-10-31", "2026-11-30", "2026-12-31", "2027-01-31"])
+        );
+        assert_eq!(
+            series("FREQ=YEARLY", "2024-02-29", "2032-12-31"),
+            dates(&["2024-02-29", "2028-02-29", "2032-02-29"])
+        );
+    }
+
+    #[test]
+    fn bysetpos_picks_from_each_period() {
+        assert_eq!(
+            series(
+                "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1",
+                "2026-10-01",
+                "2027-01-31"
+            ),
+            dates(&["2026-10-30", "2026-11-30", "2026-12-31", "2027-01-29"])
+        );
+        assert_eq!(
+            series(
+                "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=1,2;COUNT=3",
+                "2026-10-01",
+                "2027-12-31"
+            ),
+            dates(&["2026-10-01", "2026-10-02", "2026-11-02"])
+        );
+        // Past the end of the set there's nothing to pick.
+        assert!(series(
+            "FREQ=MONTHLY;BYMONTHDAY=1,2;BYSETPOS=3",
+            "2026-10-01",
+            "2026-12-31"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn yearly_numbered_byday_counts_through_the_whole_year() {
+        // The 20th Monday of the year, from RFC 5545.
+        assert_eq!(
+            series("FREQ=YEARLY;BYDAY=20MO", "1997-05-12", "1999-12-31"),
+            dates(&["1997-05-19", "1998-05-18", "1999-05-17"])
+        );
+    }
+
+    #[test]
+    fn pin_to_spells_out_what_came_from_dtstart() {
+        let mut weekly = rule("FREQ=WEEKLY");
+        weekly.pin_to(date("2026-11-04"));
+        assert_eq!(weekly.to_string(), "FREQ=WEEKLY;BYDAY=WE");
+
+        let mut monthly = rule("FREQ=MONTHLY");
+        monthly.pin_to(date("2026-11-04"));
+        assert_eq!(monthly.to_string(), "FREQ=MONTHLY;BYMONTHDAY=4");
+
+        let mut yearly = rule("FREQ=YEARLY");
+        yearly.pin_to(date("2026-11-04"));
+        assert_eq!(yearly.to_string(), "FREQ=YEARLY;BYMONTHDAY=4;BYMONTH=11");
+
+        for kept in [
+            "FREQ=WEEKLY;BYDAY=MO",
+            "FREQ=MONTHLY;BYDAY=1MO",
+            "FREQ=YEARLY;BYDAY=20MO",
+        ] {
+            let mut recurrence = rule(kept);
+            recurrence.pin_to(date("2026-11-04"));
+            assert_eq!(recurrence.to_string(), kept);
+        }
+    }
+
+    #[test]
+    fn weekday_mask_follows_the_dates_the_rule_lands_on() {
+        // 2026-11-04 is a Wednesday; the mask starts on Sunday.
+        assert_eq!(
+            rule("FREQ=WEEKLY").weekday_mask(date("2026-11-04")),
+            vec![0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            rule("FREQ=WEEKLY;BYDAY=MO,FR").weekday_mask(date("2026-11-04")),
+            vec![0, 1, 0, 0, 0, 1, 0]
+        );
+        assert_eq!(
+            rule("FREQ=DAILY").weekday_mask(date("2026-11-04")),
+            vec![1; 7]
+        );
+        assert_eq!(
+            rule("FREQ=DAILY;COUNT=1").weekday_mask(date("2026-11-04")),
+            vec![0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            rule("FREQ=DAILY;UNTIL=20261106").weekday_mask(date("2026-11-04")),
+            vec![0, 0, 0, 1, 1, 1, 0]
+        );
+    }
+
+    #[test]
+    fn recurring_days_are_a_plain_weekly_rule() {
+        let recurrence = Recurrence::from_weekdays(&[0, 1, 0, 1, 0, 0, 0]);
+        assert!(recurrence.is_plain_weekly());
+        assert_eq!(recurrence.to_string(), "FREQ=WEEKLY;BYDAY=MO,WE");
+        assert!(!rule("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO").is_plain_weekly());
+        assert!(!rule("FREQ=WEEKLY;BYDAY=MO;COUNT=4").is_plain_weekly());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::{
+    connect::connect_to_db,
+    duration::expand_occurrences,
+    time_entry::{self, day_range, minutes_by_day, TimeEntry},
+};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Datelike, Duration as Days, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Serialize, Deserialize)]
+pub struct ReportQuery {
+    owner_id: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+// Planned and tracked minutes for one day, one week (starting Monday) or the whole range,
+// with the tasks completed in it and what they were estimated to take.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeriodComparison {
+    pub starts_on: NaiveDate,
+    pub planned_minutes: i64,
+    pub actual_minutes: i64,
+    pub variance_percent: Option<f64>,
+    pub completed_tasks: i64,
+    pub completed_estimate_minutes: i64,
+}
+
+// What's added up per category and date before it's rolled into periods.
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    planned: i64,
+    actual: i64,
+    completed_tasks: i64,
+    completed_estimate: i64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.planned += other.planned;
+        self.actual += other.actual;
+        self.completed_tasks += other.completed_tasks;
+        self.completed_estimate += other.completed_estimate;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CategoryComparison {
+    pub category_id: i32,
+    pub title: String,
+    pub total: PeriodComparison,
+    pub weeks: Vec<PeriodComparison>,
+    pub days: Vec<PeriodComparison>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlannedActualReport {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub total: PeriodComparison,
+    pub categories: Vec<CategoryComparison>,
+}
+
+// How far the tracked time is off the plan, e.g. -25.0 for 3h tracked out of 4h planned.
+// There's nothing to compare against when nothing was planned.
+pub fn variance_percent(planned: i64, actual: i64) -> Option<f64> {
+    if planned == 0 {
+        return None;
+    }
+    let percent = (actual - planned) as f64 * 100.0 / planned as f64;
+    return Some((percent * 10.0).round() / 10.0);
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    return date - Days::days(date.weekday().num_days_from_monday() as i64);
+}
+
+fn comparison(starts_on: NaiveDate, totals: &Totals) -> PeriodComparison {
+    return PeriodComparison {
+        starts_on,
+        planned_minutes: totals.planned,
+        actual_minutes: totals.actual,
+        variance_percent: variance_percent(totals.planned, totals.actual),
+        completed_tasks: totals.completed_tasks,
+        completed_estimate_minutes: totals.completed_estimate,
+    };
+}
+
+// Rolls the totals keyed by date up into a total, weeks and days.
+fn breakdown(
+    from: NaiveDate,
+    days: &BTreeMap<NaiveDate, Totals>,
+) -> (
+    PeriodComparison,
+    Vec<PeriodComparison>,
+    Vec<PeriodComparison>,
+) {
+    let mut weeks: BTreeMap<NaiveDate, Totals> = BTreeMap::new();
+    let mut total = Totals::default();
+    for (date, day) in days {
+        weeks.entry(week_start(*date)).or_default().add(day);
+        total.add(day);
+    }
+
+    return (
+        comparison(from, &total),
+        weeks
+            .iter()
+            .map(|(week, totals)| comparison(*week, totals))
+            .collect(),
+        days.iter()
+            .map(|(date, totals)| comparison(*date, totals))
+            .collect(),
+    );
+}
+
+// The parts of a pomodoro entry that no manual entry already covers, so time tracked both
+// ways at once only counts once.
+fn uncovered(entry: &TimeEntry, manual: &[&TimeEntry], now: NaiveDateTime) -> Vec<TimeEntry> {
+    let mut pieces = vec![(entry.started_at, entry.ended_at.unwrap_or(now))];
+    for other in manual {
+        let (start, end) = (other.started_at, other.ended_at.unwrap_or(now));
+        pieces = pieces
+            .into_iter()
+            .flat_map(|(from, to)| {
+                if end <= from || to <= start {
+                    return vec![(from, to)];
+                }
+                let mut left = Vec::new();
+                if from < start {
+                    left.push((from, start));
+                }
+                if end < to {
+                    left.push((end, to));
+                }
+                left
+            })
+            .collect();
+    }
+
+    return pieces
+        .into_iter()
+        .map(|(started_at, ended_at)| TimeEntry {
+            started_at,
+            ended_at: Some(ended_at),
+            note: entry.note.to_owned(),
+            ..*entry
+        })
+        .collect();
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+fn csv_row(out: &mut String, period: &str, category: &CategoryComparison, row: &PeriodComparison) {
+    out.push_str(&format!(
+        "{},{},{},{},{:.2},{:.2},{},{},{:.2}\r\n",
+        period,
+        row.starts_on,
+        category.category_id,
+        csv_field(&category.title),
+        row.planned_minutes as f64 / 60.0,
+        row.actual_minutes as f64 / 60.0,
+        row.variance_percent
+            .map(|variance| variance.to_string())
+            .unwrap_or_default(),
+        row.completed_tasks,
+        row.completed_estimate_minutes as f64 / 60.0,
+    ));
+}
+
+// One row per category and period, with hours rather than minutes for spreadsheets.
+fn to_csv(report: &PlannedActualReport) -> String {
+    let mut out = String::from(
+        "period,starts_on,category_id,category,planned_hours,actual_hours,variance_percent,\
+        completed_tasks,completed_estimate_hours\r\n",
+    );
+    for category in &report.categories {
+        csv_row(&mut out, "total", category, &category.total);
+        for week in &category.weeks {
+            csv_row(&mut out, "week", category, week);
+        }
+        for day in &category.days {
+            csv_row(&mut out, "day", category, day);
+        }
+    }
+    return out;
+}
+
+// #[get("/reports/planned_vs_actual")]
+// Compares the hours each category was given by its duration occurrences with the hours
+// tracked against it and the tasks completed in it, per day, per week and over the whole
+// range. Pomodoro time that overlaps a manual timer is only counted once, on the timer.
+pub async fn get_planned_vs_actual(query: web::Query<ReportQuery>) -> impl Responder {
+    if query.to < query.from {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("to must not be before from");
+    }
+
+    let client = connect_to_db().await;
+
+    let mut titles: BTreeMap<i32, String> = BTreeMap::new();
+    for row in client
+        .query(
+            "SELECT id, title FROM public.category WHERE owner_id=$1",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        titles.insert(row.get(0), row.get(1));
+    }
+
+    let mut totals: BTreeMap<i32, BTreeMap<NaiveDate, Totals>> = BTreeMap::new();
+    for occ in expand_occurrences(&client, Some(query.owner_id), query.from, query.to).await {
+        let day = totals
+            .entry(occ.category_id)
+            .or_default()
+            .entry(occ.date)
+            .or_default();
+        day.planned += ((occ.end_hour - occ.start_hour) * 60) as i64;
+    }
+
+    let (from, to) = day_range(query.from, query.to);
+    let now = time_entry::now();
+    let entries = time_entry::get_entries(&client, query.owner_id, from, to).await;
+    let ids: Vec<i32> = entries.iter().map(|entry| entry.id).collect();
+    let pomodoro_ids: HashSet<i32> = client
+        .query(
+            "SELECT time_entry_id FROM public.pomodoro_interval WHERE time_entry_id = ANY($1)",
+            &[&ids],
+        )
+        .await
+        .expect("error getting pomodoro intervals")
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let manual: Vec<&TimeEntry> = entries
+        .iter()
+        .filter(|entry| !pomodoro_ids.contains(&entry.id))
+        .collect();
+    for entry in &entries {
+        let pieces = match pomodoro_ids.contains(&entry.id) {
+            true => uncovered(entry, &manual, now),
+            false => vec![entry.clone()],
+        };
+        for piece in &pieces {
+            for (date, tracked) in minutes_by_day(piece, from, to, now) {
+                let day = totals
+                    .entry(entry.category_id)
+                    .or_default()
+                    .entry(date)
+                    .or_default();
+                day.actual += tracked;
+            }
+        }
+    }
+
+    for row in client
+        .query(
+            "SELECT category_id, completed_at, estimate_minutes FROM public.task
+            WHERE owner_id=$1 AND deleted_at IS NULL
+                AND completed_at >= $2 AND completed_at < $3",
+            &[&query.owner_id, &from, &to],
+        )
+        .await
+        .expect("error getting completed tasks")
+    {
+        let completed_at: NaiveDateTime = row.get(1);
+        let day = totals
+            .entry(row.get(0))
+            .or_default()
+            .entry(completed_at.date())
+            .or_default();
+        day.completed_tasks += 1;
+        day.completed_estimate += row.get::<_, Option<i32>>(2).unwrap_or(0) as i64;
+    }
+
+    let mut all_days: BTreeMap<NaiveDate, Totals> = BTreeMap::new();
+    let mut categories = Vec::new();
+    for (category_id, days) in &totals {
+        for (date, day) in days {
+            all_days.entry(*date).or_default().add(day);
+        }
+
+        let (total, weeks, days) = breakdown(query.from, days);
+        categories.push(CategoryComparison {
+            category_id: *category_id,
+            title: titles.get(category_id).cloned().unwrap_or_default(),
+            total,
+            weeks,
+            days,
+        });
+    }
+
+    let report = PlannedActualReport {
+        from: query.from,
+        to: query.to,
+        total: breakdown(query.from, &all_days).0,
+        categories,
+    };
+
+    match query.format.as_deref() {
+        Some("csv") => {
+            return HttpResponse::Ok()
+                .content_type("text/csv; charset=utf-8")
+                .body(to_csv(&report));
+        }
+        _ => return HttpResponse::Ok().json(report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").expect("valid time");
+    }
+
+    fn entry(started_at: &str, ended_at: Option<&str>) -> TimeEntry {
+        return TimeEntry {
+            id: 1,
+            owner_id: 1,
+            task_id: Some(1),
+            category_id: 1,
+            started_at: at(started_at),
+            ended_at: ended_at.map(at),
+            note: None,
+        };
+    }
+
+    fn spans(entries: &[TimeEntry]) -> Vec<(NaiveDateTime, Option<NaiveDateTime>)> {
+        return entries
+            .iter()
+            .map(|entry| (entry.started_at, entry.ended_at))
+            .collect();
+    }
+
+    #[test]
+    fn pomodoro_time_under_a_manual_timer_only_counts_once() {
+        let pomodoro = entry("2026-11-02 09:00", Some("2026-11-02 10:00"));
+        let timer = entry("2026-11-02 09:20", Some("2026-11-02 09:40"));
+        let now = at("2026-11-02 12:00");
+
+        assert_eq!(
+            spans(&uncovered(&pomodoro, &[&timer], now)),
+            vec![
+                (at("2026-11-02 09:00"), Some(at("2026-11-02 09:20"))),
+                (at("2026-11-02 09:40"), Some(at("2026-11-02 10:00"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_running_timer_covers_up_to_now() {
+        let pomodoro = entry("2026-11-02 09:00", Some("2026-11-02 10:00"));
+        let running = entry("2026-11-02 09:30", None);
+        let elsewhere = entry("2026-11-02 11:00", Some("2026-11-02 12:00"));
+
+        assert_eq!(
+            spans(&uncovered(
+                &pomodoro,
+                &[&running, &elsewhere],
+                at("2026-11-02 10:30")
+            )),
+            vec![(at("2026-11-02 09:00"), Some(at("2026-11-02 09:30")))]
+        );
+        assert!(uncovered(
+            &pomodoro,
+            &[&entry("2026-11-02 08:00", None)],
+            at("2026-11-02 10:30")
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_they_need_it() {
+        assert_eq!(csv_field("Deep work"), "Deep work");
+        assert_eq!(csv_field("Reading, writing"), "\"Reading, writing\"");
+        assert_eq!(csv_field("The \"big\" one"), "\"The \"\"big\"\" one\"");
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{self, duration_from_row, normalize_recurrence},
+    time_entry,
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{GenericClient, Row};
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathId {
+    pub id: i32,
+}
+
+// A named set of durations, e.g. "Fall term", that is only in effect between `starts_on` and
+// `ends_on` (inclusive, open ended when unset). An owner's schedules never overlap, so at most
+// one is active on any date. Durations without a schedule apply on every date.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub id: i32,
+    pub owner_id: i32,
+    pub title: String,
+    pub starts_on: NaiveDate,
+    pub ends_on: Option<NaiveDate>,
+    // Set while the schedule is in the trash, along with its durations.
+    #[serde(default)]
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleRequest {
+    owner_id: i32,
+    title: String,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CloneRequest {
+    title: String,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ActiveQuery {
+    owner_id: i32,
+    date: NaiveDate,
+}
+
+pub fn schedule_from_row(row: &Row) -> Schedule {
+    return Schedule {
+        id: row.get(0),
+        owner_id: row.get(1),
+        title: row.get(2),
+        starts_on: row.get(3),
+        ends_on: row.get(4),
+        deleted_at: row.get(5),
+    };
+}
+
+impl Schedule {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        return date >= self.starts_on && self.ends_on.is_none_or(|ends_on| date <= ends_on);
+    }
+}
+
+pub async fn get_schedules_by_ids<C: GenericClient>(client: &C, ids: &[i32]) -> Vec<Schedule> {
+    let mut schedules = Vec::new();
+
+    for row in client
+        .query("SELECT * FROM public.schedule WHERE id = ANY($1)", &[&ids])
+        .await
+        .expect("error getting schedules")
+    {
+        schedules.push(schedule_from_row(&row));
+    }
+    return schedules;
+}
+
+fn validate_range(starts_on: NaiveDate, ends_on: Option<NaiveDate>) -> Result<(), String> {
+    if ends_on.is_some_and(|ends_on| ends_on < starts_on) {
+        return Err("ends_on must not be before starts_on".to_string());
+    }
+    return Ok(());
+}
+
+// The owner's other schedules whose date range touches `starts_on..=ends_on`. Ones in the
+// trash don't count.
+pub async fn overlapping_schedules<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    skip_id: i32,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+) -> Vec<Schedule> {
+    let mut schedules = Vec::new();
+
+    for row in client
+        .query(
+            "SELECT * FROM public.schedule
+            WHERE owner_id = $1
+                AND id <> $2
+                AND deleted_at IS NULL
+                AND starts_on <= COALESCE($4, 'infinity'::DATE)
+                AND COALESCE(ends_on, 'infinity'::DATE) >= $3",
+            &[&owner_id, &skip_id, &starts_on, &ends_on],
+        )
+        .await
+        .expect("error checking schedule overlap")
+    {
+        schedules.push(schedule_from_row(&row));
+    }
+    return schedules;
+}
+
+async fn insert_schedule<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    title: &str,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+) -> Result<Schedule, HttpResponse> {
+    if let Err(msg) = validate_range(starts_on, ends_on) {
+        return Err(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg));
+    }
+
+    let overlapping = overlapping_schedules(client, owner_id, 0, starts_on, ends_on).await;
+    if !overlapping.is_empty() {
+        return Err(HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(overlapping));
+    }
+
+    match client
+        .query_one(
+            "INSERT INTO public.schedule (
+                owner_id,
+                title,
+                starts_on,
+                ends_on
+            ) VALUES ($1, $2, $3, $4) RETURNING *",
+            &[&owner_id, &title, &starts_on, &ends_on],
+        )
+        .await
+    {
+        Ok(row) => return Ok(schedule_from_row(&row)),
+        Err(err) => {
+            return Err(HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .json(err.to_string()));
+        }
+    }
+}
+
+// #[get("/schedules")]
+pub async fn get_schedules() -> impl Responder {
+    let client = connect_to_db().await;
+    let mut schedules: Vec<Schedule> = Vec::new();
+
+    for row in client
+        .query(
+            "SELECT * FROM public.schedule WHERE deleted_at IS NULL ORDER BY starts_on ASC",
+            &[],
+        )
+        .await
+        .expect("error getting schedules")
+    {
+        schedules.push(schedule_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(schedules);
+}
+
+// #[get("/schedules/active")]
+pub async fn get_active_schedule(query: web::Query<ActiveQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let row = client
+        .query_opt(
+            "SELECT * FROM public.schedule
+            WHERE owner_id = $1
+                AND deleted_at IS NULL
+                AND starts_on <= $2
+                AND COALESCE(ends_on, 'infinity'::DATE) >= $2",
+            &[&query.owner_id, &query.date],
+        )
+        .await
+        .expect("error getting active schedule");
+
+    match row {
+        Some(row) => return HttpResponse::Ok().json(schedule_from_row(&row)),
+        None => return HttpResponse::NotFound().json("no schedule active on that date"),
+    }
+}
+
+// #[post("/schedule")]
+pub async fn create_schedule(
+    _req: HttpRequest,
+    params: web::Json<ScheduleRequest>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    match insert_schedule(
+        &client,
+        params.owner_id,
+        &params.title,
+        params.starts_on,
+        params.ends_on,
+    )
+    .await
+    {
+        Ok(schedule) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(schedule);
+        }
+        Err(response) => return response,
+    }
+}
+
+// #[get("/schedule/{id}")]
+pub async fn get_schedule_by_id(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let row = client
+        .query_one("SELECT * FROM public.schedule WHERE id=$1", &[&info.id])
+        .await
+        .expect("error getting schedule");
+
+    return HttpResponse::Ok().json(schedule_from_row(&row));
+}
+
+// #[put("/schedule/{id}")]
+pub async fn update_schedule(
+    _req: HttpRequest,
+    params: web::Json<ScheduleRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let schedule = Schedule {
+        id: info.id,
+        owner_id: params.owner_id,
+        title: params.title.to_owned(),
+        starts_on: params.starts_on,
+        ends_on: params.ends_on,
+        deleted_at: None,
+    };
+
+    if let Err(msg) = validate_range(schedule.starts_on, schedule.ends_on) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+    let overlapping = overlapping_schedules(
+        &client,
+        schedule.owner_id,
+        schedule.id,
+        schedule.starts_on,
+        schedule.ends_on,
+    )
+    .await;
+    if !overlapping.is_empty() {
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(overlapping);
+    }
+
+    match client
+        .execute(
+            "UPDATE public.schedule
+            SET owner_id = $1,
+                title = $2,
+                starts_on = $3,
+                ends_on = $4
+             WHERE id = $5",
+            &[
+                &schedule.owner_id,
+                &schedule.title,
+                &schedule.starts_on,
+                &schedule.ends_on,
+                &schedule.id,
+            ],
+        )
+        .await
+    {
+        Ok(_data) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(schedule);
+        }
+        Err(err) => {
+            return HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[post("/schedule/{id}/clone")]
+// Copies the schedule and all of its durations into a new schedule for another date range.
+// The copied series start again from the new schedule's first day, keeping the weekday or day
+// of the month they fell on and with any UNTIL moved by as much as the start; exceptions
+// aren't copied since they belong to dates of the old range.
+pub async fn clone_schedule(
+    _req: HttpRequest,
+    params: web::Json<CloneRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let mut client = connect_to_db().await;
+
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let source = match transaction
+        .query_opt(
+            "SELECT * FROM public.schedule WHERE id=$1 AND deleted_at IS NULL",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting schedule")
+    {
+        Some(row) => schedule_from_row(&row),
+        None => return HttpResponse::NotFound().json("schedule not found"),
+    };
+
+    let schedule = match insert_schedule(
+        &transaction,
+        source.owner_id,
+        &params.title,
+        params.starts_on,
+        params.ends_on,
+    )
+    .await
+    {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+
+    for row in transaction
+        .query(
+            "SELECT * FROM public.duration WHERE schedule_id = $1 AND deleted_at IS NULL",
+            &[&source.id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        let dur = duration_from_row(&row);
+        let (mut rule, old_start) = dur.recurrence(source.starts_on);
+        rule.pin_to(old_start);
+        rule.until = rule
+            .until
+            .map(|until| until + (schedule.starts_on - old_start));
+        let (recurring_days, rrule, dtstart) = match normalize_recurrence(
+            &dur.recurring_days,
+            &Some(rule.to_string()),
+            Some(schedule.starts_on),
+        ) {
+            Ok(normalized) => normalized,
+            Err(msg) => {
+                return HttpResponse::InternalServerError()
+                    .content_type("application/json")
+                    .json(format!("duration {} has an unreadable rule: {}", dur.id, msg));
+            }
+        };
+
+        let copied = transaction
+            .query_one(
+                "INSERT INTO public.duration (
+                    owner_id,
+                    category_id,
+                    start_hour,
+                    end_hour,
+                    recurring_days,
+                    color,
+                    rrule,
+                    dtstart,
+                    schedule_id
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+                &[
+                    &dur.owner_id,
+                    &dur.category_id,
+                    &dur.start_hour,
+                    &dur.end_hour,
+                    &recurring_days,
+                    &dur.color,
+                    &rrule,
+                    &dtstart,
+                    &schedule.id,
+                ],
+            )
+            .await;
+        let copied = match copied {
+            Ok(row) => duration::stamp_completed(&transaction, row.get(0)).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = copied {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+
+    transaction
+        .commit()
+        .await
+        .expect("error cloning schedule");
+
+    return HttpResponse::Created()
+        .content_type("application/json")
+        .json(schedule);
+}
+
+// #[delete("/schedule/{id}")]
+// Moves the schedule to the trash with its durations, all stamped with the same deleted_at
+// so restoring the schedule brings back exactly those.
+pub async fn delete_schedule_by_id(info: Path<InfoPathId>) -> impl Responder {
+    let id = info.id;
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let now = time_entry::now();
+    for sql in [
+        "UPDATE public.schedule SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL",
+        "UPDATE public.duration SET deleted_at = $2 WHERE schedule_id = $1 AND deleted_at IS NULL",
+    ] {
+        transaction
+            .execute(sql, &[&id, &now])
+            .await
+            .expect("error deleting schedule");
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
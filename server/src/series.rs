@@ -0,0 +1,333 @@
+use crate::{
+    connect::connect_to_db,
+    rank,
+    recurrence::Recurrence,
+    status,
+    task::{task_from_row, InfoPathId, Task},
+    time_entry,
+};
+use actix_web::{web::Path, HttpResponse, Responder};
+use chrono::{Duration as Days, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient, Row};
+
+// How far ahead to look for the next date of a fixed schedule.
+const SEARCH_YEARS: i64 = 5;
+
+// One completed instance of a recurring task, and the instance it spawned.
+#[derive(Serialize, Deserialize)]
+pub struct TaskCompletion {
+    pub id: i32,
+    pub task_id: i32,
+    pub series_id: i32,
+    pub owner_id: i32,
+    pub due_at: Option<NaiveDateTime>,
+    pub completed_at: NaiveDateTime,
+    pub next_task_id: Option<i32>,
+}
+
+pub fn completion_from_row(row: &Row) -> TaskCompletion {
+    return TaskCompletion {
+        id: row.get(0),
+        task_id: row.get(1),
+        series_id: row.get(2),
+        owner_id: row.get(3),
+        due_at: row.get(4),
+        completed_at: row.get(5),
+        next_task_id: row.get(6),
+    };
+}
+
+// A task repeats on a fixed RRULE or a number of days after it's completed, not both.
+pub fn check_rule(rrule: Option<&str>, repeat_after_days: Option<i32>) -> Result<(), String> {
+    match (rrule, repeat_after_days) {
+        (Some(_), Some(_)) => {
+            return Err("rrule and repeat_after_days can't both be set".to_string());
+        }
+        (Some(rule), None) => return Recurrence::parse(rule).map(|_| ()),
+        (None, Some(days)) if days < 1 => {
+            return Err("repeat_after_days must be at least 1".to_string());
+        }
+        _ => return Ok(()),
+    }
+}
+
+pub fn is_recurring(task: &Task) -> bool {
+    return task.rrule.is_some() || task.repeat_after_days.is_some();
+}
+
+// When the next instance is due. A fixed schedule counts on from the series' first due
+// date (`dtstart`) to the first date after this instance's, so finishing late doesn't
+// shift the series. `repeat_after_days` counts from the completion. The time of day of
+// the due date carries over; undated tasks are due at the completion time.
+pub fn next_due(
+    task: &Task,
+    dtstart: Option<NaiveDateTime>,
+    completed_at: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let due_at = task.due_at.unwrap_or(completed_at);
+
+    if let Some(days) = task.repeat_after_days {
+        return Some(completed_at.date().and_time(due_at.time()) + Days::days(days as i64));
+    }
+
+    let rule = Recurrence::parse(task.rrule.as_deref()?).ok()?;
+    let dtstart = dtstart.unwrap_or(due_at).date();
+    let after = due_at.date() + Days::days(1);
+    return rule
+        .occurrences(dtstart, after, after + Days::days(366 * SEARCH_YEARS))
+        .first()
+        .map(|date| date.and_time(due_at.time()));
+}
+
+// Records that `task` was just completed and, unless its series has run out, adds the
+// next open instance. Completing the same instance twice only counts once.
+pub async fn complete<C: GenericClient>(client: &C, task: &Task) -> Result<Option<i32>, Error> {
+    let completed_at = time_entry::now();
+    let series_id = task.series_id.unwrap_or(task.id);
+
+    let recorded = client
+        .execute(
+            "INSERT INTO public.task_completion (task_id, series_id, owner_id, due_at, completed_at)
+            VALUES ($1, $2, $3, $4, $5) ON CONFLICT (task_id) DO NOTHING",
+            &[
+                &task.id,
+                &series_id,
+                &task.owner_id,
+                &task.due_at,
+                &completed_at,
+            ],
+        )
+        .await?;
+    if recorded == 0 {
+        return Ok(None);
+    }
+
+    // Where the rule was set, kept on every instance. Older series fall back to the head's
+    // due date.
+    let dtstart: Option<NaiveDateTime> = client
+        .query_opt(
+            "SELECT COALESCE(task.series_dtstart, head.due_at)
+            FROM public.task AS task LEFT JOIN public.task AS head ON head.id = $2
+            WHERE task.id = $1",
+            &[&task.id, &series_id],
+        )
+        .await?
+        .and_then(|row| row.get(0));
+    let Some(due_at) = next_due(task, dtstart, completed_at) else {
+        return Ok(None);
+    };
+    // The defer date keeps the same lead on the due date.
+    let start_at = match (task.start_at, task.due_at) {
+        (Some(start_at), Some(old_due)) => Some(due_at - (old_due - start_at)),
+        _ => None,
+    };
+
+    let row = client
+        .query_one(
+            "INSERT INTO public.task (
+                title,
+                description,
+                is_complete,
+                priority,
+                owner_id,
+                category_id,
+                estimate_minutes,
+                due_at,
+                start_at,
+                rrule,
+                repeat_after_days,
+                series_id,
+                parent_id,
+                rank,
+                series_dtstart
+            ) VALUES (
+                $1, $2, false, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                (SELECT COALESCE(MAX(rank), 0) + $13 FROM public.task WHERE category_id = $5),
+                $14
+            ) RETURNING id",
+            &[
+                &task.title,
+                &task.description,
+                &task.priority,
+                &task.owner_id,
+                &task.category_id,
+                &task.estimate_minutes,
+                &due_at,
+                &start_at,
+                &task.rrule,
+                &task.repeat_after_days,
+                &series_id,
+                &task.parent_id,
+                &rank::RANK_STEP,
+                &dtstart.filter(|_| task.rrule.is_some()),
+            ],
+        )
+        .await?;
+    let next_id: i32 = row.get(0);
+
+    client
+        .execute(
+            "INSERT INTO public.task_tag (task_id, tag_id)
+            SELECT $1, tag_id FROM public.task_tag WHERE task_id = $2",
+            &[&next_id, &task.id],
+        )
+        .await?;
+
+    status::reconcile(client, next_id, None).await?;
+
+    client
+        .execute(
+            "UPDATE public.task_completion SET next_task_id = $1 WHERE task_id = $2",
+            &[&next_id, &task.id],
+        )
+        .await?;
+    return Ok(Some(next_id));
+}
+
+// #[get("/task/{id}/series")]
+// Every completion recorded for the series the task belongs to, oldest first.
+pub async fn get_task_series(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let task = match client
+        .query_opt("SELECT * FROM public.task WHERE id=$1", &[&info.id])
+        .await
+        .expect("error getting task")
+    {
+        Some(row) => task_from_row(&row),
+        None => {
+            return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json("no such task");
+        }
+    };
+
+    let mut completions: Vec<TaskCompletion> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task_completion WHERE series_id=$1 ORDER BY completed_at",
+            &[&task.series_id.unwrap_or(task.id)],
+        )
+        .await
+        .expect("error getting completions")
+    {
+        completions.push(completion_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(completions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(value: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").expect("valid time");
+    }
+
+    fn task(due_at: Option<&str>, rrule: Option<&str>, repeat_after_days: Option<i32>) -> Task {
+        return serde_json::from_value(json!({
+            "id": 1,
+            "title": "water the plants",
+            "description": "",
+            "is_complete": true,
+            "priority": 2,
+            "owner_id": 1,
+            "category_id": 1,
+            "due_at": due_at.map(at),
+            "rrule": rrule,
+            "repeat_after_days": repeat_after_days,
+        }))
+        .expect("valid task");
+    }
+
+    #[test]
+    fn repeat_after_days_counts_from_the_completion() {
+        let dated = task(Some("2026-11-02 09:00"), None, Some(3));
+        assert_eq!(
+            next_due(&dated, None, at("2026-11-05 18:00")),
+            Some(at("2026-11-08 09:00"))
+        );
+
+        let undated = task(None, None, Some(2));
+        assert_eq!(
+            next_due(&undated, None, at("2026-11-05 18:00")),
+            Some(at("2026-11-07 18:00"))
+        );
+    }
+
+    #[test]
+    fn a_fixed_schedule_doesnt_move_when_finished_late() {
+        let weekly = task(Some("2026-11-02 09:00"), Some("FREQ=WEEKLY;BYDAY=MO"), None);
+        assert_eq!(
+            next_due(
+                &weekly,
+                Some(at("2026-11-02 09:00")),
+                at("2026-11-12 20:00")
+            ),
+            Some(at("2026-11-09 09:00"))
+        );
+
+        let last_friday = task(
+            Some("2026-10-30 17:00"),
+            Some("FREQ=MONTHLY;BYDAY=-1FR"),
+            None,
+        );
+        assert_eq!(
+            next_due(
+                &last_friday,
+                Some(at("2026-10-30 17:00")),
+                at("2026-10-30 16:00")
+            ),
+            Some(at("2026-11-27 17:00"))
+        );
+    }
+
+    #[test]
+    fn count_is_counted_from_the_series_start() {
+        let third = task(Some("2026-11-04 09:00"), Some("FREQ=DAILY;COUNT=3"), None);
+        assert_eq!(
+            next_due(&third, Some(at("2026-11-02 09:00")), at("2026-11-04 10:00")),
+            None
+        );
+
+        let until = task(
+            Some("2026-11-09 09:00"),
+            Some("FREQ=WEEKLY;UNTIL=20261116"),
+            None,
+        );
+        let dtstart = Some(at("2026-11-02 09:00"));
+        assert_eq!(
+            next_due(&until, dtstart, at("2026-11-09 10:00")),
+            Some(at("2026-11-16 09:00"))
+        );
+        let last = task(
+            Some("2026-11-16 09:00"),
+            Some("FREQ=WEEKLY;UNTIL=20261116"),
+            None,
+        );
+        assert_eq!(next_due(&last, dtstart, at("2026-11-16 10:00")), None);
+    }
+
+    #[test]
+    fn an_undated_series_runs_out_from_where_its_rule_was_set() {
+        let rule = Some("FREQ=DAILY;COUNT=2");
+        let dtstart = Some(at("2026-11-02 08:00"));
+
+        let first = task(None, rule, None);
+        let second_due = next_due(&first, dtstart, at("2026-11-02 10:00"));
+        assert_eq!(second_due, Some(at("2026-11-03 10:00")));
+
+        let mut second = task(None, rule, None);
+        second.due_at = second_due;
+        assert_eq!(next_due(&second, dtstart, at("2026-11-03 11:00")), None);
+    }
+
+    #[test]
+    fn a_rule_that_doesnt_parse_ends_the_series() {
+        let broken = task(Some("2026-11-02 09:00"), Some("FREQ=SOMETIMES"), None);
+        assert_eq!(next_due(&broken, None, at("2026-11-02 10:00")), None);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{
+    connect::connect_to_db,
+    duration::{self, get_owner_durations, insert_duration, DurationReq, DEFAULT_COLOR},
+    recurrence::Recurrence,
+    schedule,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+// Hours of the week, indexed by weekday (0 = Sunday) and hour.
+type Grid = [[bool; 24]; 7];
+
+// Lower is better: hours already on the day, distance from the preferred hours, day of the
+// week, start hour.
+type Score = (i32, i32, usize, i32);
+
+#[derive(Serialize, Deserialize)]
+pub struct SolveQuery {
+    #[serde(default)]
+    commit: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CategoryTarget {
+    pub category_id: i32,
+    pub hours: i32,
+    #[serde(default = "default_min_block")]
+    pub min_block: i32,
+    #[serde(default = "default_max_block")]
+    pub max_block: i32,
+    // Blocks inside these hours are picked first when there's a choice.
+    #[serde(default)]
+    pub preferred_start: Option<i32>,
+    #[serde(default)]
+    pub preferred_end: Option<i32>,
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+fn default_min_block() -> i32 {
+    return 1;
+}
+
+fn default_max_block() -> i32 {
+    return 2;
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AvailabilityWindow {
+    pub day: i32,
+    pub start_hour: i32,
+    pub end_hour: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SolveRequest {
+    owner_id: i32,
+    #[serde(default)]
+    schedule_id: Option<i32>,
+    #[serde(default)]
+    dtstart: Option<NaiveDate>,
+    targets: Vec<CategoryTarget>,
+    availability: Vec<AvailabilityWindow>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Shortfall {
+    pub category_id: i32,
+    pub missing_hours: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Solution {
+    pub durations: Vec<DurationReq>,
+    pub shortfalls: Vec<Shortfall>,
+    // Ids of the rows written when the solution was committed.
+    pub created: Vec<i32>,
+}
+
+// One placed block, before blocks with the same hours are folded into a weekly series.
+struct Block {
+    target: usize,
+    day: usize,
+    start_hour: i32,
+    end_hour: i32,
+}
+
+fn validate(params: &SolveRequest) -> Result<(), String> {
+    if params.availability.is_empty() {
+        return Err("availability needs at least one window".to_string());
+    }
+    for window in &params.availability {
+        if !(0..7).contains(&window.day) {
+            return Err("availability day must be within 0-6".to_string());
+        }
+        duration::validate_block(window.start_hour, window.end_hour)?;
+    }
+    for target in &params.targets {
+        if target.hours < 0 {
+            return Err("target hours can't be negative".to_string());
+        }
+        if target.min_block < 1 || target.max_block < target.min_block {
+            return Err("min_block must be at least 1 and no more than max_block".to_string());
+        }
+        if let (Some(start), Some(end)) = (target.preferred_start, target.preferred_end) {
+            duration::validate_block(start, end)?;
+        }
+    }
+    return Ok(());
+}
+
+// Monday first, so "earliest" reads the way a week is usually laid out.
+fn week_order(day: usize) -> usize {
+    return (day + 6) % 7;
+}
+
+fn is_free(grid: &Grid, day: usize, start_hour: i32, end_hour: i32) -> bool {
+    return (start_hour..end_hour).all(|hour| grid[day][hour as usize]);
+}
+
+// How far a block sits outside the preferred hours, 0 when it's inside or there are none.
+fn preference_distance(target: &CategoryTarget, start_hour: i32, end_hour: i32) -> i32 {
+    let start = target.preferred_start.unwrap_or(0);
+    let end = target.preferred_end.unwrap_or(24);
+    return (start - start_hour).max(0) + (end_hour - end).max(0);
+}
+
+// Greedy placement, target by target in the order given. Each block is as long as allowed,
+// on the day where the category has the fewest hours so far, as close to the preferred hours
+// as possible, and otherwise as early in the week and day as possible.
+fn place_blocks(targets: &[CategoryTarget], mut grid: Grid) -> (Vec<Block>, Vec<Shortfall>) {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut shortfalls = Vec::new();
+
+    for (index, target) in targets.iter().enumerate() {
+        let mut remaining = target.hours;
+        let mut hours_on_day = [0; 7];
+
+        while remaining >= target.min_block {
+            let mut best: Option<(Score, usize, i32, i32)> = None;
+
+            for length in (target.min_block..=target.max_block.min(remaining)).rev() {
+                for (day, hours) in hours_on_day.iter().enumerate() {
+                    for start_hour in 0..=(24 - length) {
+                        let end_hour = start_hour + length;
+                        if !is_free(&grid, day, start_hour, end_hour) {
+                            continue;
+                        }
+                        let score = (
+                            *hours,
+                            preference_distance(target, start_hour, end_hour),
+                            week_order(day),
+                            start_hour,
+                        );
+                        if best.is_none_or(|(best_score, _, _, _)| score < best_score) {
+                            best = Some((score, day, start_hour, length));
+                        }
+                    }
+                }
+                // Longer blocks win whenever one fits anywhere.
+                if best.is_some() {
+                    break;
+                }
+            }
+
+            let Some((_, day, start_hour, length)) = best else {
+                break;
+            };
+            for hour in start_hour..start_hour + length {
+                grid[day][hour as usize] = false;
+            }
+            hours_on_day[day] += length;
+            remaining -= length;
+            blocks.push(Block {
+                target: index,
+                day,
+                start_hour,
+                end_hour: start_hour + length,
+            });
+        }
+
+        if remaining > 0 {
+            shortfalls.push(Shortfall {
+                category_id: target.category_id,
+                missing_hours: remaining,
+            });
+        }
+    }
+    return (blocks, shortfalls);
+}
+
+// Blocks of one category at the same hours become a single duration over several weekdays.
+fn fold_blocks(params: &SolveRequest, blocks: &[Block], dtstart: NaiveDate) -> Vec<DurationReq> {
+    let mut durations: Vec<DurationReq> = Vec::new();
+
+    for block in blocks {
+        let target = &params.targets[block.target];
+        let existing = durations.iter_mut().find(|dur| {
+            dur.category_id == target.category_id
+                && dur.start_hour == block.start_hour
+                && dur.end_hour == block.end_hour
+        });
+        match existing {
+            Some(dur) => dur.recurring_days[block.day] = 1,
+            None => {
+                let mut recurring_days = vec![0; 7];
+                recurring_days[block.day] = 1;
+                durations.push(DurationReq {
+                    owner_id: params.owner_id,
+                    category_id: target.category_id,
+                    start_hour: block.start_hour,
+                    end_hour: block.end_hour,
+                    recurring_days,
+                    color: target.color.to_owned().unwrap_or(DEFAULT_COLOR.to_string()),
+                    rrule: None,
+                    dtstart: Some(dtstart),
+                    schedule_id: params.schedule_id,
+                });
+            }
+        }
+    }
+
+    for dur in durations.iter_mut() {
+        dur.rrule = Some(Recurrence::from_weekdays(&dur.recurring_days).to_string());
+    }
+    return durations;
+}
+
+// #[post("/schedule/solve")]
+// Proposes weekly durations that meet the hour targets inside the availability windows without
+// touching the owner's existing blocks. With `?commit=true` the same proposal is written in
+// one transaction.
+pub async fn solve_schedule(
+    _req: HttpRequest,
+    params: web::Json<SolveRequest>,
+    query: web::Query<SolveQuery>,
+) -> impl Responder {
+    if let Err(msg) = validate(&params) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    let mut client = connect_to_db().await;
+
+    let dtstart = match params.schedule_id {
+        Some(schedule_id) => {
+            match schedule::get_schedules_by_ids(&client, &[schedule_id])
+                .await
+                .first()
+            {
+                Some(schedule) => params.dtstart.unwrap_or(schedule.starts_on),
+                None => return HttpResponse::NotFound().json("schedule not found"),
+            }
+        }
+        None => params.dtstart.unwrap_or(Local::now().date_naive()),
+    };
+
+    let mut grid: Grid = [[false; 24]; 7];
+    for window in &params.availability {
+        for hour in window.start_hour..window.end_hour {
+            grid[window.day as usize][hour as usize] = true;
+        }
+    }
+    // Existing blocks are taken on every weekday they're switched on for, even when their
+    // rule skips some weeks, so the proposal never clashes with them.
+    for dur in get_owner_durations(&client, params.owner_id).await {
+        if dur.schedule_id.is_some()
+            && params.schedule_id.is_some()
+            && dur.schedule_id != params.schedule_id
+        {
+            continue;
+        }
+        for (day, on) in dur.recurring_days.iter().enumerate().take(7) {
+            if *on != 1 {
+                continue;
+            }
+            for hour in dur.start_hour.max(0)..dur.end_hour.min(24) {
+                grid[day][hour as usize] = false;
+            }
+        }
+    }
+
+    let (blocks, shortfalls) = place_blocks(&params.targets, grid);
+    let durations = fold_blocks(&params, &blocks, dtstart);
+
+    if !query.commit {
+        return HttpResponse::Ok().json(Solution {
+            durations,
+            shortfalls,
+            created: Vec::new(),
+        });
+    }
+
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+    let mut created = Vec::new();
+    for dur in &durations {
+        match insert_duration(&transaction, dur).await {
+            Ok(id) => created.push(id),
+            Err(err) => {
+                return HttpResponse::Conflict()
+                    .content_type("application/json")
+                    .json(err.to_string());
+            }
+        }
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error committing schedule");
+
+    return HttpResponse::Created()
+        .content_type("application/json")
+        .json(Solution {
+            durations,
+            shortfalls,
+            created,
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn grid(windows: &[(usize, i32, i32)]) -> Grid {
+        let mut grid = [[false; 24]; 7];
+        for (day, start_hour, end_hour) in windows {
+            for hour in *start_hour..*end_hour {
+                grid[*day][hour as usize] = true;
+            }
+        }
+        return grid;
+    }
+
+    fn target(category_id: i32, hours: i32, extra: serde_json::Value) -> CategoryTarget {
+        let mut value = json!({ "category_id": category_id, "hours": hours });
+        value
+            .as_object_mut()
+            .expect("object")
+            .extend(extra.as_object().expect("object").clone());
+        return serde_json::from_value(value).expect("valid target");
+    }
+
+    fn placed(blocks: &[Block]) -> Vec<(usize, usize, i32, i32)> {
+        return blocks
+            .iter()
+            .map(|block| (block.target, block.day, block.start_hour, block.end_hour))
+            .collect();
+    }
+
+    fn missing(shortfalls: &[Shortfall]) -> Vec<(i32, i32)> {
+        return shortfalls
+            .iter()
+            .map(|short| (short.category_id, short.missing_hours))
+            .collect();
+    }
+
+    #[test]
+    fn blocks_spread_over_the_days_monday_first() {
+        let week = grid(&[(0, 9, 17), (1, 9, 17), (2, 9, 17)]);
+        let (blocks, shortfalls) = place_blocks(&[target(1, 5, json!({}))], week);
+
+        assert_eq!(
+            placed(&blocks),
+            vec![(0, 1, 9, 11), (0, 2, 9, 11), (0, 0, 9, 10)]
+        );
+        assert!(shortfalls.is_empty());
+    }
+
+    #[test]
+    fn preferred_hours_win_over_earlier_ones() {
+        let week = grid(&[(1, 9, 17)]);
+        let afternoon = target(1, 2, json!({ "preferred_start": 13, "preferred_end": 16 }));
+        let (blocks, _) = place_blocks(&[afternoon], week);
+
+        assert_eq!(placed(&blocks), vec![(0, 1, 13, 15)]);
+    }
+
+    #[test]
+    fn shorter_blocks_fill_in_when_longer_ones_dont_fit() {
+        let week = grid(&[(1, 9, 10), (2, 14, 15)]);
+        let (blocks, shortfalls) = place_blocks(&[target(1, 3, json!({ "max_block": 2 }))], week);
+
+        assert_eq!(placed(&blocks), vec![(0, 1, 9, 10), (0, 2, 14, 15)]);
+        assert_eq!(missing(&shortfalls), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn nothing_under_min_block_is_placed() {
+        let week = grid(&[(1, 9, 10), (2, 9, 10)]);
+        let (blocks, shortfalls) = place_blocks(&[target(1, 2, json!({ "min_block": 2 }))], week);
+
+        assert!(blocks.is_empty());
+        assert_eq!(missing(&shortfalls), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn earlier_targets_take_the_hours_first() {
+        let week = grid(&[(1, 9, 12)]);
+        let targets = [target(1, 2, json!({})), target(2, 2, json!({}))];
+        let (blocks, shortfalls) = place_blocks(&targets, week);
+
+        assert_eq!(placed(&blocks), vec![(0, 1, 9, 11), (1, 1, 11, 12)]);
+        assert_eq!(missing(&shortfalls), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn blocks_at_the_same_hours_fold_into_one_duration() {
+        let params: SolveRequest = serde_json::from_value(json!({
+            "owner_id": 1,
+            "targets": [{ "category_id": 1, "hours": 6 }],
+            "availability": [],
+        }))
+        .expect("valid request");
+        let week = grid(&[(1, 9, 11), (3, 9, 11), (5, 13, 15)]);
+        let (blocks, _) = place_blocks(&params.targets, week);
+        let dtstart = NaiveDate::from_ymd_opt(2026, 11, 2).expect("valid date");
+
+        let durations = fold_blocks(&params, &blocks, dtstart);
+        let folded: Vec<(i32, i32, Vec<i32>, Option<String>)> = durations
+            .into_iter()
+            .map(|dur| (dur.start_hour, dur.end_hour, dur.recurring_days, dur.rrule))
+            .collect();
+        assert_eq!(
+            folded,
+            vec![
+                (
+                    9,
+                    11,
+                    vec![0, 1, 0, 1, 0, 0, 0],
+                    Some("FREQ=WEEKLY;BYDAY=MO,WE".to_string())
+                ),
+                (
+                    13,
+                    15,
+                    vec![0, 0, 0, 0, 0, 1, 0],
+                    Some("FREQ=WEEKLY;BYDAY=FR".to_string())
+                ),
+            ]
+        );
+    }
+}
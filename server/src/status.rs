@@ -0,0 +1,501 @@
+use crate::{
+    category,
+    connect::connect_to_db,
+    history,
+    task::{annotate_tasks, save_and_complete, task_from_row, InfoPathId, Task},
+    time_entry,
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient, Row};
+
+// A column on a category's board. Tasks in an `is_done` status count as complete, and
+// `is_complete` on the task follows its status.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TaskStatus {
+    pub id: i32,
+    pub category_id: i32,
+    pub title: String,
+    pub position: i32,
+    pub is_done: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusRequest {
+    title: String,
+    #[serde(default)]
+    position: Option<i32>,
+    #[serde(default)]
+    is_done: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusChange {
+    pub task_id: i32,
+    pub from_status_id: Option<i32>,
+    pub to_status_id: Option<i32>,
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BoardColumn {
+    pub status: TaskStatus,
+    pub tasks: Vec<Task>,
+}
+
+pub fn status_from_row(row: &Row) -> TaskStatus {
+    return TaskStatus {
+        id: row.get(0),
+        category_id: row.get(1),
+        title: row.get(2),
+        position: row.get(3),
+        is_done: row.get(4),
+    };
+}
+
+// Gives a category the default statuses if it has none: "To do" and "Done", matching the
+// old is_complete split.
+pub async fn ensure_statuses<C: GenericClient>(client: &C, category_id: i32) -> Result<(), Error> {
+    client
+        .execute(
+            "INSERT INTO public.task_status (category_id, title, position, is_done)
+            SELECT $1, s.title, s.position, s.is_done
+            FROM (VALUES ('To do', 0, false), ('Done', 1, true)) AS s(title, position, is_done)
+            WHERE NOT EXISTS (SELECT 1 FROM public.task_status WHERE category_id = $1)
+            ON CONFLICT (category_id, title) DO NOTHING",
+            &[&category_id],
+        )
+        .await?;
+    return Ok(());
+}
+
+pub async fn get_statuses<C: GenericClient>(
+    client: &C,
+    category_id: i32,
+) -> Result<Vec<TaskStatus>, Error> {
+    let mut statuses = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task_status WHERE category_id=$1 ORDER BY position, id",
+            &[&category_id],
+        )
+        .await?
+    {
+        statuses.push(status_from_row(&row));
+    }
+    return Ok(statuses);
+}
+
+// Makes the task's status agree with its category and is_complete, recording the move when
+// it changes. `wanted` is used when it fits; otherwise the current status is kept if it
+// still fits, and failing that the first status on the right side of done. completed_at
+// is stamped the first time the task is seen complete and cleared when it's reopened, and
+// the category's follows along.
+pub async fn reconcile<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+    wanted: Option<i32>,
+) -> Result<Option<i32>, Error> {
+    let Some(row) = client
+        .query_opt(
+            "SELECT category_id, is_complete, status_id FROM public.task WHERE id=$1",
+            &[&task_id],
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+    let category_id: i32 = row.get(0);
+    let is_complete: bool = row.get(1);
+    let current: Option<i32> = row.get(2);
+
+    ensure_statuses(client, category_id).await?;
+    let statuses = get_statuses(client, category_id).await?;
+    let fits = |id: Option<i32>| {
+        return statuses
+            .iter()
+            .find(|status| Some(status.id) == id && status.is_done == is_complete);
+    };
+    let Some(target) = fits(wanted)
+        .or(fits(current))
+        .or(statuses.iter().find(|status| status.is_done == is_complete))
+        .or(statuses.first())
+    else {
+        return Ok(current);
+    };
+
+    if Some(target.id) != current {
+        client
+            .execute(
+                "UPDATE public.task SET status_id = $1, is_complete = $2 WHERE id = $3",
+                &[&target.id, &target.is_done, &task_id],
+            )
+            .await?;
+        client
+            .execute(
+                "INSERT INTO public.task_status_change
+                    (task_id, from_status_id, to_status_id, changed_at)
+                VALUES ($1, $2, $3, $4)",
+                &[&task_id, &current, &target.id, &time_entry::now()],
+            )
+            .await?;
+    }
+    client
+        .execute(
+            "UPDATE public.task
+            SET completed_at = CASE WHEN is_complete THEN COALESCE(completed_at, $1) END
+            WHERE id = $2",
+            &[&time_entry::now(), &task_id],
+        )
+        .await?;
+    category::stamp_completed(client, category_id).await?;
+    return Ok(Some(target.id));
+}
+
+// The status a save moves the task into, if it's a move into one of the category's
+// statuses. Moving a task into a status is what decides whether it's complete; a save
+// that leaves the status alone goes by is_complete instead.
+pub async fn requested<C: GenericClient>(
+    client: &C,
+    category_id: i32,
+    status_id: Option<i32>,
+    stored: Option<i32>,
+) -> Result<Option<TaskStatus>, String> {
+    let Some(status_id) = status_id.filter(|id| Some(*id) != stored) else {
+        return Ok(None);
+    };
+    let row = client
+        .query_opt(
+            "SELECT * FROM public.task_status WHERE id=$1",
+            &[&status_id],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+    match row.map(|row| status_from_row(&row)) {
+        Some(status) if status.category_id == category_id => return Ok(Some(status)),
+        // A task moving category can't keep its old column; it lands in the new one's.
+        Some(_) => return Ok(None),
+        None => return Err(format!("status {} doesn't exist", status_id)),
+    }
+}
+
+// Whether `status` is the only one of its category on its side of done. A category keeps
+// at least one of each, so there's always a column for open and for finished tasks.
+async fn last_on_its_side<C: GenericClient>(
+    client: &C,
+    status: &TaskStatus,
+) -> Result<bool, Error> {
+    let row = client
+        .query_one(
+            "SELECT COUNT(*) FROM public.task_status WHERE category_id = $1 AND is_done = $2",
+            &[&status.category_id, &status.is_done],
+        )
+        .await?;
+    return Ok(row.get::<_, i64>(0) <= 1);
+}
+
+fn last_status_conflict(status: &TaskStatus) -> HttpResponse {
+    let side = if status.is_done { "done" } else { "not done" };
+    return HttpResponse::Conflict()
+        .content_type("application/json")
+        .json(format!(
+            "'{}' is the category's last {} status",
+            status.title, side
+        ));
+}
+
+// #[get("/category/{id}/statuses")]
+pub async fn get_category_statuses(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    ensure_statuses(&client, info.id)
+        .await
+        .expect("error adding statuses");
+    let statuses = get_statuses(&client, info.id)
+        .await
+        .expect("error getting statuses");
+
+    return HttpResponse::Ok().json(statuses);
+}
+
+// #[post("/category/{id}/status")]
+// Adds a column, at the end unless a position is given, in which case the columns from
+// there on shift along.
+pub async fn create_status(
+    _req: HttpRequest,
+    params: web::Json<StatusRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    ensure_statuses(&client, info.id)
+        .await
+        .expect("error adding statuses");
+    client
+        .execute(
+            "UPDATE public.task_status SET position = position + 1
+            WHERE category_id = $1 AND position >= $2",
+            &[&info.id, &params.position],
+        )
+        .await
+        .expect("error moving statuses");
+
+    match client
+        .query_one(
+            "INSERT INTO public.task_status (category_id, title, position, is_done)
+            SELECT $1, $2, COALESCE($3, MAX(position) + 1, 0), $4
+            FROM public.task_status WHERE category_id = $1
+            RETURNING *",
+            &[&info.id, &params.title, &params.position, &params.is_done],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(status_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[put("/status/{id}")]
+// Renames, reorders or flips `is_done`. Tasks in the status follow a flip of `is_done`,
+// each saved like any other task edit so it gets its completed_at and history.
+pub async fn update_status(
+    req: HttpRequest,
+    params: web::Json<StatusRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(current) = transaction
+        .query_opt(
+            "SELECT * FROM public.task_status WHERE id=$1 FOR UPDATE",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting status")
+        .map(|row| status_from_row(&row))
+    else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json("no such status");
+    };
+    let flipped = current.is_done != params.is_done;
+    if flipped
+        && last_on_its_side(&transaction, &current)
+            .await
+            .expect("error getting statuses")
+    {
+        return last_status_conflict(&current);
+    }
+
+    let row = match transaction
+        .query_one(
+            "UPDATE public.task_status
+            SET title = $1,
+                position = COALESCE($2, position),
+                is_done = $3
+            WHERE id = $4 RETURNING *",
+            &[&params.title, &params.position, &params.is_done, &info.id],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    };
+
+    if flipped {
+        let task_ids: Vec<i32> = transaction
+            .query(
+                "SELECT id FROM public.task WHERE status_id=$1 AND deleted_at IS NULL",
+                &[&info.id],
+            )
+            .await
+            .expect("error getting tasks")
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for task_id in task_ids {
+            let Some(mut task) = history::snapshot(&transaction, task_id)
+                .await
+                .expect("error getting task")
+            else {
+                continue;
+            };
+            task.is_complete = params.is_done;
+            let actor_id = history::actor(&req, task.owner_id);
+            save_and_complete(&transaction, &mut task, actor_id)
+                .await
+                .expect("error updating task");
+        }
+        // Tasks in the trash just follow along. They get their history when they're restored
+        // and saved again.
+        transaction
+            .execute(
+                "UPDATE public.task
+                SET is_complete = $1,
+                    completed_at = CASE WHEN $1 THEN COALESCE(completed_at, $3) END
+                WHERE status_id = $2 AND deleted_at IS NOT NULL",
+                &[&params.is_done, &info.id, &time_entry::now()],
+            )
+            .await
+            .expect("error updating tasks");
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Created()
+        .content_type("application/json")
+        .json(status_from_row(&row));
+}
+
+// #[delete("/status/{id}")]
+// Tasks in the deleted status move to the first remaining one on the same side of done. The
+// last status on either side can't be deleted.
+pub async fn delete_status(info: Path<InfoPathId>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(status) = transaction
+        .query_opt(
+            "SELECT * FROM public.task_status WHERE id=$1 FOR UPDATE",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting status")
+        .map(|row| status_from_row(&row))
+    else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json("no such status");
+    };
+    if last_on_its_side(&transaction, &status)
+        .await
+        .expect("error getting statuses")
+    {
+        return last_status_conflict(&status);
+    }
+
+    let task_ids: Vec<i32> = transaction
+        .query("SELECT id FROM public.task WHERE status_id=$1", &[&info.id])
+        .await
+        .expect("error getting tasks")
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    transaction
+        .execute("DELETE FROM public.task_status WHERE id=$1", &[&info.id])
+        .await
+        .expect("error deleting status");
+    for task_id in task_ids {
+        reconcile(&transaction, task_id, None)
+            .await
+            .expect("error moving task");
+    }
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
+
+// #[get("/category/{id}/board")]
+// The category's tasks grouped into their status columns, in column order.
+pub async fn get_board(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    ensure_statuses(&client, info.id)
+        .await
+        .expect("error adding statuses");
+    let statuses = get_statuses(&client, info.id)
+        .await
+        .expect("error getting statuses");
+
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE category_id=$1 AND deleted_at IS NULL ORDER BY rank NULLS LAST, id",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    annotate_tasks(&client, &mut tasks).await;
+
+    let mut columns: Vec<BoardColumn> = Vec::new();
+    for status in statuses {
+        let (in_column, rest): (Vec<Task>, Vec<Task>) = tasks
+            .into_iter()
+            .partition(|task| task.status_id == Some(status.id));
+        tasks = rest;
+        columns.push(BoardColumn {
+            status,
+            tasks: in_column,
+        });
+    }
+    // Anything not placed yet goes in the first column on its side of done.
+    for task in tasks {
+        if let Some(column) = columns
+            .iter_mut()
+            .find(|column| column.status.is_done == task.is_complete)
+        {
+            column.tasks.push(task);
+        }
+    }
+
+    return HttpResponse::Ok().json(columns);
+}
+
+// #[get("/task/{id}/transitions")]
+// Every status the task moved through, oldest first.
+pub async fn get_transitions(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut changes: Vec<StatusChange> = Vec::new();
+    for row in client
+        .query(
+            "SELECT task_id, from_status_id, to_status_id, changed_at
+            FROM public.task_status_change WHERE task_id=$1 ORDER BY changed_at, id",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting transitions")
+    {
+        changes.push(StatusChange {
+            task_id: row.get(0),
+            from_status_id: row.get(1),
+            to_status_id: row.get(2),
+            changed_at: row.get(3),
+        });
+    }
+
+    return HttpResponse::Ok().json(changes);
+}
@@ -0,0 +1,213 @@
+use crate::{
+    connect::connect_to_db,
+    task::{annotate_tasks, task_from_row, InfoPathId, Task},
+};
+use actix_web::{web::Path, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio_postgres::{Error, GenericClient};
+
+// A task with its subtasks nested under it. `done` and `total` count every task below it,
+// however deep.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TaskNode {
+    #[serde(flatten)]
+    pub task: Task,
+    pub done: usize,
+    pub total: usize,
+    pub subtasks: Vec<TaskNode>,
+}
+
+fn node(task: Task, children: &mut HashMap<i32, Vec<Task>>) -> TaskNode {
+    let subtasks: Vec<TaskNode> = children
+        .remove(&task.id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|child| node(child, children))
+        .collect();
+    let done = subtasks
+        .iter()
+        .map(|sub| sub.done + sub.task.is_complete as usize)
+        .sum();
+    let total = subtasks.iter().map(|sub| sub.total + 1).sum();
+
+    return TaskNode {
+        task,
+        done,
+        total,
+        subtasks,
+    };
+}
+
+// Nests the tasks under their parents, keeping their order. A task whose parent isn't in
+// the list, say because a filter left it out, becomes a root.
+pub fn build_tree(tasks: Vec<Task>) -> Vec<TaskNode> {
+    let ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+    let mut roots = Vec::new();
+    let mut children: HashMap<i32, Vec<Task>> = HashMap::new();
+    for task in tasks {
+        match task.parent_id.filter(|parent_id| ids.contains(parent_id)) {
+            Some(parent_id) => children.entry(parent_id).or_default().push(task),
+            None => roots.push(task),
+        }
+    }
+    return roots
+        .into_iter()
+        .map(|task| node(task, &mut children))
+        .collect();
+}
+
+// A subtask whose parent is in the trash keeps its parent_id, so restoring the parent puts
+// it back underneath, but reads as a top level task until then.
+pub async fn hide_trashed_parents<C: GenericClient>(client: &C, tasks: &mut [Task]) {
+    let parent_ids: Vec<i32> = tasks.iter().filter_map(|task| task.parent_id).collect();
+    if parent_ids.is_empty() {
+        return;
+    }
+    let mut trashed: Vec<i32> = Vec::new();
+    for row in client
+        .query(
+            "SELECT id FROM public.task WHERE id = ANY($1) AND deleted_at IS NOT NULL",
+            &[&parent_ids],
+        )
+        .await
+        .expect("error getting parents")
+    {
+        trashed.push(row.get(0));
+    }
+    for task in tasks.iter_mut() {
+        if task.parent_id.is_some_and(|parent_id| trashed.contains(&parent_id)) {
+            task.parent_id = None;
+        }
+    }
+}
+
+// The task's stored parent, if that parent is in the trash.
+pub async fn trashed_parent<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+) -> Result<Option<i32>, Error> {
+    let row = client
+        .query_opt(
+            "SELECT parent.id FROM public.task
+            JOIN public.task parent ON parent.id = task.parent_id
+            WHERE task.id = $1 AND parent.deleted_at IS NOT NULL",
+            &[&task_id],
+        )
+        .await?;
+    return Ok(row.map(|row| row.get(0)));
+}
+
+// Ids of every task below `task_id`.
+pub async fn descendants<C: GenericClient>(client: &C, task_id: i32) -> Result<Vec<i32>, Error> {
+    let mut ids = Vec::new();
+    for row in client
+        .query(
+            "WITH RECURSIVE subtree AS (
+                SELECT id FROM public.task WHERE parent_id = $1
+                UNION
+                SELECT task.id FROM public.task JOIN subtree ON task.parent_id = subtree.id
+            ) SELECT id FROM subtree",
+            &[&task_id],
+        )
+        .await?
+    {
+        ids.push(row.get(0));
+    }
+    return Ok(ids);
+}
+
+// Checks a task's parent and returns the parent's category, which the task has to move
+// to since a subtree always lives in one category. A task can't sit under itself or its
+// own subtasks. `task_id` is None for a task that's still to be created.
+pub async fn parent_category<C: GenericClient>(
+    client: &C,
+    task_id: Option<i32>,
+    owner_id: i32,
+    parent_id: Option<i32>,
+) -> Result<Option<i32>, String> {
+    let Some(parent_id) = parent_id else {
+        return Ok(None);
+    };
+    if task_id == Some(parent_id) {
+        return Err("a task can't be its own parent".to_string());
+    }
+
+    let parent = match client
+        .query_opt(
+            "SELECT * FROM public.task WHERE id=$1 AND deleted_at IS NULL",
+            &[&parent_id],
+        )
+        .await
+        .map_err(|err| err.to_string())?
+    {
+        Some(row) => task_from_row(&row),
+        None => return Err(format!("parent task {} doesn't exist", parent_id)),
+    };
+    if parent.owner_id != owner_id {
+        return Err("the parent task belongs to someone else".to_string());
+    }
+
+    if let Some(task_id) = task_id {
+        let below = descendants(client, task_id)
+            .await
+            .map_err(|err| err.to_string())?;
+        if below.contains(&parent_id) {
+            return Err("the parent task is one of this task's subtasks".to_string());
+        }
+    }
+    return Ok(Some(parent.category_id));
+}
+
+// Brings every subtask along when a task changes category.
+pub async fn move_subtree<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+    category_id: i32,
+) -> Result<u64, Error> {
+    let ids = descendants(client, task_id).await?;
+    return client
+        .execute(
+            "UPDATE public.task SET category_id = $1
+            WHERE id = ANY($2) AND category_id <> $1",
+            &[&category_id, &ids],
+        )
+        .await;
+}
+
+// #[get("/task/{id}/tree")]
+// The task with all its subtasks and how many of them are done.
+pub async fn get_task_tree(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut ids = descendants(&client, info.id)
+        .await
+        .expect("error getting subtasks");
+    ids.push(info.id);
+
+    let mut tasks: Vec<Task> = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE id = ANY($1) AND deleted_at IS NULL ORDER BY priority ASC, id",
+            &[&ids],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        tasks.push(task_from_row(&row));
+    }
+    annotate_tasks(&client, &mut tasks).await;
+
+    let tree = build_tree(tasks)
+        .into_iter()
+        .find(|node| node.task.id == info.id);
+    match tree {
+        Some(tree) => return HttpResponse::Ok().json(tree),
+        None => {
+            return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json("no such task");
+        }
+    }
+}
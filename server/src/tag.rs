@@ -0,0 +1,328 @@
+use crate::{connect::connect_to_db, task::Task};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio_postgres::{Error, GenericClient, Row};
+
+const DEFAULT_TAG_COLOR: &str = "#888888";
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathId {
+    id: i32,
+}
+
+// A label like `@phone` or `waiting-for`. Tags belong to one user and cut across their
+// categories.
+#[derive(Serialize, Deserialize)]
+pub struct Tag {
+    pub id: i32,
+    pub owner_id: i32,
+    pub name: String,
+    pub color: String,
+}
+
+// A tag with how many tasks carry it, and how many of those are still open.
+#[derive(Serialize, Deserialize)]
+pub struct TagCount {
+    #[serde(flatten)]
+    pub tag: Tag,
+    pub task_count: i64,
+    pub open_count: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagRequest {
+    owner_id: i32,
+    name: String,
+    #[serde(default)]
+    color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagUpdate {
+    name: String,
+    color: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MergeRequest {
+    into: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerQuery {
+    owner_id: i32,
+}
+
+pub fn tag_from_row(row: &Row) -> Tag {
+    return Tag {
+        id: row.get(0),
+        owner_id: row.get(1),
+        name: row.get(2),
+        color: row.get(3),
+    };
+}
+
+fn clean_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("tag names can't be empty".to_string());
+    }
+    return Ok(name.to_string());
+}
+
+pub fn check_names(names: &[String]) -> Result<(), String> {
+    for name in names {
+        clean_name(name)?;
+    }
+    return Ok(());
+}
+
+// Fills in each task's tag names, sorted.
+pub async fn load_tags<C: GenericClient>(client: &C, tasks: &mut [Task]) {
+    let ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+    let mut names: HashMap<i32, Vec<String>> = HashMap::new();
+    for row in client
+        .query(
+            "SELECT tt.task_id, tag.name
+            FROM public.task_tag tt
+            JOIN public.tag ON tag.id = tt.tag_id
+            WHERE tt.task_id = ANY($1)
+            ORDER BY tag.name",
+            &[&ids],
+        )
+        .await
+        .expect("error getting tags")
+    {
+        names.entry(row.get(0)).or_default().push(row.get(1));
+    }
+    for task in tasks.iter_mut() {
+        task.tags = Some(names.remove(&task.id).unwrap_or_default());
+    }
+}
+
+// Replaces the task's tags with `names`, creating any the owner doesn't have yet.
+pub async fn set_task_tags<C: GenericClient>(
+    client: &C,
+    task_id: i32,
+    owner_id: i32,
+    names: &[String],
+) -> Result<(), Error> {
+    let names: Vec<String> = names
+        .iter()
+        .filter_map(|name| clean_name(name).ok())
+        .collect();
+
+    client
+        .execute(
+            "INSERT INTO public.tag (owner_id, name, color)
+            SELECT $1, name, $3 FROM unnest($2::VARCHAR[]) AS name
+            ON CONFLICT (owner_id, name) DO NOTHING",
+            &[&owner_id, &names, &DEFAULT_TAG_COLOR],
+        )
+        .await?;
+    client
+        .execute("DELETE FROM public.task_tag WHERE task_id=$1", &[&task_id])
+        .await?;
+    client
+        .execute(
+            "INSERT INTO public.task_tag (task_id, tag_id)
+            SELECT $1, id FROM public.tag WHERE owner_id=$2 AND name = ANY($3)",
+            &[&task_id, &owner_id, &names],
+        )
+        .await?;
+    return Ok(());
+}
+
+// #[get("/tags")]
+pub async fn get_tags(query: web::Query<OwnerQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut tags: Vec<TagCount> = Vec::new();
+    for row in client
+        .query(
+            "SELECT tag.*,
+                COUNT(task.id),
+                COUNT(task.id) FILTER (WHERE NOT task.is_complete)
+            FROM public.tag
+            LEFT JOIN public.task_tag tt ON tt.tag_id = tag.id
+            LEFT JOIN public.task ON task.id = tt.task_id AND task.deleted_at IS NULL
+            WHERE tag.owner_id=$1
+            GROUP BY tag.id
+            ORDER BY tag.name",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting tags")
+    {
+        tags.push(TagCount {
+            tag: tag_from_row(&row),
+            task_count: row.get(4),
+            open_count: row.get(5),
+        });
+    }
+
+    return HttpResponse::Ok().json(tags);
+}
+
+// #[post("/tag")]
+pub async fn create_tag(_req: HttpRequest, params: web::Json<TagRequest>) -> impl Responder {
+    let name = match clean_name(&params.name) {
+        Ok(name) => name,
+        Err(msg) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    };
+    let color = params
+        .color
+        .to_owned()
+        .unwrap_or(DEFAULT_TAG_COLOR.to_string());
+
+    let client = connect_to_db().await;
+
+    match client
+        .query_one(
+            "INSERT INTO public.tag (owner_id, name, color) VALUES ($1, $2, $3) RETURNING *",
+            &[&params.owner_id, &name, &color],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(tag_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[put("/tag/{id}")]
+// Renames or recolors a tag. Renaming onto another of the owner's tags is refused; merge
+// them instead.
+pub async fn update_tag(
+    _req: HttpRequest,
+    params: web::Json<TagUpdate>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let name = match clean_name(&params.name) {
+        Ok(name) => name,
+        Err(msg) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    };
+
+    let client = connect_to_db().await;
+
+    match client
+        .query_opt(
+            "UPDATE public.tag SET name = $1, color = $2 WHERE id = $3 RETURNING *",
+            &[&name, &params.color, &info.id],
+        )
+        .await
+    {
+        Ok(Some(row)) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(tag_from_row(&row));
+        }
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json("no such tag");
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[post("/tag/{id}/merge")]
+// Moves every task from this tag onto `into` and deletes this one.
+pub async fn merge_tag(
+    _req: HttpRequest,
+    params: web::Json<MergeRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    if params.into == info.id {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("a tag can't be merged into itself");
+    }
+
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let owners: Vec<i32> = transaction
+        .query(
+            "SELECT owner_id FROM public.tag WHERE id = ANY($1)",
+            &[&vec![info.id, params.into]],
+        )
+        .await
+        .expect("error getting tags")
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    if owners.len() != 2 {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json("no such tag");
+    }
+    if owners[0] != owners[1] {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("tags belong to different users");
+    }
+
+    transaction
+        .execute(
+            "INSERT INTO public.task_tag (task_id, tag_id)
+            SELECT task_id, $2 FROM public.task_tag WHERE tag_id = $1
+            ON CONFLICT DO NOTHING",
+            &[&info.id, &params.into],
+        )
+        .await
+        .expect("error merging tags");
+    transaction
+        .execute("DELETE FROM public.tag WHERE id=$1", &[&info.id])
+        .await
+        .expect("error deleting tag");
+
+    let row = transaction
+        .query_one("SELECT * FROM public.tag WHERE id=$1", &[&params.into])
+        .await
+        .expect("error getting tag");
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(tag_from_row(&row));
+}
+
+// #[delete("/tag/{id}")]
+pub async fn delete_tag(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    client
+        .execute("DELETE FROM public.tag WHERE id=$1", &[&info.id])
+        .await
+        .expect("error deleting tag");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
@@ -1,17 +1,21 @@
-use crate::connect::connect_to_db;
+use crate::{
+    connect::connect_to_db, dependency, history, rank, series, status, subtask, tag, time_entry,
+};
 use actix_web::{
     web::Path,
     web::{self},
     HttpRequest, HttpResponse, Responder,
 };
+use chrono::{Datelike, Duration as Days, NaiveDateTime};
 use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, GenericClient, Row};
 // mod connect;
 #[derive(Serialize, Deserialize)]
 pub struct InfoPathId {
     pub id: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Task {
     pub id: i32,
     pub title: String,
@@ -20,6 +24,58 @@ pub struct Task {
     pub priority: i32,
     pub owner_id: i32,
     pub category_id: i32,
+    #[serde(default)]
+    pub estimate_minutes: Option<i32>,
+    // When the task has to be done by, and when it's worth starting. Both are server local
+    // times like the rest of the schedule.
+    #[serde(default)]
+    pub due_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub start_at: Option<NaiveDateTime>,
+    // Repeats on a fixed RRULE or this many days after completion. Completing an instance
+    // adds the next one, and every instance points at the first through `series_id`.
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub repeat_after_days: Option<i32>,
+    #[serde(default)]
+    pub series_id: Option<i32>,
+    // The task this is a subtask of. Subtasks share their parent's category.
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+    // Whether a task this one waits on is still open. Worked out from the dependencies
+    // rather than stored.
+    #[serde(default)]
+    pub is_blocked: bool,
+    // Tag names. A save without them leaves the task's tags as they are.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    // The column the task sits in on its category's board. See `status`.
+    #[serde(default)]
+    pub status_id: Option<i32>,
+    // Manual order within the category, set by `POST /task/{id}/move`. Separate from
+    // priority, which is how important the task is.
+    #[serde(default)]
+    pub rank: Option<f64>,
+    // Kept by the server: when the task was added, last edited and last completed.
+    // completed_at is cleared again when the task is reopened.
+    #[serde(default)]
+    pub created_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub updated_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub completed_at: Option<NaiveDateTime>,
+    // Set while the task is in the trash.
+    #[serde(default)]
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+// A saved task along with the tasks that completing it unblocked.
+#[derive(Serialize, Deserialize)]
+pub struct UpdatedTask {
+    #[serde(flatten)]
+    pub task: Task,
+    pub unblocked: Vec<Task>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,31 +86,332 @@ pub struct TaskRequest {
     priority: i32,
     owner_id: i32,
     category_id: i32,
+    #[serde(default)]
+    estimate_minutes: Option<i32>,
+    #[serde(default)]
+    due_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    start_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    rrule: Option<String>,
+    #[serde(default)]
+    repeat_after_days: Option<i32>,
+    #[serde(default)]
+    parent_id: Option<i32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    status_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteQuery {
+    // `cascade` deletes the subtasks too, `orphan` (the default) keeps them as top level tasks.
+    #[serde(default)]
+    children: Option<String>,
+}
+
+// `filter` is one of overdue, due_today or due_this_week. `hide_deferred` leaves out tasks
+// whose start_at hasn't come yet and `hide_blocked` ones still waiting on another task.
+// `tags` is a comma separated list of tag names the tasks must all carry. `sort` is
+// priority, due, urgency or rank. `tree` nests subtasks under their parents.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TaskQuery {
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub hide_deferred: bool,
+    #[serde(default)]
+    pub hide_blocked: bool,
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub tree: bool,
+}
+
+pub fn task_from_row(row: &Row) -> Task {
+    return Task {
+        id: row.get(0),
+        title: row.get(1),
+        description: row.try_get(2).expect("value was null"),
+        is_complete: row.get(3),
+        priority: row.get(4),
+        owner_id: row.get(5),
+        category_id: row.get(6),
+        estimate_minutes: row.get(7),
+        due_at: row.get(8),
+        start_at: row.get(9),
+        rrule: row.get(10),
+        repeat_after_days: row.get(11),
+        series_id: row.get(12),
+        parent_id: row.get(13),
+        is_blocked: false,
+        tags: None,
+        status_id: row.get(14),
+        rank: row.get(15),
+        created_at: row.get(16),
+        updated_at: row.get(17),
+        completed_at: row.get(18),
+        deleted_at: row.get(19),
+    };
+}
+
+impl Task {
+    pub fn is_overdue(&self, now: NaiveDateTime) -> bool {
+        return !self.is_complete && self.due_at.is_some_and(|due_at| due_at < now);
+    }
+
+    pub fn is_deferred(&self, now: NaiveDateTime) -> bool {
+        return self.start_at.is_some_and(|start_at| start_at > now);
+    }
+
+    // Priority with a boost as the due date gets close: 3 when overdue, 2 when due within a
+    // day and 1 when due within a week. Lower comes first, like priority.
+    pub fn urgency(&self, now: NaiveDateTime) -> i32 {
+        let boost = match self.due_at {
+            _ if self.is_overdue(now) => 3,
+            Some(due_at) if due_at <= now + Days::days(1) => 2,
+            Some(due_at) if due_at <= now + Days::days(7) => 1,
+            _ => 0,
+        };
+        return self.priority - boost;
+    }
+}
+
+fn check_dates(
+    due_at: Option<NaiveDateTime>,
+    start_at: Option<NaiveDateTime>,
+) -> Result<(), String> {
+    match (start_at, due_at) {
+        (Some(start_at), Some(due_at)) if start_at > due_at => {
+            return Err("start_at must not be after due_at".to_string());
+        }
+        _ => return Ok(()),
+    }
+}
+
+fn check_task(task: &Task) -> Result<(), String> {
+    check_dates(task.due_at, task.start_at)?;
+    tag::check_names(task.tags.as_deref().unwrap_or_default())?;
+    return series::check_rule(task.rrule.as_deref(), task.repeat_after_days);
+}
+
+fn matches_filter(task: &Task, filter: &str, now: NaiveDateTime) -> Result<bool, String> {
+    let today = now.date();
+    // The rest of the week, up to and including Sunday.
+    let week_end = today + Days::days(6 - today.weekday().num_days_from_monday() as i64);
+    let due_on = task.due_at.map(|due_at| due_at.date());
+
+    match filter {
+        "overdue" => return Ok(task.is_overdue(now)),
+        "due_today" => return Ok(due_on == Some(today)),
+        "due_this_week" => return Ok(due_on.is_some_and(|date| today <= date && date <= week_end)),
+        _ => return Err(format!("unknown filter '{}'", filter)),
+    }
+}
+
+// Sorts tasks for `sort=priority`, `sort=due` (soonest first, undated last),
+// `sort=urgency` or `sort=rank` (the manual order). Ties fall back to the other keys and
+// then the id.
+pub fn sort_tasks(tasks: &mut [Task], sort: &str, now: NaiveDateTime) -> Result<(), String> {
+    let due = |task: &Task| (task.due_at.is_none(), task.due_at);
+    match sort {
+        "priority" => tasks.sort_by_key(|task| (task.priority, due(task), task.id)),
+        "due" => tasks.sort_by_key(|task| (due(task), task.priority, task.id)),
+        "urgency" => tasks.sort_by_key(|task| (task.urgency(now), due(task), task.id)),
+        "rank" => tasks.sort_by(|a, b| {
+            let rank = |task: &Task| task.rank.unwrap_or(f64::MAX);
+            return rank(a).total_cmp(&rank(b)).then(a.id.cmp(&b.id));
+        }),
+        _ => return Err(format!("unknown sort '{}'", sort)),
+    }
+    return Ok(());
+}
+
+// Narrows and orders the tasks the way `/tasks` and `/categories` were asked to. Without a
+// sort the order is left as it came from the database.
+pub fn apply_query(
+    tasks: &mut Vec<Task>,
+    query: &TaskQuery,
+    now: NaiveDateTime,
+) -> Result<(), String> {
+    if let Some(filter) = query.filter.as_deref() {
+        let mut kept = Vec::new();
+        for task in tasks.drain(..) {
+            if matches_filter(&task, filter, now)? {
+                kept.push(task);
+            }
+        }
+        *tasks = kept;
+    }
+    if query.hide_deferred {
+        tasks.retain(|task| !task.is_deferred(now));
+    }
+    if query.hide_blocked {
+        tasks.retain(|task| !task.is_blocked);
+    }
+    if let Some(wanted) = query.tags.as_deref() {
+        let wanted: Vec<&str> = wanted.split(',').map(|name| name.trim()).collect();
+        tasks.retain(|task| {
+            let tags = task.tags.as_deref().unwrap_or_default();
+            return wanted.iter().all(|name| tags.iter().any(|tag| tag == name));
+        });
+    }
+    if let Some(sort) = query.sort.as_deref() {
+        sort_tasks(tasks, sort, now)?;
+    }
+    return Ok(());
+}
+
+// Fills in what isn't stored on the task row itself: whether it's blocked and its tags.
+// Parents in the trash are left out.
+pub async fn annotate_tasks<C: GenericClient>(client: &C, tasks: &mut [Task]) {
+    dependency::mark_blocked(client, tasks).await;
+    tag::load_tags(client, tasks).await;
+    subtask::hide_trashed_parents(client, tasks).await;
+}
+
+// Writes every editable field of an existing task. The series link is kept by the server,
+// and so is the series' start: it's set again only when the rule changes.
+pub async fn save_task<C: GenericClient>(client: &C, task: &Task) -> Result<u64, Error> {
+    return client
+        .execute(
+            "UPDATE public.task
+            SET title = $1,
+                description = $2,
+                is_complete = $3,
+                priority = $4,
+                owner_id = $5,
+                category_id = $6,
+                estimate_minutes = $7,
+                due_at = $8,
+                start_at = $9,
+                rrule = $10,
+                repeat_after_days = $11,
+                parent_id = $12,
+                series_dtstart = CASE
+                    WHEN $10 IS NULL THEN NULL
+                    WHEN rrule = $10 AND series_dtstart IS NOT NULL THEN series_dtstart
+                    ELSE COALESCE($8, $14)
+                END
+             WHERE id = $13",
+            &[
+                &task.title,
+                &task.description,
+                &task.is_complete,
+                &task.priority,
+                &task.owner_id,
+                &task.category_id,
+                &task.estimate_minutes,
+                &task.due_at,
+                &task.start_at,
+                &task.rrule,
+                &task.repeat_after_days,
+                &task.parent_id,
+                &task.id,
+                &time_entry::now(),
+            ],
+        )
+        .await;
+}
+
+// Settles where a task being saved ends up: in its parent's category, and complete or not
+// according to the status it's being moved into.
+async fn place_task<C: GenericClient>(client: &C, task: &mut Task) -> Result<(), String> {
+    let category_id =
+        subtask::parent_category(client, Some(task.id), task.owner_id, task.parent_id).await?;
+    task.category_id = category_id.unwrap_or(task.category_id);
+    // The client never sees a parent that's in the trash, so it can't send it back.
+    if task.parent_id.is_none() {
+        task.parent_id = subtask::trashed_parent(client, task.id)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    let stored: Option<i32> = client
+        .query_opt("SELECT status_id FROM public.task WHERE id=$1", &[&task.id])
+        .await
+        .map_err(|err| err.to_string())?
+        .and_then(|row| row.get(0));
+    let status = status::requested(client, task.category_id, task.status_id, stored).await?;
+    if let Some(status) = status {
+        task.is_complete = status.is_done;
+    }
+    return Ok(());
+}
+
+// Saves the task and brings its subtasks along to its category, settling everyone's
+// status, and records what changed in the task's history. When this save is what
+// completes the task, a recurring one gets its completion recorded and the next instance
+// added, and the tasks that were only waiting on this one come back as unblocked.
+pub async fn save_and_complete<C: GenericClient>(
+    client: &C,
+    task: &mut Task,
+    actor_id: i32,
+) -> Result<Vec<Task>, Error> {
+    client
+        .query_opt(
+            "SELECT id FROM public.task WHERE id=$1 FOR UPDATE",
+            &[&task.id],
+        )
+        .await?;
+    let before = history::snapshot(client, task.id).await?;
+    let was_complete = before.as_ref().map(|before| before.is_complete);
+
+    save_task(client, task).await?;
+    task.status_id = status::reconcile(client, task.id, task.status_id).await?;
+    if subtask::move_subtree(client, task.id, task.category_id).await? > 0 {
+        for id in subtask::descendants(client, task.id).await? {
+            status::reconcile(client, id, None).await?;
+        }
+    }
+    if let Some(tags) = &task.tags {
+        tag::set_task_tags(client, task.id, task.owner_id, tags).await?;
+    }
+
+    let Some(saved) = history::snapshot(client, task.id).await? else {
+        return Ok(Vec::new());
+    };
+    if let Some(before) = &before {
+        history::record(client, before, &saved, Some(actor_id)).await?;
+    }
+
+    if !task.is_complete || was_complete != Some(false) {
+        return Ok(Vec::new());
+    }
+    if series::is_recurring(&saved) {
+        series::complete(client, &saved).await?;
+    }
+    return dependency::newly_unblocked(client, task.id).await;
 }
 
 // #[get("/tasks")]
-pub async fn get_tasks() -> impl Responder {
+pub async fn get_tasks(query: web::Query<TaskQuery>) -> impl Responder {
     let client = connect_to_db().await;
     let mut tasks: Vec<Task> = Vec::new();
 
     for row in client
-        .query("SELECT * FROM task", &[])
+        .query("SELECT * FROM task WHERE deleted_at IS NULL", &[])
         .await
         .expect("error getting tasks")
     {
-        let task = Task {
-            id: row.get(0),
-            title: row.get(1),
-            description: row.try_get(2).expect("value was null"),
-            is_complete: row.get(3),
-            priority: row.get(4),
-            owner_id: row.get(5),
-            category_id: row.get(6),
-        };
+        let task = task_from_row(&row);
 
         tasks.push(task);
     }
 
+    annotate_tasks(&client, &mut tasks).await;
+    if let Err(msg) = apply_query(&mut tasks, &query, time_entry::now()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    if query.tree {
+        return HttpResponse::Ok().json(subtask::build_tree(tasks));
+    }
     return HttpResponse::Ok().json(tasks);
 }
 
@@ -68,42 +425,84 @@ pub async fn get_task_by_id(info: Path<InfoPathId>) -> impl Responder {
         .await
         .expect("error getting task");
 
-    let task = Task {
-        id: row.get(0),
-        title: row.get(1),
-        description: row.try_get(2).expect("value was null"),
-        is_complete: row.get(3),
-        priority: row.get(4),
-        owner_id: row.get(5),
-        category_id: row.get(6),
-    };
+    let mut task = [task_from_row(&row)];
+    annotate_tasks(&client, &mut task).await;
 
-    return HttpResponse::Ok().json(task);
+    return HttpResponse::Ok().json(&task[0]);
 }
 
 // #[post("/task")]
 pub async fn create_task(_req: HttpRequest, params: web::Json<TaskRequest>) -> impl Responder {
+    if let Err(msg) = check_dates(params.due_at, params.start_at)
+        .and_then(|_| series::check_rule(params.rrule.as_deref(), params.repeat_after_days))
+        .and_then(|_| tag::check_names(&params.tags))
+    {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
     let client = connect_to_db().await;
 
-    let task = TaskRequest {
+    let category_id =
+        match subtask::parent_category(&client, None, params.owner_id, params.parent_id).await {
+            Ok(category_id) => category_id.unwrap_or(params.category_id),
+            Err(msg) => {
+                return HttpResponse::BadRequest()
+                    .content_type("application/json")
+                    .json(msg);
+            }
+        };
+
+    let is_complete =
+        match status::requested(&client, category_id, params.status_id, None).await {
+            Ok(status) => status.map_or(params.is_complete, |status| status.is_done),
+            Err(msg) => {
+                return HttpResponse::BadRequest()
+                    .content_type("application/json")
+                    .json(msg);
+            }
+        };
+
+    let mut task = TaskRequest {
         title: params.title.to_owned(),
         description: params.description.to_owned(),
-        is_complete: params.is_complete,
+        is_complete,
         priority: params.priority,
         owner_id: params.owner_id,
-        category_id: params.category_id,
+        category_id,
+        estimate_minutes: params.estimate_minutes,
+        due_at: params.due_at,
+        start_at: params.start_at,
+        rrule: params.rrule.to_owned(),
+        repeat_after_days: params.repeat_after_days,
+        parent_id: params.parent_id,
+        tags: params.tags.to_owned(),
+        status_id: params.status_id,
     };
 
     match client
-        .execute(
+        .query_one(
             "INSERT INTO public.task (
                 title,
                 description,
                 is_complete,
                 priority,
                 owner_id,
-                category_id
-            ) VALUES ($1, $2, $3, $4, $5, $6)",
+                category_id,
+                estimate_minutes,
+                due_at,
+                start_at,
+                rrule,
+                repeat_after_days,
+                parent_id,
+                rank,
+                series_dtstart
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                (SELECT COALESCE(MAX(rank), 0) + $13 FROM public.task WHERE category_id = $6),
+                CASE WHEN $10 IS NULL THEN NULL ELSE COALESCE($8, $14) END
+            ) RETURNING id",
             &[
                 &task.title,
                 &task.description,
@@ -111,11 +510,28 @@ pub async fn create_task(_req: HttpRequest, params: web::Json<TaskRequest>) -> i
                 &task.priority,
                 &task.owner_id,
                 &task.category_id,
+                &task.estimate_minutes,
+                &task.due_at,
+                &task.start_at,
+                &task.rrule,
+                &task.repeat_after_days,
+                &task.parent_id,
+                &rank::RANK_STEP,
+                &time_entry::now(),
             ],
         )
         .await
     {
-        Ok(_data) => {
+        Ok(data) => {
+            let id: i32 = data.get(0);
+            if !task.tags.is_empty() {
+                tag::set_task_tags(&client, id, task.owner_id, &task.tags)
+                    .await
+                    .expect("error tagging task");
+            }
+            task.status_id = status::reconcile(&client, id, task.status_id)
+                .await
+                .expect("error setting status");
             return HttpResponse::Created()
                 .content_type("application/json")
                 .json(task);
@@ -129,10 +545,16 @@ pub async fn create_task(_req: HttpRequest, params: web::Json<TaskRequest>) -> i
 }
 
 // #[put("/task")]
-pub async fn update_task(_req: HttpRequest, params: web::Json<Task>) -> impl Responder {
-    let client = connect_to_db().await;
+pub async fn update_task(req: HttpRequest, params: web::Json<Task>) -> impl Responder {
+    if let Err(msg) = check_task(&params) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
 
-    let task = Task {
+    let mut client = connect_to_db().await;
+
+    let mut task = Task {
         id: params.id,
         title: params.title.to_owned(),
         description: params.description.to_owned(),
@@ -140,34 +562,57 @@ pub async fn update_task(_req: HttpRequest, params: web::Json<Task>) -> impl Res
         priority: params.priority,
         owner_id: params.owner_id,
         category_id: params.category_id,
+        estimate_minutes: params.estimate_minutes,
+        due_at: params.due_at,
+        start_at: params.start_at,
+        rrule: params.rrule.to_owned(),
+        repeat_after_days: params.repeat_after_days,
+        series_id: params.series_id,
+        parent_id: params.parent_id,
+        is_blocked: params.is_blocked,
+        tags: params.tags.to_owned(),
+        status_id: params.status_id,
+        rank: params.rank,
+        created_at: params.created_at,
+        updated_at: params.updated_at,
+        completed_at: params.completed_at,
+        deleted_at: params.deleted_at,
     };
 
-    match client
-        .execute(
-            "UPDATE public.task
-            SET title = $1,
-                description = $2,
-                is_complete = $3,
-                priority = $4,
-                owner_id = $5,
-                category_id = $6
-             WHERE id = $7",
-            &[
-                &task.title,
-                &task.description,
-                &task.is_complete,
-                &task.priority,
-                &task.owner_id,
-                &task.category_id,
-                &task.id,
-            ],
-        )
+    let transaction = client
+        .transaction()
         .await
-    {
-        Ok(_data) => {
+        .expect("error starting transaction");
+
+    if let Err(msg) = place_task(&transaction, &mut task).await {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    let actor_id = history::actor(&req, task.owner_id);
+    match save_and_complete(&transaction, &mut task, actor_id).await {
+        Ok(unblocked) => {
+            // The task as stored, with what the server filled in rather than what was sent.
+            let Some(row) = transaction
+                .query_opt("SELECT * FROM public.task WHERE id=$1", &[&task.id])
+                .await
+                .expect("error getting task")
+            else {
+                return HttpResponse::NotFound()
+                    .content_type("application/json")
+                    .json("no such task");
+            };
+            let mut saved = [task_from_row(&row)];
+            annotate_tasks(&transaction, &mut saved).await;
+            let [task] = saved;
+            transaction
+                .commit()
+                .await
+                .expect("error committing transaction");
             return HttpResponse::Created()
                 .content_type("application/json")
-                .json(task);
+                .json(UpdatedTask { task, unblocked });
         }
         Err(err) => {
             return HttpResponse::Conflict()
@@ -178,64 +623,76 @@ pub async fn update_task(_req: HttpRequest, params: web::Json<Task>) -> impl Res
 }
 
 // #[put("/tasks")]
-pub async fn update_many_tasks(_req: HttpRequest, params: web::Json<Vec<Task>>) -> impl Responder {
-    let client = connect_to_db().await;
+pub async fn update_many_tasks(req: HttpRequest, params: web::Json<Vec<Task>>) -> impl Responder {
+    let mut client = connect_to_db().await;
 
-    let tasks = params.0;
-
-    for i in 0..tasks.len() {
-        let statement = client
-            .prepare(
-                "UPDATE public.task
-                SET title = $1,
-                    description = $2,
-                    is_complete = $3,
-                    priority = $4,
-                    owner_id = $5,
-                    category_id = $6
-                WHERE id = $7",
-            )
-            .await
-            .expect("Could not prepare query.");
-
-        let parsed_task = Task {
-            id: tasks[i].id,
-            title: tasks[i].title.to_owned(),
-            description: tasks[i].description.to_owned(),
-            is_complete: tasks[i].is_complete,
-            priority: tasks[i].priority,
-            owner_id: tasks[i].owner_id,
-            category_id: tasks[i].category_id,
-        };
-        client
-            .execute(
-                &statement,
-                &[
-                    &parsed_task.title,
-                    &parsed_task.description,
-                    &parsed_task.is_complete,
-                    &parsed_task.priority,
-                    &parsed_task.owner_id,
-                    &parsed_task.category_id,
-                    &parsed_task.id,
-                ],
-            )
+    let mut tasks = params.0;
+
+    for task in tasks.iter() {
+        if let Err(msg) = check_task(task) {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+    }
+
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    for task in tasks.iter_mut() {
+        if let Err(msg) = place_task(&transaction, task).await {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(msg);
+        }
+        let actor_id = history::actor(&req, task.owner_id);
+        save_and_complete(&transaction, task, actor_id)
             .await
             .expect("error executing query");
     }
 
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
     return HttpResponse::Ok()
         .content_type("application/json")
         .json("{success: 200}");
 }
 
 // #[delete("/task/{id}")]
-pub async fn delete_task_by_id(info: Path<InfoPathId>) -> impl Responder {
-    let id = info.id;
+pub async fn delete_task_by_id(
+    info: Path<InfoPathId>,
+    query: web::Query<DeleteQuery>,
+) -> impl Responder {
     let client = connect_to_db().await;
 
+    let mut ids = vec![info.id];
+    match query.children.as_deref() {
+        Some("cascade") => ids.extend(
+            subtask::descendants(&client, info.id)
+                .await
+                .expect("error getting subtasks"),
+        ),
+        // The subtasks keep their parent_id and read as top level tasks while it's in the
+        // trash. See `subtask::hide_trashed_parents`.
+        None | Some("orphan") => {}
+        Some(other) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(format!("unknown children option '{}'", other));
+        }
+    }
+
+    // Into the trash, all with one deleted_at so a restore brings the subtasks back too.
     client
-        .execute("DELETE FROM public.task WHERE id=$1", &[&id])
+        .execute(
+            "UPDATE public.task SET deleted_at = $2 WHERE id = ANY($1) AND deleted_at IS NULL",
+            &[&ids, &time_entry::now()],
+        )
         .await
         .expect("error deleting task");
 
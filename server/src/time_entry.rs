@@ -0,0 +1,591 @@
+use crate::connect::connect_to_db;
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{Duration as Days, Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio_postgres::{GenericClient, Row};
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoPathId {
+    pub id: i32,
+}
+
+// Time actually spent, either on a task or on a category as a whole. A running timer is an
+// entry without `ended_at`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TimeEntry {
+    pub id: i32,
+    pub owner_id: i32,
+    pub task_id: Option<i32>,
+    pub category_id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimerRequest {
+    owner_id: i32,
+    #[serde(default)]
+    task_id: Option<i32>,
+    #[serde(default)]
+    category_id: Option<i32>,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerRequest {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OwnerQuery {
+    owner_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimeEntryRequest {
+    owner_id: i32,
+    #[serde(default)]
+    task_id: Option<i32>,
+    #[serde(default)]
+    category_id: Option<i32>,
+    started_at: NaiveDateTime,
+    ended_at: NaiveDateTime,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RangeQuery {
+    pub owner_id: i32,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TaskTotal {
+    pub task_id: Option<i32>,
+    pub title: Option<String>,
+    pub minutes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CategoryTotal {
+    pub category_id: i32,
+    pub title: String,
+    pub minutes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DayTotal {
+    pub date: NaiveDate,
+    pub minutes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimeReport {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub total_minutes: i64,
+    pub by_task: Vec<TaskTotal>,
+    pub by_category: Vec<CategoryTotal>,
+    pub by_day: Vec<DayTotal>,
+}
+
+pub fn time_entry_from_row(row: &Row) -> TimeEntry {
+    return TimeEntry {
+        id: row.get(0),
+        owner_id: row.get(1),
+        task_id: row.get(2),
+        category_id: row.get(3),
+        started_at: row.get(4),
+        ended_at: row.get(5),
+        note: row.get(6),
+    };
+}
+
+pub fn now() -> NaiveDateTime {
+    return Local::now().naive_local();
+}
+
+// The start of `from` up to the end of `to`, as the half open range entries are clipped to.
+pub fn day_range(from: NaiveDate, to: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+    return (
+        from.and_hms_opt(0, 0, 0).expect("valid time"),
+        (to + Days::days(1))
+            .and_hms_opt(0, 0, 0)
+            .expect("valid time"),
+    );
+}
+
+// Minutes of the entry that fall on each day between `from` and `to`. A running entry counts
+// up to `now`.
+pub fn minutes_by_day(
+    entry: &TimeEntry,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Vec<(NaiveDate, i64)> {
+    let mut start = entry.started_at.max(from);
+    let end = entry.ended_at.unwrap_or(now).min(to);
+
+    let mut days = Vec::new();
+    while start < end {
+        let midnight = (start.date() + Days::days(1))
+            .and_hms_opt(0, 0, 0)
+            .expect("valid time");
+        let until = midnight.min(end);
+        days.push((start.date(), (until - start).num_minutes()));
+        start = until;
+    }
+    return days;
+}
+
+// Entries of the owner that overlap `from`..`to`, oldest first.
+pub async fn get_entries<C: GenericClient>(
+    client: &C,
+    owner_id: i32,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Vec<TimeEntry> {
+    let mut entries = Vec::new();
+    for row in client
+        .query(
+            "SELECT * FROM public.time_entry
+            WHERE owner_id=$1 AND started_at < $3 AND (ended_at IS NULL OR ended_at > $2)
+            ORDER BY started_at",
+            &[&owner_id, &from, &to],
+        )
+        .await
+        .expect("error getting time entries")
+    {
+        entries.push(time_entry_from_row(&row));
+    }
+    return entries;
+}
+
+async fn get_running<C: GenericClient>(client: &C, owner_id: i32) -> Option<TimeEntry> {
+    return client
+        .query_opt(
+            "SELECT * FROM public.time_entry WHERE owner_id=$1 AND ended_at IS NULL",
+            &[&owner_id],
+        )
+        .await
+        .expect("error getting running timer")
+        .map(|row| time_entry_from_row(&row));
+}
+
+// Entries on a task always count towards the task's own category.
+async fn resolve_category<C: GenericClient>(
+    client: &C,
+    task_id: Option<i32>,
+    category_id: Option<i32>,
+) -> Result<i32, HttpResponse> {
+    match (task_id, category_id) {
+        (Some(task_id), _) => {
+            match client
+                .query_opt(
+                    "SELECT category_id FROM public.task WHERE id=$1",
+                    &[&task_id],
+                )
+                .await
+                .expect("error getting task")
+            {
+                Some(row) => return Ok(row.get(0)),
+                None => return Err(HttpResponse::NotFound().json("task not found")),
+            }
+        }
+        (None, Some(category_id)) => return Ok(category_id),
+        (None, None) => {
+            return Err(HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json("a task_id or category_id is required"));
+        }
+    }
+}
+
+fn validate_entry(started_at: NaiveDateTime, ended_at: NaiveDateTime) -> Result<(), String> {
+    if ended_at <= started_at {
+        return Err("ended_at must be after started_at".to_string());
+    }
+    if ended_at > now() {
+        return Err("ended_at can't be in the future".to_string());
+    }
+    return Ok(());
+}
+
+// #[get("/timer")]
+pub async fn get_timer(query: web::Query<OwnerQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    return HttpResponse::Ok().json(get_running(&client, query.owner_id).await);
+}
+
+// #[post("/timer/start")]
+pub async fn start_timer(_req: HttpRequest, params: web::Json<TimerRequest>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    if let Some(running) = get_running(&client, params.owner_id).await {
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(running);
+    }
+    let category_id = match resolve_category(&client, params.task_id, params.category_id).await {
+        Ok(category_id) => category_id,
+        Err(response) => return response,
+    };
+
+    match client
+        .query_one(
+            "INSERT INTO public.time_entry (
+                owner_id,
+                task_id,
+                category_id,
+                started_at,
+                note
+            ) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+            &[
+                &params.owner_id,
+                &params.task_id,
+                &category_id,
+                &now(),
+                &params.note,
+            ],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(time_entry_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[post("/timer/stop")]
+pub async fn stop_timer(_req: HttpRequest, params: web::Json<OwnerRequest>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    // A timer stopped within the same second it started still needs a positive length.
+    match client
+        .query_opt(
+            "UPDATE public.time_entry
+            SET ended_at = GREATEST($2, started_at + INTERVAL '1 second')
+            WHERE owner_id = $1 AND ended_at IS NULL
+            RETURNING *",
+            &[&params.owner_id, &now()],
+        )
+        .await
+        .expect("error stopping timer")
+    {
+        Some(row) => return HttpResponse::Ok().json(time_entry_from_row(&row)),
+        None => return HttpResponse::NotFound().json("no timer is running"),
+    }
+}
+
+// #[get("/time_entries")]
+pub async fn get_time_entries(query: web::Query<RangeQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+    let (from, to) = day_range(query.from, query.to);
+
+    return HttpResponse::Ok().json(get_entries(&client, query.owner_id, from, to).await);
+}
+
+// #[post("/time_entry")]
+pub async fn create_time_entry(
+    _req: HttpRequest,
+    params: web::Json<TimeEntryRequest>,
+) -> impl Responder {
+    if let Err(msg) = validate_entry(params.started_at, params.ended_at) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+
+    let client = connect_to_db().await;
+    let category_id = match resolve_category(&client, params.task_id, params.category_id).await {
+        Ok(category_id) => category_id,
+        Err(response) => return response,
+    };
+
+    match client
+        .query_one(
+            "INSERT INTO public.time_entry (
+                owner_id,
+                task_id,
+                category_id,
+                started_at,
+                ended_at,
+                note
+            ) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            &[
+                &params.owner_id,
+                &params.task_id,
+                &category_id,
+                &params.started_at,
+                &params.ended_at,
+                &params.note,
+            ],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Created()
+                .content_type("application/json")
+                .json(time_entry_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[put("/time_entry/{id}")]
+// Also used to correct a running timer's start; its end stays open.
+pub async fn update_time_entry(
+    _req: HttpRequest,
+    params: web::Json<TimeEntryRequest>,
+    info: Path<InfoPathId>,
+) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let existing = match client
+        .query_opt("SELECT * FROM public.time_entry WHERE id=$1", &[&info.id])
+        .await
+        .expect("error getting time entry")
+    {
+        Some(row) => time_entry_from_row(&row),
+        None => return HttpResponse::NotFound().json("time entry not found"),
+    };
+    let ended_at = existing.ended_at.map(|_| params.ended_at);
+    if let Err(msg) = validate_entry(params.started_at, ended_at.unwrap_or(now())) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(msg);
+    }
+    let category_id = match resolve_category(&client, params.task_id, params.category_id).await {
+        Ok(category_id) => category_id,
+        Err(response) => return response,
+    };
+
+    match client
+        .query_one(
+            "UPDATE public.time_entry
+            SET task_id = $1,
+                category_id = $2,
+                started_at = $3,
+                ended_at = $4,
+                note = $5
+            WHERE id = $6
+            RETURNING *",
+            &[
+                &params.task_id,
+                &category_id,
+                &params.started_at,
+                &ended_at,
+                &params.note,
+                &info.id,
+            ],
+        )
+        .await
+    {
+        Ok(row) => {
+            return HttpResponse::Ok()
+                .content_type("application/json")
+                .json(time_entry_from_row(&row));
+        }
+        Err(err) => {
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .json(err.to_string());
+        }
+    }
+}
+
+// #[delete("/time_entry/{id}")]
+pub async fn delete_time_entry(info: Path<InfoPathId>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    client
+        .execute("DELETE FROM public.time_entry WHERE id=$1", &[&info.id])
+        .await
+        .expect("error deleting time entry");
+
+    return HttpResponse::Ok().json("Deleted Item");
+}
+
+// #[get("/time_entries/report")]
+// Tracked minutes between `from` and `to` (inclusive days) per task, category and day.
+// Entries crossing midnight are split between the days they touch.
+pub async fn get_time_report(query: web::Query<RangeQuery>) -> impl Responder {
+    if query.to < query.from {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json("to must not be before from");
+    }
+
+    let client = connect_to_db().await;
+    let (from, to) = day_range(query.from, query.to);
+    let entries = get_entries(&client, query.owner_id, from, to).await;
+
+    let mut task_titles: BTreeMap<i32, String> = BTreeMap::new();
+    for row in client
+        .query(
+            "SELECT id, title FROM public.task WHERE owner_id=$1",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        task_titles.insert(row.get(0), row.get(1));
+    }
+    let mut category_titles: BTreeMap<i32, String> = BTreeMap::new();
+    for row in client
+        .query(
+            "SELECT id, title FROM public.category WHERE owner_id=$1",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        category_titles.insert(row.get(0), row.get(1));
+    }
+
+    let now = now();
+    let mut by_task: BTreeMap<Option<i32>, i64> = BTreeMap::new();
+    let mut by_category: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut by_day: BTreeMap<NaiveDate, i64> = BTreeMap::new();
+    for entry in &entries {
+        for (date, minutes) in minutes_by_day(entry, from, to, now) {
+            *by_task.entry(entry.task_id).or_default() += minutes;
+            *by_category.entry(entry.category_id).or_default() += minutes;
+            *by_day.entry(date).or_default() += minutes;
+        }
+    }
+
+    return HttpResponse::Ok().json(TimeReport {
+        from: query.from,
+        to: query.to,
+        total_minutes: by_day.values().sum(),
+        by_task: by_task
+            .into_iter()
+            .map(|(task_id, minutes)| TaskTotal {
+                task_id,
+                title: task_id.and_then(|id| task_titles.get(&id).cloned()),
+                minutes,
+            })
+            .collect(),
+        by_category: by_category
+            .into_iter()
+            .map(|(category_id, minutes)| CategoryTotal {
+                category_id,
+                title: category_titles
+                    .get(&category_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                minutes,
+            })
+            .collect(),
+        by_day: by_day
+            .into_iter()
+            .map(|(date, minutes)| DayTotal { date, minutes })
+            .collect(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").expect("valid time");
+    }
+
+    fn date(value: &str) -> NaiveDate {
+        return NaiveDate::parse_from_str(value, "%Y-%m-%d").expect("valid date");
+    }
+
+    fn entry(started_at: &str, ended_at: Option<&str>) -> TimeEntry {
+        return TimeEntry {
+            id: 1,
+            owner_id: 1,
+            task_id: None,
+            category_id: 1,
+            started_at: at(started_at),
+            ended_at: ended_at.map(at),
+            note: None,
+        };
+    }
+
+    fn november() -> (NaiveDateTime, NaiveDateTime) {
+        return day_range(date("2026-11-01"), date("2026-11-30"));
+    }
+
+    #[test]
+    fn an_entry_past_midnight_counts_on_both_days() {
+        let (from, to) = november();
+        let late = entry("2026-11-02 22:30", Some("2026-11-03 01:15"));
+
+        assert_eq!(
+            minutes_by_day(&late, from, to, at("2026-11-10 12:00")),
+            vec![(date("2026-11-02"), 90), (date("2026-11-03"), 75)]
+        );
+    }
+
+    #[test]
+    fn an_entry_over_several_days_fills_the_days_between() {
+        let (from, to) = november();
+        let long = entry("2026-11-02 23:00", Some("2026-11-04 00:30"));
+
+        assert_eq!(
+            minutes_by_day(&long, from, to, at("2026-11-10 12:00")),
+            vec![
+                (date("2026-11-02"), 60),
+                (date("2026-11-03"), 24 * 60),
+                (date("2026-11-04"), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_are_clipped_to_the_range() {
+        let (from, to) = november();
+        let into_november = entry("2026-10-31 23:00", Some("2026-11-01 00:45"));
+        let out_of_november = entry("2026-11-30 23:30", Some("2026-12-01 02:00"));
+        let october = entry("2026-10-30 09:00", Some("2026-10-30 10:00"));
+        let now = at("2026-12-10 12:00");
+
+        assert_eq!(
+            minutes_by_day(&into_november, from, to, now),
+            vec![(date("2026-11-01"), 45)]
+        );
+        assert_eq!(
+            minutes_by_day(&out_of_november, from, to, now),
+            vec![(date("2026-11-30"), 30)]
+        );
+        assert!(minutes_by_day(&october, from, to, now).is_empty());
+    }
+
+    #[test]
+    fn a_running_entry_counts_up_to_now() {
+        let (from, to) = november();
+        let running = entry("2026-11-05 23:40", None);
+
+        assert_eq!(
+            minutes_by_day(&running, from, to, at("2026-11-06 00:10")),
+            vec![(date("2026-11-05"), 20), (date("2026-11-06"), 10)]
+        );
+    }
+}
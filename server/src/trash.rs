@@ -0,0 +1,472 @@
+use crate::{
+    category::{category_from_row, Category},
+    connect::{connect_to_db, try_connect_to_db},
+    duration::{duration_from_row, resolve_conflicts, ConflictPolicy, Duration},
+    schedule::{overlapping_schedules, schedule_from_row, Schedule},
+    subtask,
+    task::{task_from_row, InfoPathId, Task},
+    time_entry,
+};
+use actix_web::{
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{Duration as Days, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::env;
+use tokio_postgres::{Error, GenericClient};
+
+// Deleted tasks, categories, durations and schedules are kept this many days unless
+// TRASH_RETENTION_DAYS says otherwise.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+const PURGE_INTERVAL_SECONDS: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+pub struct TrashQuery {
+    owner_id: i32,
+}
+
+// Everything of one owner's that's waiting to be purged, most recently deleted first.
+#[derive(Serialize)]
+pub struct Trash<'a> {
+    pub categories: Vec<Category<'a>>,
+    pub tasks: Vec<Task>,
+    pub durations: Vec<Duration>,
+    pub schedules: Vec<Schedule>,
+}
+
+pub fn retention_days() -> i64 {
+    dotenv::dotenv().ok();
+    return env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|days| *days >= 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+}
+
+// Permanently removes what was deleted before `cutoff`. Time tracked against a purged
+// category goes with it. A category that live rows still point at stays in the trash and is
+// tried again next time.
+pub async fn purge<C: GenericClient>(client: &C, cutoff: NaiveDateTime) -> Result<(), Error> {
+    client
+        .execute("DELETE FROM public.task WHERE deleted_at < $1", &[&cutoff])
+        .await?;
+    client
+        .execute(
+            "DELETE FROM public.duration WHERE deleted_at < $1",
+            &[&cutoff],
+        )
+        .await?;
+    client
+        .execute(
+            "DELETE FROM public.schedule WHERE deleted_at < $1",
+            &[&cutoff],
+        )
+        .await?;
+
+    for row in client
+        .query(
+            "SELECT id FROM public.category WHERE deleted_at < $1",
+            &[&cutoff],
+        )
+        .await?
+    {
+        let id: i32 = row.get(0);
+        if let Err(err) = client
+            .execute("DELETE FROM public.category WHERE id=$1", &[&id])
+            .await
+        {
+            eprintln!("category {} not purged: {}", id, err);
+        }
+    }
+    return Ok(());
+}
+
+// Started once next to the HTTP server, like the reminder scheduler. A failed purge is
+// logged and tried again on the next tick.
+pub async fn run_purger() {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(PURGE_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let cutoff = time_entry::now() - Days::days(retention_days());
+        let purged = match try_connect_to_db().await {
+            Ok(client) => purge(&client, cutoff).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = purged {
+            eprintln!("error purging trash: {}", err);
+        }
+    }
+}
+
+// Whether the category is missing or in the trash, which keeps what's in it from being
+// restored on its own.
+async fn category_deleted<C: GenericClient>(client: &C, category_id: i32) -> Result<bool, Error> {
+    let row = client
+        .query_opt(
+            "SELECT deleted_at IS NOT NULL FROM public.category WHERE id=$1",
+            &[&category_id],
+        )
+        .await?;
+    return Ok(row.is_none_or(|row| row.get(0)));
+}
+
+async fn schedule_deleted<C: GenericClient>(
+    client: &C,
+    schedule_id: Option<i32>,
+) -> Result<bool, Error> {
+    let Some(schedule_id) = schedule_id else {
+        return Ok(false);
+    };
+    let row = client
+        .query_opt(
+            "SELECT deleted_at IS NOT NULL FROM public.schedule WHERE id=$1",
+            &[&schedule_id],
+        )
+        .await?;
+    return Ok(row.is_none_or(|row| row.get(0)));
+}
+
+fn not_in_trash(what: &str) -> HttpResponse {
+    return HttpResponse::NotFound()
+        .content_type("application/json")
+        .json(format!("no such {} in the trash", what));
+}
+
+fn restore_category_first(category_id: i32) -> HttpResponse {
+    return HttpResponse::Conflict()
+        .content_type("application/json")
+        .json(format!(
+            "category {} is in the trash; restore it first",
+            category_id
+        ));
+}
+
+fn restore_schedule_first(schedule_id: i32) -> HttpResponse {
+    return HttpResponse::Conflict()
+        .content_type("application/json")
+        .json(format!(
+            "schedule {} is in the trash; restore it first",
+            schedule_id
+        ));
+}
+
+// #[get("/trash")]
+pub async fn get_trash(query: web::Query<TrashQuery>) -> impl Responder {
+    let client = connect_to_db().await;
+
+    let mut trash = Trash {
+        categories: Vec::new(),
+        tasks: Vec::new(),
+        durations: Vec::new(),
+        schedules: Vec::new(),
+    };
+    for row in client
+        .query(
+            "SELECT * FROM public.category
+            WHERE owner_id=$1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC, id",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting categories")
+    {
+        trash.categories.push(category_from_row(&row));
+    }
+    for row in client
+        .query(
+            "SELECT * FROM public.task
+            WHERE owner_id=$1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC, id",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting tasks")
+    {
+        trash.tasks.push(task_from_row(&row));
+    }
+    for row in client
+        .query(
+            "SELECT * FROM public.duration
+            WHERE owner_id=$1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC, id",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting durations")
+    {
+        trash.durations.push(duration_from_row(&row));
+    }
+    for row in client
+        .query(
+            "SELECT * FROM public.schedule
+            WHERE owner_id=$1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC, id",
+            &[&query.owner_id],
+        )
+        .await
+        .expect("error getting schedules")
+    {
+        trash.schedules.push(schedule_from_row(&row));
+    }
+
+    return HttpResponse::Ok().json(trash);
+}
+
+// #[post("/task/{id}/restore")]
+// Brings the task back with the subtasks that were deleted along with it. A task whose
+// parent is still in the trash comes back as a top level task.
+pub async fn restore_task(_req: HttpRequest, info: Path<InfoPathId>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(task) = transaction
+        .query_opt(
+            "SELECT * FROM public.task WHERE id=$1 AND deleted_at IS NOT NULL",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting task")
+        .map(|row| task_from_row(&row))
+    else {
+        return not_in_trash("task");
+    };
+    if category_deleted(&transaction, task.category_id)
+        .await
+        .expect("error getting category")
+    {
+        return restore_category_first(task.category_id);
+    }
+
+    let mut ids = subtask::descendants(&transaction, task.id)
+        .await
+        .expect("error getting subtasks");
+    ids.push(task.id);
+    transaction
+        .execute(
+            "UPDATE public.task SET deleted_at = NULL WHERE id = ANY($1) AND deleted_at = $2",
+            &[&ids, &task.deleted_at],
+        )
+        .await
+        .expect("error restoring task");
+
+    let row = transaction
+        .query_one("SELECT * FROM public.task WHERE id=$1", &[&task.id])
+        .await
+        .expect("error getting task");
+    let mut restored = [task_from_row(&row)];
+    subtask::hide_trashed_parents(&transaction, &mut restored).await;
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(&restored[0]);
+}
+
+// #[post("/category/{id}/restore")]
+// Brings the category back with the tasks and durations that were deleted along with it,
+// as long as none of those durations overlap blocks added since. Ones deleted on their own
+// before that stay in the trash.
+pub async fn restore_category(_req: HttpRequest, info: Path<InfoPathId>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(deleted_at) = transaction
+        .query_opt(
+            "SELECT deleted_at FROM public.category
+            WHERE id=$1 AND deleted_at IS NOT NULL FOR UPDATE",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting category")
+        .map(|row| row.get::<_, NaiveDateTime>(0))
+    else {
+        return not_in_trash("category");
+    };
+    for sql in [
+        "UPDATE public.category SET deleted_at = NULL WHERE id = $1 AND deleted_at = $2",
+        "UPDATE public.task SET deleted_at = NULL WHERE category_id = $1 AND deleted_at = $2",
+    ] {
+        transaction
+            .execute(sql, &[&info.id, &deleted_at])
+            .await
+            .expect("error restoring category");
+    }
+
+    // Durations come back one at a time so each is checked against blocks added since and
+    // against the ones restored before it.
+    let durations: Vec<Duration> = transaction
+        .query(
+            "SELECT * FROM public.duration WHERE category_id = $1 AND deleted_at = $2",
+            &[&info.id, &deleted_at],
+        )
+        .await
+        .expect("error getting durations")
+        .iter()
+        .map(duration_from_row)
+        .collect();
+    for dur in &durations {
+        if schedule_deleted(&transaction, dur.schedule_id)
+            .await
+            .expect("error getting schedule")
+        {
+            return restore_schedule_first(dur.schedule_id.unwrap_or_default());
+        }
+        if let Some(response) = resolve_conflicts(&transaction, dur, ConflictPolicy::Reject).await {
+            return response;
+        }
+        transaction
+            .execute(
+                "UPDATE public.duration SET deleted_at = NULL WHERE id = $1",
+                &[&dur.id],
+            )
+            .await
+            .expect("error restoring category");
+    }
+
+    let row = transaction
+        .query_one("SELECT * FROM public.category WHERE id=$1", &[&info.id])
+        .await
+        .expect("error getting category");
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(category_from_row(&row));
+}
+
+// #[post("/duration/{id}/restore")]
+// Puts the duration back unless it overlaps blocks added since, the same check a new
+// duration goes through.
+pub async fn restore_duration(_req: HttpRequest, info: Path<InfoPathId>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(dur) = transaction
+        .query_opt(
+            "SELECT * FROM public.duration WHERE id=$1 AND deleted_at IS NOT NULL FOR UPDATE",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting duration")
+        .map(|row| duration_from_row(&row))
+    else {
+        return not_in_trash("duration");
+    };
+    if category_deleted(&transaction, dur.category_id)
+        .await
+        .expect("error getting category")
+    {
+        return restore_category_first(dur.category_id);
+    }
+    if schedule_deleted(&transaction, dur.schedule_id)
+        .await
+        .expect("error getting schedule")
+    {
+        return restore_schedule_first(dur.schedule_id.unwrap_or_default());
+    }
+    if let Some(response) = resolve_conflicts(&transaction, &dur, ConflictPolicy::Reject).await {
+        return response;
+    }
+
+    let row = transaction
+        .query_one(
+            "UPDATE public.duration SET deleted_at = NULL WHERE id=$1 RETURNING *",
+            &[&info.id],
+        )
+        .await
+        .expect("error restoring duration");
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(duration_from_row(&row));
+}
+
+// #[post("/schedule/{id}/restore")]
+// Brings the schedule back with the durations that were deleted along with it, as long as
+// its dates are still free and none of those durations overlap blocks added since.
+pub async fn restore_schedule(_req: HttpRequest, info: Path<InfoPathId>) -> impl Responder {
+    let mut client = connect_to_db().await;
+    let transaction = client
+        .transaction()
+        .await
+        .expect("error starting transaction");
+
+    let Some(schedule) = transaction
+        .query_opt(
+            "SELECT * FROM public.schedule WHERE id=$1 AND deleted_at IS NOT NULL FOR UPDATE",
+            &[&info.id],
+        )
+        .await
+        .expect("error getting schedule")
+        .map(|row| schedule_from_row(&row))
+    else {
+        return not_in_trash("schedule");
+    };
+    let overlapping = overlapping_schedules(
+        &transaction,
+        schedule.owner_id,
+        schedule.id,
+        schedule.starts_on,
+        schedule.ends_on,
+    )
+    .await;
+    if !overlapping.is_empty() {
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(overlapping);
+    }
+
+    let durations: Vec<Duration> = transaction
+        .query(
+            "SELECT * FROM public.duration WHERE schedule_id = $1 AND deleted_at = $2",
+            &[&schedule.id, &schedule.deleted_at],
+        )
+        .await
+        .expect("error getting durations")
+        .iter()
+        .map(duration_from_row)
+        .collect();
+    for dur in &durations {
+        if category_deleted(&transaction, dur.category_id)
+            .await
+            .expect("error getting category")
+        {
+            return restore_category_first(dur.category_id);
+        }
+        if let Some(response) = resolve_conflicts(&transaction, dur, ConflictPolicy::Reject).await {
+            return response;
+        }
+        transaction
+            .execute(
+                "UPDATE public.duration SET deleted_at = NULL WHERE id = $1",
+                &[&dur.id],
+            )
+            .await
+            .expect("error restoring schedule");
+    }
+
+    let row = transaction
+        .query_one(
+            "UPDATE public.schedule SET deleted_at = NULL WHERE id = $1 RETURNING *",
+            &[&schedule.id],
+        )
+        .await
+        .expect("error restoring schedule");
+    transaction
+        .commit()
+        .await
+        .expect("error committing transaction");
+
+    return HttpResponse::Ok().json(schedule_from_row(&row));
+}